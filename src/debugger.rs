@@ -0,0 +1,187 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::io::IoHandler;
+use crate::memory::Memory;
+use crate::vm::VM;
+
+const HELP: &str = "commands:
+    s, step [n]         run n instructions (default 1)
+    c, continue         run until a breakpoint or exit
+    rs, rstep [n]       undo n instructions (default 1)
+    rc, rcontinue       go back until a breakpoint or as far as the history goes
+    goto <cycle>        go back to just before <cycle> ran
+    b, break <addr>     stop before running <addr>
+    d, delete <addr>    remove a breakpoint
+    r, reg [n]          print register n, or all of them
+    x <addr>            print the word at <addr>
+    w, who <addr>       find the last instruction that stored to <addr>
+    q, quit";
+
+/// runs commands from `input` against a vm with history enabled, printing what they do to `out`
+///
+/// program output goes wherever the io handler sends it, flushed after every command
+pub fn run<M: Memory, R: BufRead, W: Write>(vm: &mut VM, io: &mut IoHandler, memory: &mut M, input: R, out: &mut W) -> std::io::Result<()> {
+    let mut breakpoints = BTreeSet::new();
+    // set once the program exits or faults, until something steps back past it
+    let mut stopped = false;
+    write!(out, "(raven) ")?;
+    out.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let count = |i: usize| words.get(i).map_or(Ok(1), |n| crate::parse_u32(n));
+        let addr = |i: usize| words.get(i).ok_or_else(|| "expected an address".to_string()).and_then(|a| crate::parse_u32(a));
+
+        let res: Result<(), String> = match words[..] {
+            [] => Ok(()),
+            ["s" | "step", ..] => count(1).map(|n| {
+                for _ in 0..n {
+                    if stopped {
+                        break
+                    }
+                    stopped = forward(vm, io, memory, out);
+                }
+                if !stopped {
+                    location(vm, out)
+                }
+            }),
+            ["c" | "continue"] => {
+                while !stopped {
+                    stopped = forward(vm, io, memory, out);
+                    if breakpoints.contains(&vm.pc()) {
+                        break
+                    }
+                }
+                if !stopped {
+                    location(vm, out)
+                }
+                Ok(())
+            }
+            ["rs" | "rstep", ..] => count(1).map(|n| {
+                for _ in 0..n {
+                    if vm.reverse_step(memory).is_none() {
+                        let _ = writeln!(out, "can't go back any further");
+                        break
+                    }
+                    stopped = false;
+                }
+                location(vm, out)
+            }),
+            ["rc" | "rcontinue"] => {
+                let before = vm.cycles();
+                if vm.reverse_continue(memory, &breakpoints).is_none() {
+                    let _ = writeln!(out, "can't go back any further");
+                }
+                stopped &= vm.cycles() == before;
+                location(vm, out);
+                Ok(())
+            }
+            ["goto", cycle] => cycle.parse::<u64>().map_err(|e| format!("{}: {}", cycle, e)).map(|c| {
+                let before = vm.cycles();
+                vm.rewind_to(memory, c);
+                stopped &= vm.cycles() == before;
+                location(vm, out)
+            }),
+            ["b" | "break", _] => addr(1).map(|a| { breakpoints.insert(a); }),
+            ["d" | "delete", _] => addr(1).and_then(|a| {
+                if breakpoints.remove(&a) { Ok(()) } else { Err(format!("no breakpoint at {:#010x}", a)) }
+            }),
+            ["r" | "reg"] => {
+                for r in 0..32 {
+                    let _ = write!(out, "r{:<2} {:#010x}{}", r, vm.register(r).unwrap(), if r % 4 == 3 { "\n" } else { "  " });
+                }
+                Ok(())
+            }
+            ["r" | "reg", n] => n.parse::<u8>().ok().and_then(|r| vm.register(r))
+                .ok_or_else(|| format!("{}: no such register", n))
+                .map(|v| { let _ = writeln!(out, "r{} {:#010x}", n, v); }),
+            ["x", _] => addr(1).map(|a| {
+                let _ = match memory.read_u32(a) {
+                    Ok(v) => writeln!(out, "{:#010x}: {:#010x}", a, v),
+                    Err(e) => writeln!(out, "{:#010x}: {:?}", a, e),
+                };
+            }),
+            ["w" | "who", _] => addr(1).map(|a| {
+                let _ = match vm.last_write(a) {
+                    Some((cycle, pc)) => writeln!(out, "cycle {} at {:#010x}", cycle, pc),
+                    None => writeln!(out, "not stored to as far back as the history goes"),
+                };
+            }),
+            ["q" | "quit"] => break,
+            ["h" | "help"] => {
+                let _ = writeln!(out, "{}", HELP);
+                Ok(())
+            }
+            _ => Err("unknown command, try help".into())
+        };
+        if let Err(e) = res {
+            writeln!(out, "{}", e)?
+        }
+        io.flush_console()?;
+        write!(out, "(raven) ")?;
+        out.flush()?;
+    }
+    writeln!(out)
+}
+
+/// runs one instruction, returning true if the program can't go any further
+fn forward<M: Memory, W: Write>(vm: &mut VM, io: &mut IoHandler, memory: &mut M, out: &mut W) -> bool {
+    match vm.cycle(io, memory) {
+        Ok(false) => false,
+        Ok(true) => {
+            let _ = writeln!(out, "exited after cycle {}", vm.cycles());
+            true
+        }
+        Err(e) => {
+            let _ = writeln!(out, "{:#010x}: {}", vm.pc(), e);
+            true
+        }
+    }
+}
+
+fn location<W: Write>(vm: &VM, out: &mut W) {
+    let _ = writeln!(out, "{:#010x}  cycle {}", vm.pc(), vm.cycles());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::program;
+
+    #[test]
+    fn session() {
+        let words = [
+            arith_imm(8, 0, 0, 0x100), arith_imm(9, 0, 0, 7), sw(8, 9),
+            arith_imm(9, 9, 0, 1), sw(8, 9), arith_imm(10, 0, 0, 1),
+        ];
+        let (mut mem, mut io) = (program(&words), IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_history(4, 8);
+        let script = "b 0x10\nc\nx 0x100\nw 0x100\nrs 2\nx 0x100\nr 9\nrc\nrs\nrc\ns 3\ngoto 2\nx 0x100\nd 0x10\nd 0x10\nfoo\nq\ns\n";
+        let mut out = Vec::new();
+        run(&mut vm, &mut io, &mut mem, script.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap().replace("(raven) ", "");
+        assert_eq!(out, "\
+0x00000010  cycle 4
+0x00000100: 0x00000007
+cycle 2 at 0x00000008
+0x00000008  cycle 2
+0x00000100: Uninit
+r9 0x00000007
+can't go back any further
+0x00000000  cycle 0
+can't go back any further
+0x00000000  cycle 0
+can't go back any further
+0x00000000  cycle 0
+0x0000000c  cycle 3
+0x00000008  cycle 2
+0x00000100: Uninit
+no breakpoint at 0x00000010
+unknown command, try help
+
+");
+    }
+}
@@ -4,13 +4,30 @@ use std::io::{
 };
 use file::*;
 use crate::vm::instruction::InsData;
+use crate::vm::snapshot::*;
 
 use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 
 mod file;
 
+/// maps s3 bytes of host file s2, from offset s1, and returns the address they went to.
+/// the offset has to be a multiple of 4096, and the mapping stays after s2 is closed. stores to it fault
+pub const IO_MMAP: u32 = 80;
+/// the same, but copy on write: stores change what the guest sees, never the file
+pub const IO_MMAP_COW: u32 = 81;
+/// opens the host file named by the s3 bytes at s1, and returns its fd. with s2 bit 0 set it's opened
+/// for writing too, and created if it isn't there.
+/// relative names start from the directory given to `IoHandler::allow_host_files`, and anything outside it,
+/// or any file at all without one, is refused with PermissionDenied
+pub const IO_OPEN: u32 = 82;
+/// closes fd s2
+pub const IO_CLOSE: u32 = 83;
+
 pub struct IoHandler {
     files: FileTable,
+    /// the only directory IO_OPEN reaches into, canonical. it's up to whoever runs the program, so snapshots leave it out
+    host_root: Option<PathBuf>,
 
     stdout: VecDeque<u8>,
     stderr: VecDeque<u8>,
@@ -19,6 +36,8 @@ pub struct IoHandler {
 
 impl IoHandler {
     const NUM_VIO: u32 = 32;
+    /// longest path IO_OPEN takes
+    const MAX_PATH: u32 = 4096;
 
     pub fn new() -> Self {
         let files = FileTable::new(Self::NUM_VIO);
@@ -27,25 +46,98 @@ impl IoHandler {
         let stdin = VecDeque::new();
 
         Self {
-            files,
+            files, host_root: None,
             stdin, stdout, stderr
         }
     }
+    /// lets IO_OPEN open and create files under `dir`
+    pub fn allow_host_files(&mut self, dir: &Path) -> std::io::Result<()> {
+        self.host_root = Some(dir.canonicalize()?);
+        Ok(())
+    }
     pub fn io<M: Memory>(&mut self, funct: u32, i: InsData, mem: &mut M) -> IoResult<u32> {
         let fd = i.s2;
         match funct {
             64 => {
                 self.write_one(i.s1, fd).map(|_| 0)
             }
-            _ => todo!()
+            IO_MMAP | IO_MMAP_COW => {
+                self.mmap(fd, i.s1, i.s3, funct == IO_MMAP_COW, mem)
+            }
+            IO_OPEN => self.open(i.s1, i.s3, i.s2 & 1 != 0, mem),
+            IO_CLOSE if fd >= Self::NUM_VIO => self.files.close(fd).map(|_| 0),
+            IO_CLOSE => Err(IoError::BadFd),
+            _ => Err(IoError::Funct)
+        }
+    }
+
+    /// hand buffered console output over to the host
+    pub fn flush_console(&mut self) -> std::io::Result<()> {
+        let mut out = std::io::stdout();
+        out.write_all(self.stdout.make_contiguous())?;
+        out.flush()?;
+        self.stdout.clear();
+
+        let mut err = std::io::stderr();
+        err.write_all(self.stderr.make_contiguous())?;
+        self.stderr.clear();
+        Ok(())
+    }
+
+    /// console output the guest hasn't had flushed yet
+    #[cfg(test)]
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self.stdout.drain(..).collect()
+    }
+
+    fn mmap<M: Memory>(&mut self, fd: u32, offset: u32, len: u32, writable: bool, mem: &mut M) -> IoResult<u32> {
+        if fd < Self::NUM_VIO {
+            return Err(IoError::BadFd)
         }
+        let region = self.files.map(fd, offset as u64, len, writable)?;
+        // out of address space
+        mem.map(region).ok_or(IoError::Other)
+    }
+
+    fn open<M: Memory>(&mut self, path: u32, len: u32, writable: bool, mem: &M) -> IoResult<u32> {
+        if len > Self::MAX_PATH {
+            return Err(IoError::InvalidParams)
+        }
+        let mut buf = vec![0; len as usize];
+        mem.read_into(path, &mut buf).map_err(|_| IoError::InvalidParams)?;
+        let path = String::from_utf8(buf).map_err(|_| IoError::InvalidData)?;
+        let path = self.host_path(Path::new(&path), writable)?;
+        let f = std::fs::OpenOptions::new().read(true).write(writable).create(writable).open(&path)?;
+        Ok(self.files.insert(RFile::File(f), path))
+    }
+    /// where `path` really is, as long as that's under `host_root`.
+    /// a file that isn't there yet can be created if its directory is
+    fn host_path(&self, path: &Path, creating: bool) -> IoResult<PathBuf> {
+        let root = self.host_root.as_ref().ok_or(IoError::PermissionDenied)?;
+        let path = root.join(path);
+        let real = match path.canonicalize() {
+            Ok(p) => p,
+            // a dangling symlink would be followed when it's created, so only a name with nothing behind it at all will do
+            Err(e) if creating && e.kind() == std::io::ErrorKind::NotFound && path.symlink_metadata().is_err() => {
+                let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+                    return Err(IoError::InvalidParams)
+                };
+                dir.canonicalize()?.join(name)
+            }
+            Err(e) => return Err(e.into())
+        };
+        if real.starts_with(root) { Ok(real) } else { Err(IoError::PermissionDenied) }
     }
 
     fn write_one(&mut self, v: u32, fd: u32) -> IoResult<()> {
         match fd {
             1 => self.stdout.push_back(v as u8),
             2 => self.stderr.push_back(v as u8),
-            x if x >= Self::NUM_VIO => todo!("no real files yet"),
+            x if x >= Self::NUM_VIO => match self.files.get_mut(x) {
+                Some(RFile::File(f)) => f.write_all(&[v as u8])?,
+                Some(RFile::Directory(_)) => return Err(IoError::InvalidParams),
+                None => return Err(IoError::BadFd)
+            },
             _x => return Err(IoError::BadFd)
         }
 
@@ -53,8 +145,31 @@ impl IoHandler {
     }
 }
 
+impl Persist for IoHandler {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.files.save(w)?;
+        for b in [&self.stdout, &self.stderr, &self.stdin] {
+            let (a, b) = b.as_slices();
+            put_u32(w, (a.len() + b.len()) as u32)?;
+            w.write_all(a)?;
+            w.write_all(b)?
+        }
+        Ok(())
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let files = FileTable::load(r)?;
+        let stdout = get_bytes(r)?.into();
+        let stderr = get_bytes(r)?.into();
+        let stdin = get_bytes(r)?.into();
+        Ok(Self {
+            files, host_root: None,
+            stdin, stdout, stderr
+        })
+    }
+}
+
 pub type IoResult<T> = Result<T, IoError>;
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum IoError {
     Other = 1,
     NotFound = 2,
@@ -64,6 +179,26 @@ pub enum IoError {
     PermissionDenied = 6,
     BadFd = 7,
     Empty = 8,
+    /// no such io funct
+    Funct = 9,
+}
+
+impl IoError {
+    pub fn from_code(c: u32) -> Option<Self> {
+        use IoError::*;
+        Some(match c {
+            1 => Other,
+            2 => NotFound,
+            3 => InvalidParams,
+            4 => InvalidData,
+            5 => BrokenPipe,
+            6 => PermissionDenied,
+            7 => BadFd,
+            8 => Empty,
+            9 => Funct,
+            _ => return None
+        })
+    }
 }
 
 impl From<std::io::Error> for IoError {
@@ -81,3 +216,93 @@ impl From<std::io::Error> for IoError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::temp_file;
+
+    #[test]
+    fn mmap() {
+        let contents: Vec<u8> = (0..0x2000u32).map(|i| (i / 3) as u8).collect();
+        let mut io = IoHandler::new();
+        let mut mem = MainMemory::new(vec![0; 8]).unwrap();
+        let f = temp_file("mmap", &contents);
+        let fd = io.files.insert(RFile::File(std::fs::File::open(&f.0).unwrap()), f.0.clone());
+
+        let ro = io.io(IO_MMAP, InsData::new(0x1000, fd, 0x800), &mut mem).unwrap();
+        let cow = io.io(IO_MMAP_COW, InsData::new(0, fd, 0x2000), &mut mem).unwrap();
+        assert!(ro.is_multiple_of(0x1000) && cow >= ro + 0x1000);
+        io.files.close(fd).unwrap();
+
+        // still there with the descriptor gone
+        assert_eq!(mem.read_u8(ro), Ok(contents[0x1000]));
+        assert_eq!(mem.read_u8(ro + 0x7ff), Ok(contents[0x17ff]));
+        assert_eq!(mem.read_u8(ro + 0x800), Err(MemoryError::Uninit));
+        assert_eq!(mem.write_u32(ro, 0), Err(MemoryError::ReadOnly));
+        mem.write_u32(cow + 0x1000, 0).unwrap();
+        assert_eq!(mem.read_u8(cow + 0x1000), Ok(0));
+        assert_eq!(mem.read_u8(ro), Ok(contents[0x1000]));
+
+        let mut buf = vec![0; 0x20];
+        mem.read_into(ro - 0x10, &mut buf).unwrap_err();
+        mem.write_from(ro + 0x7f0, &[1; 0x20]).unwrap_err();
+        mem.read_into(cow + 0xff0, &mut buf).unwrap();
+        assert_eq!(buf[..0x10], contents[0xff0..0x1000]);
+
+        assert_eq!(io.io(IO_MMAP, InsData::new(0, fd, 0x10), &mut mem), Err(IoError::BadFd));
+        assert_eq!(io.io(IO_MMAP, InsData::new(0, 1, 0x10), &mut mem), Err(IoError::BadFd));
+        let f = temp_file("mmap-short", &contents[..0x10]);
+        let fd = io.files.insert(RFile::File(std::fs::File::open(&f.0).unwrap()), f.0.clone());
+        assert_eq!(io.io(IO_MMAP, InsData::new(0, fd, 0x11), &mut mem), Err(IoError::InvalidParams));
+        assert_eq!(io.io(IO_MMAP, InsData::new(4, fd, 4), &mut mem), Err(IoError::InvalidParams));
+    }
+
+    #[test]
+    fn open() {
+        let dir = std::env::temp_dir().join(format!("raven-{}-open", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let mut io = IoHandler::new();
+        let mut mem = MainMemory::new(vec![0; 8]).unwrap();
+        let open = |io: &mut IoHandler, mem: &mut MainMemory, name: &str, flags| {
+            mem.write_from(0x100, name.as_bytes()).unwrap();
+            io.io(IO_OPEN, InsData::new(0x100, flags, name.len() as u32), mem)
+        };
+
+        // nothing without a directory to open things in
+        assert_eq!(open(&mut io, &mut mem, "f", 1), Err(IoError::PermissionDenied));
+        io.allow_host_files(&dir).unwrap();
+        assert_eq!(open(&mut io, &mut mem, "f", 0), Err(IoError::NotFound));
+        let fd = open(&mut io, &mut mem, "f", 1).unwrap();
+        for b in b"hi" {
+            io.io(64, InsData::new(*b as u32, fd, 0), &mut mem).unwrap();
+        }
+        assert_eq!(io.io(IO_CLOSE, InsData::new(0, fd, 0), &mut mem), Ok(0));
+        assert_eq!(io.io(IO_CLOSE, InsData::new(0, fd, 0), &mut mem), Err(IoError::BadFd));
+
+        let fd = open(&mut io, &mut mem, dir.join("f").to_str().unwrap(), 0).unwrap();
+        let at = io.io(IO_MMAP, InsData::new(0, fd, 2), &mut mem).unwrap();
+        assert_eq!(mem.read_u16(at), Ok(u16::from_le_bytes(*b"hi")));
+        // opened read only
+        assert!(io.io(64, InsData::new(0, fd, 0), &mut mem).is_err());
+
+        // nothing outside it, however it's named
+        std::fs::write(dir.with_extension("outside"), b"").unwrap();
+        let outside = format!("../{}", dir.with_extension("outside").file_name().unwrap().to_str().unwrap());
+        assert_eq!(open(&mut io, &mut mem, &outside, 0), Err(IoError::PermissionDenied));
+        assert_eq!(open(&mut io, &mut mem, dir.with_extension("outside").to_str().unwrap(), 0), Err(IoError::PermissionDenied));
+        assert_eq!(open(&mut io, &mut mem, "../new", 1), Err(IoError::PermissionDenied));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.with_extension("outside"), dir.join("link")).unwrap();
+            assert_eq!(open(&mut io, &mut mem, "link", 0), Err(IoError::PermissionDenied));
+            std::os::unix::fs::symlink(dir.with_extension("dangling"), dir.join("dangling")).unwrap();
+            assert_eq!(open(&mut io, &mut mem, "dangling", 1), Err(IoError::NotFound));
+            assert!(!dir.with_extension("dangling").exists());
+        }
+        std::fs::remove_file(dir.with_extension("outside")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(io.io(IO_OPEN, InsData::new(0x100, 0, 0x2000), &mut mem), Err(IoError::InvalidParams));
+    }
+}
@@ -1,12 +1,17 @@
 use std::collections::BTreeMap;
 use std::fs::{File, ReadDir};
-use super::IoResult;
+use std::io::{Read, Write, Seek};
+use std::path::PathBuf;
+use super::{IoResult, IoError};
+use crate::memory::FileRegion;
+use crate::vm::snapshot::*;
 
 /// maps raven fds onto underlying system files
 /// 
 /// DOES NOT handle virtual io files
 pub struct FileTable {
-    files: BTreeMap<u32, RFile>,
+    /// with where each one was opened from, which mappings open again for themselves
+    files: BTreeMap<u32, (RFile, PathBuf)>,
     next_id: u32,
     returned_ids: Vec<u32>
 }
@@ -31,19 +36,82 @@ impl FileTable {
         }
     }
 
+    /// hands out a descriptor for `f`, opened from `path`
+    pub fn insert(&mut self, f: RFile, path: PathBuf) -> u32 {
+        let fd = self.next_id();
+        self.files.insert(fd, (f, path));
+        fd
+    }
     pub fn get_mut(&mut self, fd: u32) -> Option<&mut RFile> {
-        self.files.get_mut(&fd)
+        self.files.get_mut(&fd).map(|(f, _)| f)
     }
     pub fn close(&mut self, fd: u32) -> IoResult<()> {
+        let (f, _) = self.files.remove(&fd).ok_or(IoError::BadFd)?;
         self.returned_ids.push(fd);
-        if let Some(f) = self.files.remove(&fd) {
-            if let RFile::File(f) = f {
-                f.sync_all()?
-            }; // catch any close errors that would be ignored when dropping the File
+        if let RFile::File(f) = f {
+            f.sync_all()? // catch any close errors that would be ignored when dropping the File
+        }
+
+        Ok(())
+    }
+
+    /// a region of file `fd`, which opens the file again rather than holding on to the descriptor
+    pub fn map(&self, fd: u32, offset: u64, len: u32, writable: bool) -> IoResult<FileRegion> {
+        match self.files.get(&fd) {
+            Some((RFile::File(_), path)) => Ok(FileRegion::map(path, offset, len, writable)?),
+            Some((RFile::Directory(_), _)) => Err(IoError::InvalidParams),
+            None => Err(IoError::BadFd)
+        }
+    }
+}
+
+/// host files can't be carried across machines, so snapshots only describe them
+///
+/// descriptors that were open when the snapshot was taken come back closed, but they are not handed out again
+impl Persist for FileTable {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        put_u32(w, self.next_id)?;
+        put_u32(w, self.returned_ids.len() as u32)?;
+        for id in &self.returned_ids {
+            put_u32(w, *id)?
         }
 
+        put_u32(w, self.files.len() as u32)?;
+        for (fd, (f, _)) in &self.files {
+            put_u32(w, *fd)?;
+            match f {
+                RFile::File(f) => {
+                    put_u32(w, 0)?;
+                    // position is informational only
+                    let pos = (&*f).stream_position().unwrap_or(0);
+                    put_u64(w, pos)?
+                }
+                RFile::Directory(_) => {
+                    put_u32(w, 1)?;
+                    put_u64(w, 0)?
+                }
+            }
+        }
         Ok(())
     }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let next_id = get_u32(r)?;
+        let mut returned_ids = Vec::new();
+        for _ in 0..get_u32(r)? {
+            returned_ids.push(get_u32(r)?)
+        }
+        for _ in 0..get_u32(r)? {
+            let _fd = get_u32(r)?;
+            if get_u32(r)? > 1 {
+                return Err(SnapshotError::Corrupt("unknown file kind"))
+            }
+            let _pos = get_u64(r)?;
+        }
+        Ok(Self {
+            files: BTreeMap::new(),
+            next_id, returned_ids
+        })
+    }
 }
 
 pub enum RFile {
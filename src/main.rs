@@ -1,10 +1,337 @@
 #![feature(bigint_helper_methods)]
+#![cfg_attr(test, feature(test))]
 
 mod vm;
 mod io;
 mod utils;
 mod memory;
+mod debugger;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use vm::snapshot::Snapshot;
+use vm::trace::{Tracer, TraceFormat, TraceReader};
+use vm::replay::{IoLog, Recorder, Replayer};
+
+const USAGE: &str = "usage:
+    raven run <object> [options]
+    raven resume <snapshot> [options]
+    raven trace <trace> [--pc <addr>] [--opcode <name>] [--mem <addr>]
+    raven aot <object> <output.c>
+    raven debug <object> [--interval <cycles>] [--keep <checkpoints>]
+
+options:
+    --snapshot <cycles> <file>  stop after <cycles> cycles and save a snapshot
+    --trace <file>              write a binary trace
+    --trace-text <file>         write a text trace
+    --record <file>             log every value io returns to the program
+    --replay <file>             serve io from a log instead of the host, stopping at the first divergence
+    --allow-host-files <dir>    let the program open and create host files, but only under <dir>
+    --heapcheck                 fault on heap overflows, use after free and bad frees, going by IO_MALLOC and IO_FREE
+    --cache                     simulate caches and report hits and misses by pc at exit
+    --l1i <spec>, --l1d <spec>  size:ways:line[:lru|fifo|random] for an l1 cache, implying --cache (default 32k:8:64)
+    --l2 <spec>                 add an l2 behind both l1s, implying --cache
+    --timing                    count the cycles an in-order pipeline would take, with stalls by cause, at exit
+    --predict <model>           predict skips with static, bimodal[:bits] or gshare[:bits], and returns with a stack,
+                                reporting accuracy by pc at exit
+    --profile                   count instructions by pc and by function, printing flat and inclusive profiles at exit
+    --symbols <file>            name addresses in the profile, from lines of a hex address and a name, as nm prints them
+    --memcheck                  report loads of undefined bytes, and skips and jumps on undefined values
+    --jit                       compile hot code to x86-64 (builds with the jit feature only)
+    --harts <n>                 run n harts sharing memory, taking turns
+    --threads                   give each hart its own host thread
+
+debug reads commands from stdin, and can step backwards as far as the last <checkpoints> checkpoints,
+taken every <cycles> cycles (default 10000 and 64). type help for the commands";
+
+/// instructions each hart runs before the next one gets a turn
+const QUANTUM: u64 = 1000;
 
 fn main() {
-    
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = dispatch(&args) {
+        eprintln!("raven: {}", e);
+        std::process::exit(1)
+    }
+}
+
+fn dispatch(args: &[String]) -> Result<(), String> {
+    match args {
+        [cmd, path, rest @ ..] if cmd == "run" || cmd == "resume" => exec(cmd, path, rest),
+        [cmd, path, rest @ ..] if cmd == "trace" => print_trace(path, rest),
+        [cmd, path, out] if cmd == "aot" => aot(path, out),
+        [cmd, path, rest @ ..] if cmd == "debug" => debug(path, rest),
+        _ => Err(USAGE.into())
+    }
+}
+
+fn next_arg<'a, I: Iterator<Item = &'a String>>(it: &mut I) -> Result<&'a String, String> {
+    it.next().ok_or_else(|| USAGE.to_string())
+}
+/// accepts decimal or 0x-prefixed hex
+fn parse_u32(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(h) => u32::from_str_radix(h, 16),
+        None => s.parse()
+    }.map_err(|e| format!("{}: {}", s, e))
+}
+
+fn exec(cmd: &str, path: &str, rest: &[String]) -> Result<(), String> {
+    let mut checkpoint = None;
+    let mut trace = None;
+    let mut io_log = None;
+    let mut jit = false;
+    let mut memcheck = false;
+    let mut timing = false;
+    let mut predictor = None;
+    let mut profile = false;
+    let mut symbols = None;
+    let mut heapcheck = false;
+    let mut cache: Option<memory::Hierarchy> = None;
+    let mut harts = 1;
+    let mut threads = false;
+    let mut host_files = None;
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "--snapshot" => {
+                let cycles = next_arg(&mut rest)?.parse::<u64>().map_err(|e| e.to_string())?;
+                checkpoint = Some((cycles, next_arg(&mut rest)?))
+            }
+            "--trace" => trace = Some((next_arg(&mut rest)?, TraceFormat::Binary)),
+            "--trace-text" => trace = Some((next_arg(&mut rest)?, TraceFormat::Text)),
+            "--record" => io_log = Some((next_arg(&mut rest)?, true)),
+            "--replay" => io_log = Some((next_arg(&mut rest)?, false)),
+            "--memcheck" => memcheck = true,
+            "--timing" => timing = true,
+            "--profile" => profile = true,
+            "--symbols" => symbols = Some(next_arg(&mut rest)?),
+            "--predict" => predictor = Some(vm::predict::parse(next_arg(&mut rest)?)?),
+            "--heapcheck" => heapcheck = true,
+            "--cache" => { cache.get_or_insert_default(); }
+            "--l1i" => cache.get_or_insert_default().l1i = next_arg(&mut rest)?.parse()?,
+            "--l1d" => cache.get_or_insert_default().l1d = next_arg(&mut rest)?.parse()?,
+            "--l2" => cache.get_or_insert_default().l2 = Some(next_arg(&mut rest)?.parse()?),
+            "--jit" if cfg!(all(feature = "jit", target_arch = "x86_64", target_os = "linux")) => jit = true,
+            "--jit" => return Err("built without jit support".into()),
+            "--harts" => harts = next_arg(&mut rest)?.parse::<u32>().map_err(|e| e.to_string())?.max(1),
+            "--threads" => threads = true,
+            "--allow-host-files" => host_files = Some(next_arg(&mut rest)?),
+            _ => return Err(USAGE.into())
+        }
+    }
+
+    let mut s = match cmd {
+        "run" => {
+            let object = std::fs::read(path).map_err(|e| e.to_string())?;
+            Snapshot {
+                vm: vm::VM::new(),
+                memory: memory::MainMemory::new(object).map_err(|e| format!("bad object: {:?}", e))?,
+                io: io::IoHandler::new(),
+            }
+        }
+        _ => {
+            let f = File::open(path).map_err(|e| e.to_string())?;
+            Snapshot::load(&mut BufReader::new(f)).map_err(|e| e.to_string())?
+        }
+    };
+    if let Some(dir) = host_files {
+        s.io.allow_host_files(std::path::Path::new(dir)).map_err(|e| format!("{}: {}", dir, e))?
+    }
+    let (traced, logged) = (trace.is_some(), io_log.is_some());
+    if let Some((file, format)) = trace {
+        let f = File::create(file).map_err(|e| e.to_string())?;
+        let t = Tracer::new(Box::new(BufWriter::new(f)), format).map_err(|e| e.to_string())?;
+        s.vm.set_tracer(Some(t))
+    }
+    if let Some((file, record)) = io_log {
+        let l = if record {
+            let f = File::create(file).map_err(|e| e.to_string())?;
+            IoLog::Record(Recorder::new(Box::new(BufWriter::new(f))).map_err(|e| e.to_string())?)
+        }
+        else {
+            let f = File::open(file).map_err(|e| e.to_string())?;
+            IoLog::Replay(Replayer::new(BufReader::new(f)).map_err(|e| e.to_string())?)
+        };
+        s.vm.set_io_log(Some(l))
+    }
+    if memcheck {
+        s.vm.enable_shadow()
+    }
+    if timing {
+        s.vm.enable_timing(vm::timing::Config::default())
+    }
+    if profile {
+        s.vm.enable_profile()
+    }
+    let symbols = match symbols {
+        Some(file) => {
+            let text = std::fs::read_to_string(file).map_err(|e| e.to_string())?;
+            vm::profile::Symbols::parse(&text).map_err(|e| format!("{}: {}", file, e))?
+        }
+        None => vm::profile::Symbols::default()
+    };
+    let predicting = predictor.is_some();
+    if let Some(p) = predictor {
+        s.vm.enable_branch_sim(p)
+    }
+
+    if harts > 1 || threads {
+        // only hart 0 would be traced or logged, and a threaded run can't be replayed anyway
+        if checkpoint.is_some() || jit || memcheck || heapcheck || cache.is_some() || timing || predicting || profile || traced || logged {
+            return Err("--harts and --threads can't be used with --snapshot, --jit, --memcheck, --heapcheck, --cache, --timing, --predict, --profile, --trace, --record or --replay".into())
+        }
+        return run_harts(s, harts, threads)
+    }
+    if cache.is_some() {
+        if jit {
+            return Err("--cache can't be used with --jit".into())
+        }
+        // fetches that hit in it never reach memory, so the simulated l1i would miss them
+        s.vm.set_icache(false)
+    }
+
+    let limit = checkpoint.map(|(c, _)| c);
+    let res = if heapcheck {
+        let (res, m) = run_cache(&mut s.vm, &mut s.io, memory::HeapSanitizer::new(s.memory), cache, limit, jit);
+        s.memory = m.into_inner();
+        res
+    }
+    else {
+        let (res, m) = run_cache(&mut s.vm, &mut s.io, s.memory, cache, limit, jit);
+        s.memory = m;
+        res
+    };
+    s.io.flush_console().map_err(|e| e.to_string())?;
+    s.vm.flush_trace().map_err(|e| e.to_string())?;
+    for r in s.vm.shadow_reports() {
+        eprintln!("raven: {}", r)
+    }
+    if let Some(t) = s.vm.timing() {
+        eprint!("{}", t.report())
+    }
+    if let Some(b) = s.vm.branch_sim() {
+        eprint!("{}", b.report(10))
+    }
+    if let Some(p) = s.vm.profile() {
+        eprint!("{}", p.report(20, &symbols))
+    }
+    let exited = res.map_err(|e| format!("{:#010x}: {}", s.vm.pc(), e))?;
+    if exited {
+        // a run cut short by --snapshot hasn't used up its log yet
+        s.vm.finish_io_log().map_err(|e| e.to_string())?;
+    }
+
+    if let (false, Some((_, file))) = (exited, checkpoint) {
+        let f = File::create(file).map_err(|e| e.to_string())?;
+        s.save(&mut BufWriter::new(f)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// returns true if the program exited, false if it hit the cycle limit
+///
+/// compiled blocks run to completion, so with the jit the limit can be overshot a little
+fn run<M: memory::Memory>(vm: &mut vm::VM, io: &mut io::IoHandler, memory: &mut M, limit: Option<u64>, jit: bool) -> Result<bool, vm::VMError> {
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    let mut jit = jit.then(vm::jit::Jit::new);
+    #[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+    let _ = jit;
+
+    let start = vm.cycles();
+    while limit.is_none_or(|l| vm.cycles() - start < l) {
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        if let Some(j) = &mut jit {
+            if j.step(vm, io, memory)? {
+                return Ok(true)
+            }
+            continue
+        }
+        if vm.cycle(io, memory)? {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+/// runs behind a cache simulator if there's a hierarchy, printing its report after, and hands the memory back
+fn run_cache<M: memory::Memory>(vm: &mut vm::VM, io: &mut io::IoHandler, memory: M, cache: Option<memory::Hierarchy>, limit: Option<u64>, jit: bool) -> (Result<bool, vm::VMError>, M) {
+    let Some(h) = cache else {
+        let mut memory = memory;
+        return (run(vm, io, &mut memory, limit, jit), memory)
+    };
+    let mut m = memory::CacheSim::new(memory, h);
+    let res = run(vm, io, &mut m, limit, jit);
+    eprint!("{}", m.report(10));
+    (res, m.into_inner())
+}
+
+fn run_harts(s: Snapshot, harts: u32, threads: bool) -> Result<(), String> {
+    let mut m = vm::machine::Machine::new(s.vm, harts, s.memory, s.io);
+    let res = if threads { m.run_threads() } else { m.run(QUANTUM) };
+    m.io.flush_console().map_err(|e| e.to_string())?;
+    m.harts[0].flush_trace().map_err(|e| e.to_string())?;
+    res.map_err(|e| e.to_string())?;
+    m.harts[0].finish_io_log().map_err(|e| e.to_string())
+}
+
+/// steps an object forwards and backwards under commands from stdin
+fn debug(path: &str, rest: &[String]) -> Result<(), String> {
+    let mut interval = 10_000;
+    let mut keep = 64;
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "--interval" => interval = next_arg(&mut rest)?.parse::<u64>().map_err(|e| e.to_string())?,
+            "--keep" => keep = next_arg(&mut rest)?.parse::<usize>().map_err(|e| e.to_string())?,
+            _ => return Err(USAGE.into())
+        }
+    }
+
+    let object = std::fs::read(path).map_err(|e| e.to_string())?;
+    let mut memory = memory::MainMemory::new(object).map_err(|e| format!("bad object: {:?}", e))?;
+    let mut io = io::IoHandler::new();
+    let mut vm = vm::VM::new();
+    vm.enable_history(interval, keep);
+    debugger::run(&mut vm, &mut io, &mut memory, std::io::stdin().lock(), &mut std::io::stderr()).map_err(|e| e.to_string())
+}
+
+/// writes a C translation of an object, to build with the system compiler
+fn aot(path: &str, out: &str) -> Result<(), String> {
+    let object = std::fs::read(path).map_err(|e| e.to_string())?;
+    let c = vm::aot::translate(&object).map_err(|e| e.to_string())?;
+    std::fs::write(out, c).map_err(|e| e.to_string())
+}
+
+/// prints a binary trace as text, keeping only records that match every filter given
+fn print_trace(path: &str, rest: &[String]) -> Result<(), String> {
+    let mut pc = None;
+    let mut opcode = None;
+    let mut mem = None;
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "--pc" => pc = Some(parse_u32(next_arg(&mut rest)?)?),
+            "--opcode" => opcode = Some(next_arg(&mut rest)?.to_lowercase()),
+            "--mem" => mem = Some(parse_u32(next_arg(&mut rest)?)? & !3),
+            _ => return Err(USAGE.into())
+        }
+    }
+
+    let f = File::open(path).map_err(|e| e.to_string())?;
+    for r in TraceReader::new(BufReader::new(f)).map_err(|e| e.to_string())? {
+        let r = r.map_err(|e| e.to_string())?;
+        if pc.is_some_and(|pc| pc != r.pc) {
+            continue
+        }
+        if opcode.as_ref().is_some_and(|o| *o != format!("{:?}", r.opcode).to_lowercase()) {
+            continue
+        }
+        // match on the containing word so byte and halfword accesses show up too
+        if mem.is_some_and(|a| r.mem.is_none_or(|m| m.addr & !3 != a)) {
+            continue
+        }
+        println!("{}", r)
+    }
+    Ok(())
 }
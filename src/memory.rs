@@ -1,19 +1,182 @@
+use std::io::{Read, Write};
 use crate::utils::*;
+use crate::vm::snapshot::*;
 
 mod btreemem;
 mod splitmem;
+mod fileregion;
+mod heap;
+mod cachesim;
+#[cfg(any(test, feature = "mem-pagetable"))]
+mod pagemem;
+#[cfg(any(test, feature = "mem-cached"))]
+mod cachedmem;
+#[cfg(all(any(test, feature = "mem-flat"), target_arch = "x86_64", target_os = "linux"))]
+mod flatmem;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+
+pub use fileregion::FileRegion;
+pub use heap::{HeapSanitizer, HeapFault, IO_MALLOC, IO_FREE};
+pub use cachesim::{CacheSim, Hierarchy};
 
 pub trait Memory {
     fn read_u32(&self, addr: u32) -> MemoryResult<u32>;
     fn read_u16(&self, addr: u32) -> MemoryResult<u16>;
     fn read_u8(&self, addr: u32) -> MemoryResult<u8>;
+    /// reads a byte for the host's own bookkeeping. wrappers that watch the guest's accesses
+    /// pass it straight through, without counting or checking it
+    fn peek_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.read_u8(addr)
+    }
 
     /// does not have to return the entire length requested, as memory implementations may store data non-contiguously
     fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]>;
 
+    /// fills `buf` from `addr` onwards, failing if any of it can't be read
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        let mut at = 0;
+        for chunk in self.chunks(addr, buf.len() as u32) {
+            let chunk = chunk?;
+            buf[at..at + chunk.len()].copy_from_slice(chunk);
+            at += chunk.len();
+        }
+        Ok(())
+    }
+    /// writes all of `buf` from `addr` onwards. a range running off the top of the address space
+    /// is out of bounds and writes nothing, other failures may leave part of it written
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        for (i, b) in buf.iter().enumerate() {
+            self.write_u8(addr + i as u32, *b)?
+        }
+        Ok(())
+    }
+    /// `[addr, addr + len)` as the contiguous pieces `read_slice` gives, in order.
+    /// the first error ends it
+    fn chunks(&self, addr: u32, len: u32) -> Chunks<'_, Self> {
+        Chunks { mem: self, addr, left: len }
+    }
+
     fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()>;
     fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()>;
     fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()>;
+
+    /// copies `len` bytes from `src` to `dst`. the ranges can overlap, it's as if the source was read out first
+    ///
+    /// a range running off the top of the address space is out of bounds, and reading any
+    /// uninitialized source byte fails the whole copy before anything is written
+    fn copy(&mut self, dst: u32, src: u32, len: u32) -> MemoryResult<()> {
+        check_range(dst, len)?;
+        for chunk in self.chunks(src, len) {
+            chunk?;
+        }
+        // a block at a time, from the end when the destination overlaps the source's tail
+        let mut buf = [0; BLOCK_SIZE];
+        let blocks = len.div_ceil(BLOCK_SIZE as u32);
+        for i in 0..blocks {
+            let at = if dst > src { blocks - 1 - i } else { i } * BLOCK_SIZE as u32;
+            let buf = &mut buf[..BLOCK_SIZE.min((len - at) as usize)];
+            self.read_into(src + at, buf)?;
+            self.write_from(dst + at, buf)?
+        }
+        Ok(())
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        check_range(dst, len)?;
+        for i in 0..len {
+            self.write_u8(dst + i, v)?
+        }
+        Ok(())
+    }
+    /// makes every block lying wholly inside `[addr, addr + len)` uninitialized again, for memories that can.
+    /// anything else keeps its bytes
+    fn discard(&mut self, _addr: u32, _len: u32) {}
+    /// compares byte by byte like memcmp, but fails if it reaches an uninitialized byte in either range
+    /// before the first difference
+    fn compare(&self, a: u32, b: u32, len: u32) -> MemoryResult<std::cmp::Ordering> {
+        let (mut xs, mut ys) = (self.chunks(a, len), self.chunks(b, len));
+        let (mut x, mut y): (&[u8], &[u8]) = (&[], &[]);
+        loop {
+            if x.is_empty() {
+                match xs.next() {
+                    Some(c) => x = c?,
+                    None => return Ok(std::cmp::Ordering::Equal)
+                }
+            }
+            if y.is_empty() {
+                y = ys.next().expect("both ranges are as long")?;
+            }
+            let n = x.len().min(y.len());
+            match x[..n].cmp(&y[..n]) {
+                std::cmp::Ordering::Equal => (x, y) = (&x[n..], &y[n..]),
+                o => return Ok(o)
+            }
+        }
+    }
+
+    /// reads the instruction word at `pc`, for memories that tell fetches apart from loads
+    fn fetch(&self, pc: u32) -> MemoryResult<u32> {
+        self.read_u32(pc)
+    }
+
+    /// gives `region` an address of its own, for memories that have room for them
+    fn map(&mut self, _region: FileRegion) -> Option<u32> {
+        None
+    }
+
+    /// the guest's allocator handed out `[addr, addr + len)` at `pc`, for memories that check heap accesses
+    fn allocated(&mut self, _addr: u32, _len: u32, _pc: u32) -> MemoryResult<()> {
+        Ok(())
+    }
+    /// the guest's allocator freed the block at `addr` at `pc`
+    fn freed(&mut self, _addr: u32, _pc: u32) -> MemoryResult<()> {
+        Ok(())
+    }
+}
+
+pub struct Chunks<'a, M: Memory + ?Sized> {
+    mem: &'a M,
+    addr: u32,
+    left: u32
+}
+impl<'a, M: Memory + ?Sized> Iterator for Chunks<'a, M> {
+    type Item = MemoryResult<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None
+        }
+        let chunk = check_range(self.addr, self.left)
+            .and_then(|_| self.mem.read_slice(self.addr, self.left))
+            .and_then(|c| if c.is_empty() { Err(OutOfBounds) } else { Ok(c) })
+            // a backend is free to hand back more than was asked for
+            .map(|c| &c[..c.len().min(self.left as usize)]);
+        match chunk {
+            Ok(c) => {
+                self.addr = self.addr.wrapping_add(c.len() as u32);
+                self.left -= c.len() as u32;
+            }
+            Err(_) => self.left = 0
+        }
+        Some(chunk)
+    }
+}
+
+/// alignment is checked first, so an unaligned access says so even in a block nothing has been stored to
+fn check_aligned(addr: u32, size: u32) -> MemoryResult<()> {
+    if !addr.is_multiple_of(size) {
+        Err(Unaligned)
+    }
+    else { Ok(()) }
+}
+
+/// bulk ranges don't wrap around
+fn check_range(addr: u32, len: u32) -> MemoryResult<()> {
+    if addr as u64 + len as u64 > 1 << 32 {
+        Err(OutOfBounds)
+    }
+    else { Ok(()) }
 }
 
 pub type MemoryResult<T> = Result<T, MemoryError>;
@@ -22,10 +185,84 @@ use MemoryError::*;
 pub enum MemoryError {
     Uninit,
     Unaligned,
-    OutOfBounds
+    OutOfBounds,
+    /// a store to memory mapped from a file without copy on write
+    ReadOnly,
+    /// an access or free `HeapSanitizer` caught
+    Heap(Box<HeapFault>)
+}
+
+/// memory where nothing exists until it's stored to, which `SplitMemory` keeps everything past the object in
+///
+/// they all save the same way, so a snapshot doesn't care which one wrote it
+pub trait Sparse: Memory + Persist {
+    fn new() -> Self;
+}
+
+/// the sparse backend `MainMemory` uses, picked at build time.
+/// if more than one feature asks, mem-flat wins over mem-pagetable, which wins over mem-cached
+#[cfg(all(feature = "mem-flat", target_arch = "x86_64", target_os = "linux"))]
+pub type DataMemory = flatmem::FlatMemory;
+#[cfg(all(feature = "mem-pagetable", not(all(feature = "mem-flat", target_arch = "x86_64", target_os = "linux"))))]
+pub type DataMemory = pagemem::PageTableMemory;
+#[cfg(all(feature = "mem-cached", not(feature = "mem-pagetable"), not(all(feature = "mem-flat", target_arch = "x86_64", target_os = "linux"))))]
+pub type DataMemory = cachedmem::CachedMemory;
+#[cfg(not(any(feature = "mem-cached", feature = "mem-pagetable", all(feature = "mem-flat", target_arch = "x86_64", target_os = "linux"))))]
+pub type DataMemory = btreemem::BTreeMemory;
+
+pub type MainMemory = splitmem::SplitMemory<DataMemory>;
+
+/// the sparse backends all allocate in blocks this big
+const BLOCK_SIZE_LOG_2: usize = 12;
+const BLOCK_SIZE: usize = 1 << BLOCK_SIZE_LOG_2;
+type Block = [u8; BLOCK_SIZE];
+
+/// (block, byte within it)
+fn split_addr(a: u32) -> (u32, usize) {
+    (a >> BLOCK_SIZE_LOG_2, (a as usize) & (BLOCK_SIZE - 1))
+}
+
+/// the piece of `[addr, addr + len)` in each block, as (block, start, end) within the block
+fn spans(addr: u32, len: u32) -> impl Iterator<Item = (u32, usize, usize)> {
+    let end = addr as u64 + len as u64;
+    let mut a = addr as u64;
+    std::iter::from_fn(move || {
+        if a >= end {
+            return None
+        }
+        let (block, lo) = split_addr(a as u32);
+        let hi = BLOCK_SIZE.min(lo + (end - a) as usize);
+        a += (hi - lo) as u64;
+        Some((block, lo, hi))
+    })
+}
+
+/// the blocks `[addr, addr + len)` covers from end to end
+fn whole_blocks(addr: u32, len: u32) -> impl Iterator<Item = u32> {
+    spans(addr, len).filter(|(_, lo, hi)| *lo == 0 && *hi == BLOCK_SIZE).map(|(b, _, _)| b)
 }
 
-pub type MainMemory = splitmem::SplitMemory;
+/// the snapshot layout every `Sparse` memory uses: a count, then each block's number and bytes
+fn save_blocks<'a, W: Write>(w: &mut W, blocks: impl ExactSizeIterator<Item = (u32, &'a Block)>) -> std::io::Result<()> {
+    put_u32(w, blocks.len() as u32)?;
+    for (a, b) in blocks {
+        put_u32(w, a)?;
+        w.write_all(b)?
+    }
+    Ok(())
+}
+fn load_blocks<R: Read>(r: &mut R, mut insert: impl FnMut(u32, &Block)) -> SnapshotResult<()> {
+    let mut b = [0; BLOCK_SIZE];
+    for _ in 0..get_u32(r)? {
+        let a = get_u32(r)?;
+        if a >= 1 << (32 - BLOCK_SIZE_LOG_2) {
+            return Err(SnapshotError::Corrupt("block past the end of memory"))
+        }
+        r.read_exact(&mut b)?;
+        insert(a, &b)
+    }
+    Ok(())
+}
 
 impl<T: std::ops::DerefMut<Target = [u8]>> Memory for T {
     fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
@@ -42,9 +279,14 @@ impl<T: std::ops::DerefMut<Target = [u8]>> Memory for T {
         if addr >= self.len() {
             return Err(OutOfBounds)
         }
-        let end = usize::max(addr + len as usize, self.len());
+        let end = usize::min(addr + len as usize, self.len());
         Ok(&self[addr..end])
     }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        let addr = addr as usize;
+        buf.copy_from_slice(self.get(addr..addr + buf.len()).ok_or(OutOfBounds)?);
+        Ok(())
+    }
 
     fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
         write_u32_to_slice(self, addr as usize, v)
@@ -56,4 +298,131 @@ impl<T: std::ops::DerefMut<Target = [u8]>> Memory for T {
         *(self.get_mut(addr as usize).ok_or(OutOfBounds)?) = v;
         Ok(())
     }
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        let addr = addr as usize;
+        self.get_mut(addr..addr + buf.len()).ok_or(OutOfBounds)?.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn copy(&mut self, dst: u32, src: u32, len: u32) -> MemoryResult<()> {
+        let (dst, src, len) = (dst as usize, src as usize, len as usize);
+        if dst + len > self.len() || src + len > self.len() {
+            return Err(OutOfBounds)
+        }
+        self.copy_within(src..src + len, dst);
+        Ok(())
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        let (dst, len) = (dst as usize, len as usize);
+        self.get_mut(dst..dst + len).ok_or(OutOfBounds)?.fill(v);
+        Ok(())
+    }
+    fn compare(&self, a: u32, b: u32, len: u32) -> MemoryResult<std::cmp::Ordering> {
+        let (a, b, len) = (a as usize, b as usize, len as usize);
+        let (a, b) = (self.get(a..a + len).ok_or(OutOfBounds)?, self.get(b..b + len).ok_or(OutOfBounds)?);
+        Ok(a.cmp(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    /// `[base, base + 0x40)` has to be writable, and should cross whatever boundary the memory has at `base + 0x20`
+    fn bulk_access<M: Memory>(m: &mut M, base: u32) {
+        let pattern: Vec<u8> = (0..0x40).map(|i| i * 3 + 1).collect();
+        m.write_from(base, &pattern).unwrap();
+
+        let mut buf = [0; 0x40];
+        m.read_into(base, &mut buf).unwrap();
+        assert_eq!(buf[..], pattern[..]);
+        for (i, b) in pattern.iter().enumerate() {
+            assert_eq!(m.read_u8(base + i as u32), Ok(*b))
+        }
+
+        let chunks = m.chunks(base, 0x40).collect::<MemoryResult<Vec<_>>>().unwrap();
+        assert!(chunks.iter().all(|c| !c.is_empty()));
+        assert_eq!(chunks.concat(), pattern);
+        assert_eq!(m.chunks(base + 3, 5).map(|c| c.unwrap().len()).sum::<usize>(), 5);
+        assert_eq!(m.chunks(base, 0).count(), 0);
+        assert!((1..=4).contains(&m.read_slice(base, 4).unwrap().len()));
+
+        m.read_into(base, &mut []).unwrap();
+        assert_eq!(m.read_into(u32::MAX - 1, &mut [0; 4]), Err(OutOfBounds));
+        assert_eq!(m.write_from(u32::MAX - 1, &[0; 4]), Err(OutOfBounds));
+        assert_eq!(m.chunks(u32::MAX - 1, 4).next(), Some(Err(OutOfBounds)));
+    }
+
+    #[test]
+    fn slice_bulk_access() {
+        let mut m = vec![0; 0x1000];
+        bulk_access(&mut m, 0x7e0);
+        assert_eq!(m.read_slice(0, 4).map(|s| s.len()), Ok(4));
+
+        let mut chunks = m.chunks(0xff0, 0x20);
+        assert_eq!(chunks.next().map(|c| c.map(|c| c.len())), Some(Ok(0x10)));
+        assert_eq!(chunks.next(), Some(Err(OutOfBounds)));
+        assert_eq!(chunks.next(), None);
+        assert_eq!(m.read_into(0xff0, &mut [0; 0x20]), Err(OutOfBounds));
+    }
+
+    #[test]
+    fn slice_conformance() {
+        let layout = conformance::Layout { backed: 0..0x2000, seams: vec![], uninit: None, end: Some(0x2000) };
+        conformance::check(|| vec![0; 0x2000], &layout);
+    }
+
+    #[test]
+    fn btree_bulk_access() {
+        let mut m = btreemem::BTreeMemory::new();
+        bulk_access(&mut m, 0xfe0);
+        assert_eq!(m.chunks(0xfe0, 0x40).count(), 2);
+        assert_eq!(m.read_into(0x1ff0, &mut [0; 0x20]), Err(Uninit));
+        assert_eq!(m.chunks(0x1ff0, 0x20).last(), Some(Err(Uninit)));
+    }
+
+    #[test]
+    fn split_bulk_access() {
+        let mut m = MainMemory::new(vec![0; 0x1000]).unwrap();
+        bulk_access(&mut m, 0xfe0);
+        bulk_access(&mut m, 0x1fe0);
+        assert_eq!(m.chunks(0xfe0, 0x1040).count(), 3);
+        assert_eq!(m.read_into(0x2ff0, &mut [0; 0x20]), Err(Uninit));
+    }
+
+    /// a word stored and loaded back in each of 64 blocks in turn, so no two accesses in a row share a block
+    fn memory_heavy<D: Sparse>(b: &mut test::Bencher) {
+        use crate::vm::{VM, instruction::encode::*};
+        let words = [
+            arith_imm(8, 8, 0, 1), arith_imm(9, 8, 4, 63), arith_imm(9, 9, 0, 16), arith_imm(9, 9, 16, 12),
+            sw(9, 8), lw(11, 9, 0), arith_reg(12, 12, 11, 0),
+            arith_imm(2, 0, 0, -4),
+        ];
+        let mut mem = splitmem::SplitMemory::<D>::new(words.iter().flat_map(|w| w.to_le_bytes()).collect()).unwrap();
+        let mut io = crate::io::IoHandler::new();
+        let mut vm = VM::new();
+        b.iter(|| {
+            for _ in 0..30_000 {
+                vm.cycle(&mut io, &mut mem).unwrap();
+            }
+        })
+    }
+    #[bench]
+    fn memory_heavy_btree(b: &mut test::Bencher) {
+        memory_heavy::<btreemem::BTreeMemory>(b)
+    }
+    #[bench]
+    fn memory_heavy_pagetable(b: &mut test::Bencher) {
+        memory_heavy::<pagemem::PageTableMemory>(b)
+    }
+    #[bench]
+    fn memory_heavy_cached(b: &mut test::Bencher) {
+        memory_heavy::<cachedmem::CachedMemory>(b)
+    }
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[bench]
+    fn memory_heavy_flat(b: &mut test::Bencher) {
+        memory_heavy::<flatmem::FlatMemory>(b)
+    }
 }
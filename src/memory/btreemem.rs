@@ -1,32 +1,28 @@
 use std::collections::BTreeMap;
+use std::io::{Read, Write};
 
 use super::*;
-use crate::utils::*;
 
 pub struct BTreeMemory {
-    blocks: BTreeMap<u32, [u8; Self::BLOCK_SIZE]>,
+    blocks: BTreeMap<u32, Block>,
 }
 impl BTreeMemory {
-    const BLOCK_SIZE_LOG_2: usize = 12;
-    const BLOCK_SIZE: usize = 1 << Self::BLOCK_SIZE_LOG_2;
-
     pub fn new() -> Self {
         Self {
             blocks: BTreeMap::new()
         }
     }
-    fn split_addr(a: u32) -> (u32, usize) {
-        let block = a >> Self::BLOCK_SIZE_LOG_2;
-        let word = (a as usize) & (Self::BLOCK_SIZE - 1);
-        (block, word)
+
+    pub(super) fn block(&self, b: u32) -> MemoryResult<&Block> {
+        self.blocks.get(&b).ok_or(Uninit)
     }
 
-    fn modify_block<T, F: FnOnce(&mut [u8; Self::BLOCK_SIZE]) -> T>(&mut self, addr: u32, f: F) -> T {
+    fn modify_block<T, F: FnOnce(&mut Block) -> T>(&mut self, addr: u32, f: F) -> T {
         if let Some(b) = self.blocks.get_mut(&addr) {
             f(b)
         }
         else {
-            let mut b = [0; Self::BLOCK_SIZE];
+            let mut b = [0; BLOCK_SIZE];
             let ret = f(&mut b);
             self.blocks.insert(addr, b);
             ret
@@ -36,52 +32,105 @@ impl BTreeMemory {
 
 impl Memory for BTreeMemory {
     fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        check_aligned(addr, 4)?;
+        let (block_a, byte_a) = split_addr(addr);
         let block = self.blocks.get(&block_a).ok_or(Uninit)?;
         read_u32_from_slice(block, byte_a)
     }
     fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        check_aligned(addr, 2)?;
+        let (block_a, byte_a) = split_addr(addr);
         let block = self.blocks.get(&block_a).ok_or(Uninit)?;
         read_u16_from_slice(block, byte_a)
     }
     fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        let (block_a, byte_a) = split_addr(addr);
         let block = self.blocks.get(&block_a).ok_or(Uninit)?;
         Ok(block[byte_a])
     }
 
     fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        let (block_a, byte_a) = split_addr(addr);
         let block = self.blocks.get(&block_a).ok_or(Uninit)?;
 
         let len = len as usize;
 
-        if Self::BLOCK_SIZE - byte_a < len {
-            Ok(&block[byte_a..Self::BLOCK_SIZE])
+        if BLOCK_SIZE - byte_a < len {
+            Ok(&block[byte_a..BLOCK_SIZE])
         }
         else {
             Ok(&block[byte_a..byte_a + len])
         }
     }
 
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let mut at = 0;
+        for (b, lo, hi) in spans(addr, buf.len() as u32) {
+            let block = self.blocks.get(&b).ok_or(Uninit)?;
+            buf[at..at + hi - lo].copy_from_slice(&block[lo..hi]);
+            at += hi - lo;
+        }
+        Ok(())
+    }
+
     fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        let (block_a, byte_a) = split_addr(addr);
         self.modify_block(block_a, |block| {
             write_u32_to_slice(block, byte_a, v)
         })
     }
     fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        let (block_a, byte_a) = split_addr(addr);
         self.modify_block(block_a, |block| {
             write_u16_to_slice(block, byte_a, v)
         })
     }
     fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
-        let (block_a, byte_a) = Self::split_addr(addr);
+        let (block_a, byte_a) = split_addr(addr);
         self.modify_block(block_a, |block| block[byte_a] = v);
         Ok(())
     }
+
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let mut at = 0;
+        for (b, lo, hi) in spans(addr, buf.len() as u32) {
+            self.modify_block(b, |block| block[lo..hi].copy_from_slice(&buf[at..at + hi - lo]));
+            at += hi - lo;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        check_range(dst, len)?;
+        for (b, lo, hi) in spans(dst, len) {
+            self.modify_block(b, |block| block[lo..hi].fill(v))
+        }
+        Ok(())
+    }
+    fn discard(&mut self, addr: u32, len: u32) {
+        for b in whole_blocks(addr, len) {
+            self.blocks.remove(&b);
+        }
+    }
+}
+
+impl Sparse for BTreeMemory {
+    fn new() -> Self {
+        Self::new()
+    }
+}
+
+impl Persist for BTreeMemory {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        save_blocks(w, self.blocks.iter().map(|(a, b)| (*a, b)))
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let mut m = Self::new();
+        load_blocks(r, |a, b| { m.blocks.insert(a, *b); })?;
+        Ok(m)
+    }
 }
 
 #[cfg(test)]
@@ -94,10 +143,10 @@ mod tests {
         let mut m = BTreeMemory::new();
 
         for i in 0..256 {
-            m.write_u32(i as u32 * 4, i as u32).unwrap();
+            m.write_u32(i * 4, i).unwrap();
         }
-        for i in 256..0 {
-            assert_eq!(m.read_u32(i as u32 * 4), Ok(i))
+        for i in 0..256 {
+            assert_eq!(m.read_u32(i * 4), Ok(i))
         }
 
         assert_eq!(m.write_u32(1, 0), Err(MemoryError::Unaligned));
@@ -108,4 +157,10 @@ mod tests {
         assert_eq!(m.read_u8(1), Ok(0x56));
         assert_eq!(m.read_u8(3), Ok(0x12));
     }
+
+    #[test]
+    fn conformance() {
+        let layout = conformance::Layout { backed: 0xf00..0x2100, seams: vec![0x1000, 0x2000], uninit: Some(0x5003), end: None };
+        conformance::check(BTreeMemory::new, &layout);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,120 @@
+use std::cell::Cell;
+use std::io::{Read, Write};
+
+use super::*;
+use super::btreemem::BTreeMemory;
+
+/// `BTreeMemory` with the last block it found remembered, so runs of accesses to one block skip the tree
+///
+/// anything that changes the tree goes through `inner_mut`, which forgets the block first
+pub struct CachedMemory {
+    inner: BTreeMemory,
+    /// (block, where it is in `inner`). no block number is u32::MAX, so that's empty
+    last: Cell<(u32, *const Block)>,
+}
+// the pointer only ever points into `inner`, which this owns
+unsafe impl Send for CachedMemory {}
+
+impl CachedMemory {
+    const EMPTY: (u32, *const Block) = (u32::MAX, std::ptr::null());
+
+    pub fn new() -> Self {
+        Self {
+            inner: BTreeMemory::new(),
+            last: Cell::new(Self::EMPTY),
+        }
+    }
+
+    fn block(&self, b: u32) -> MemoryResult<&Block> {
+        let (last, p) = self.last.get();
+        if last == b {
+            // blocks only move or change while `inner` is borrowed mutably, and that forgets `p`
+            return Ok(unsafe { &*p })
+        }
+        let block = self.inner.block(b)?;
+        self.last.set((b, block));
+        Ok(block)
+    }
+    fn inner_mut(&mut self) -> &mut BTreeMemory {
+        self.last.set(Self::EMPTY);
+        &mut self.inner
+    }
+}
+
+impl Memory for CachedMemory {
+    fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
+        check_aligned(addr, 4)?;
+        let (b, byte) = split_addr(addr);
+        read_u32_from_slice(self.block(b)?, byte)
+    }
+    fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
+        check_aligned(addr, 2)?;
+        let (b, byte) = split_addr(addr);
+        read_u16_from_slice(self.block(b)?, byte)
+    }
+    fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
+        let (b, byte) = split_addr(addr);
+        Ok(self.block(b)?[byte])
+    }
+
+    fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
+        let (b, byte) = split_addr(addr);
+        let block = self.block(b)?;
+        Ok(&block[byte..BLOCK_SIZE.min(byte + len as usize)])
+    }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let mut at = 0;
+        for (b, lo, hi) in spans(addr, buf.len() as u32) {
+            buf[at..at + hi - lo].copy_from_slice(&self.block(b)?[lo..hi]);
+            at += hi - lo;
+        }
+        Ok(())
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
+        self.inner_mut().write_u32(addr, v)
+    }
+    fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
+        self.inner_mut().write_u16(addr, v)
+    }
+    fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
+        self.inner_mut().write_u8(addr, v)
+    }
+
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        self.inner_mut().write_from(addr, buf)
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        self.inner_mut().fill(dst, v, len)
+    }
+    fn discard(&mut self, addr: u32, len: u32) {
+        self.inner_mut().discard(addr, len)
+    }
+}
+
+impl Sparse for CachedMemory {
+    fn new() -> Self {
+        Self::new()
+    }
+}
+
+impl Persist for CachedMemory {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.inner.save(w)
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        Ok(Self { inner: BTreeMemory::load(r)?, last: Cell::new(Self::EMPTY) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conformance() {
+        let layout = conformance::Layout { backed: 0xf00..0x2100, seams: vec![0x1000, 0x2000], uninit: Some(0x5003), end: None };
+        conformance::check(CachedMemory::new, &layout);
+    }
+}
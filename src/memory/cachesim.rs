@@ -0,0 +1,427 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// counts how the accesses through it would fare in a hierarchy of caches, passing them all on unchanged
+///
+/// fetches go to the instruction cache and everything else to the data cache, with the l2 behind both if there is one.
+/// caches allocate on writes too, and don't keep anything but tags. accesses are charged to the last pc fetched,
+/// so the vm's decoded instruction cache has to be off, as it skips fetches that hit in it
+pub struct CacheSim<M> {
+    mem: M,
+    sim: RefCell<Sim>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    /// from a fixed seed, so runs repeat
+    Random,
+}
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CacheConfig {
+    pub size: u32,
+    pub ways: u32,
+    pub line: u32,
+    pub replacement: Replacement,
+}
+impl CacheConfig {
+    /// None unless the line is a power of two of at least 4 bytes, and the size a whole number of sets
+    pub fn new(size: u32, ways: u32, line: u32, replacement: Replacement) -> Option<Self> {
+        let set = ways.checked_mul(line)?;
+        if line < 4 || !line.is_power_of_two() || set == 0 || size == 0 || !size.is_multiple_of(set) {
+            return None
+        }
+        Some(Self { size, ways, line, replacement })
+    }
+    fn sets(&self) -> u32 {
+        self.size / (self.ways * self.line)
+    }
+}
+/// size:ways:line, with an optional :lru, :fifo or :random on the end. sizes can end in k or m
+impl std::str::FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("{}: expected size:ways:line[:lru|fifo|random]", s);
+        let num = |n: &str| -> Option<u32> {
+            let (n, scale) = match n.strip_suffix(['k', 'K']) {
+                Some(n) => (n, 1 << 10),
+                None => match n.strip_suffix(['m', 'M']) {
+                    Some(n) => (n, 1 << 20),
+                    None => (n, 1)
+                }
+            };
+            n.parse::<u32>().ok()?.checked_mul(scale)
+        };
+        let parts: Vec<&str> = s.split(':').collect();
+        let replacement = match parts.get(3).copied() {
+            None | Some("lru") => Replacement::Lru,
+            Some("fifo") => Replacement::Fifo,
+            Some("random") => Replacement::Random,
+            _ => return Err(bad())
+        };
+        match parts[..parts.len().min(3)] {
+            [size, ways, line] if parts.len() <= 4 => {
+                Self::new(num(size).ok_or_else(bad)?, num(ways).ok_or_else(bad)?, num(line).ok_or_else(bad)?, replacement).ok_or_else(bad)
+            }
+            _ => Err(bad())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Hierarchy {
+    pub l1i: CacheConfig,
+    pub l1d: CacheConfig,
+    pub l2: Option<CacheConfig>,
+}
+impl Default for Hierarchy {
+    /// 32KiB 8 way l1s with 64 byte lines, and no l2
+    fn default() -> Self {
+        let l1 = CacheConfig::new(32 << 10, 8, 64, Replacement::Lru).unwrap();
+        Self { l1i: l1, l1d: l1, l2: None }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Counts {
+    pub accesses: u64,
+    pub misses: u64,
+}
+impl Counts {
+    fn add(&mut self, hit: bool) {
+        self.accesses += 1;
+        self.misses += !hit as u64
+    }
+}
+/// what the accesses charged to one pc did
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct PcCounts {
+    pub fetch: Counts,
+    pub data: Counts,
+    /// l1 misses from either, which went on to the l2
+    pub l2: Counts,
+}
+
+struct Cache {
+    config: CacheConfig,
+    /// `ways` in a row for each set
+    lines: Vec<Line>,
+    counts: Counts,
+    tick: u64,
+    rng: u64,
+}
+#[derive(Clone, Copy)]
+struct Line {
+    /// the whole line number, so the set is in there too
+    tag: u32,
+    valid: bool,
+    /// last use for lru, when it came in for fifo
+    stamp: u64,
+}
+impl Cache {
+    fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            lines: vec![Line { tag: 0, valid: false, stamp: 0 }; (config.sets() * config.ways) as usize],
+            counts: Counts::default(),
+            tick: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+    /// looks the line `addr` is in up, bringing it in on a miss. true on a hit
+    fn access(&mut self, addr: u32) -> bool {
+        let tag = addr / self.config.line;
+        let ways = self.config.ways as usize;
+        let set = (tag % self.config.sets()) as usize * ways;
+        let lines = &mut self.lines[set..set + ways];
+        self.tick += 1;
+
+        let hit = match lines.iter_mut().find(|l| l.valid && l.tag == tag) {
+            Some(l) => {
+                if self.config.replacement == Replacement::Lru {
+                    l.stamp = self.tick
+                }
+                true
+            }
+            None => {
+                let victim = match lines.iter().position(|l| !l.valid) {
+                    Some(i) => i,
+                    None if self.config.replacement == Replacement::Random => {
+                        self.rng ^= self.rng << 13;
+                        self.rng ^= self.rng >> 7;
+                        self.rng ^= self.rng << 17;
+                        (self.rng % ways as u64) as usize
+                    }
+                    None => (0..ways).min_by_key(|i| lines[*i].stamp).unwrap()
+                };
+                lines[victim] = Line { tag, valid: true, stamp: self.tick };
+                false
+            }
+        };
+        self.counts.add(hit);
+        hit
+    }
+}
+
+struct Sim {
+    l1i: Cache,
+    l1d: Cache,
+    l2: Option<Cache>,
+    /// what accesses are charged to
+    pc: u32,
+    by_pc: BTreeMap<u32, PcCounts>,
+}
+impl Sim {
+    /// every line `[addr, addr + len)` touches, through an l1 and then the l2 on a miss
+    fn access(&mut self, addr: u32, len: u32, fetch: bool) {
+        let l1 = if fetch { &mut self.l1i } else { &mut self.l1d };
+        let line = l1.config.line as u64;
+        let (first, last) = (addr as u64 / line, (addr as u64 + len.max(1) as u64 - 1).min(u32::MAX as u64) / line);
+        let counts = self.by_pc.entry(self.pc).or_default();
+        for l in first..=last {
+            let a = (l * line) as u32;
+            let hit = l1.access(a);
+            if fetch { counts.fetch.add(hit) } else { counts.data.add(hit) }
+            if let (false, Some(l2)) = (hit, &mut self.l2) {
+                counts.l2.add(l2.access(a))
+            }
+        }
+    }
+}
+
+impl<M> CacheSim<M> {
+    pub fn new(mem: M, h: Hierarchy) -> Self {
+        let sim = Sim {
+            l1i: Cache::new(h.l1i),
+            l1d: Cache::new(h.l1d),
+            l2: h.l2.map(Cache::new),
+            pc: 0,
+            by_pc: BTreeMap::new(),
+        };
+        Self { mem, sim: RefCell::new(sim) }
+    }
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    /// (l1i, l1d, l2) totals
+    pub fn totals(&self) -> (Counts, Counts, Option<Counts>) {
+        let s = self.sim.borrow();
+        (s.l1i.counts, s.l1d.counts, s.l2.as_ref().map(|c| c.counts))
+    }
+
+    /// totals for each cache, then the `top` pcs with the most misses
+    pub fn report(&self, top: usize) -> String {
+        use std::fmt::Write;
+        let rate = |c: Counts| if c.accesses == 0 { 0.0 } else { c.misses as f64 * 100.0 / c.accesses as f64 };
+        let mut out = String::new();
+        let (l1i, l1d, l2) = self.totals();
+        for (name, c) in [("l1i", Some(l1i)), ("l1d", Some(l1d)), ("l2", l2)] {
+            if let Some(c) = c {
+                writeln!(out, "{:<4} {:>12} accesses {:>12} misses {:>7.2}%", name, c.accesses, c.misses, rate(c)).unwrap();
+            }
+        }
+        let s = self.sim.borrow();
+        let mut pcs: Vec<_> = s.by_pc.iter().filter(|(_, c)| c.fetch.misses + c.data.misses > 0).collect();
+        pcs.sort_by_key(|(pc, c)| (std::cmp::Reverse(c.fetch.misses + c.data.misses), **pc));
+        if !pcs.is_empty() {
+            writeln!(out, "misses by pc:").unwrap();
+        }
+        for (pc, c) in pcs.into_iter().take(top) {
+            write!(out, "  {:#010x}  fetch {}/{}  data {}/{}", pc, c.fetch.misses, c.fetch.accesses, c.data.misses, c.data.accesses).unwrap();
+            if s.l2.is_some() {
+                write!(out, "  l2 {}/{}", c.l2.misses, c.l2.accesses).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn data(&self, addr: u32, len: u32) {
+        self.sim.borrow_mut().access(addr, len, false)
+    }
+}
+
+impl<M: Memory> Memory for CacheSim<M> {
+    fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
+        self.data(addr, 4);
+        self.mem.read_u32(addr)
+    }
+    fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
+        self.data(addr, 2);
+        self.mem.read_u16(addr)
+    }
+    fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.data(addr, 1);
+        self.mem.read_u8(addr)
+    }
+    fn peek_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.mem.peek_u8(addr)
+    }
+    fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
+        let s = self.mem.read_slice(addr, len)?;
+        self.data(addr, s.len().min(len as usize) as u32);
+        Ok(s)
+    }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        self.data(addr, buf.len() as u32);
+        self.mem.read_into(addr, buf)
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
+        self.data(addr, 4);
+        self.mem.write_u32(addr, v)
+    }
+    fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
+        self.data(addr, 2);
+        self.mem.write_u16(addr, v)
+    }
+    fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
+        self.data(addr, 1);
+        self.mem.write_u8(addr, v)
+    }
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        self.data(addr, buf.len() as u32);
+        self.mem.write_from(addr, buf)
+    }
+
+    fn copy(&mut self, dst: u32, src: u32, len: u32) -> MemoryResult<()> {
+        self.data(src, len);
+        self.data(dst, len);
+        self.mem.copy(dst, src, len)
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        self.data(dst, len);
+        self.mem.fill(dst, v, len)
+    }
+    fn discard(&mut self, addr: u32, len: u32) {
+        self.mem.discard(addr, len)
+    }
+    fn compare(&self, a: u32, b: u32, len: u32) -> MemoryResult<std::cmp::Ordering> {
+        self.data(a, len);
+        self.data(b, len);
+        self.mem.compare(a, b, len)
+    }
+
+    fn fetch(&self, pc: u32) -> MemoryResult<u32> {
+        let mut s = self.sim.borrow_mut();
+        s.pc = pc;
+        s.access(pc, 4, true);
+        drop(s);
+        self.mem.fetch(pc)
+    }
+    fn map(&mut self, region: FileRegion) -> Option<u32> {
+        self.mem.map(region)
+    }
+    fn allocated(&mut self, addr: u32, len: u32, pc: u32) -> MemoryResult<()> {
+        self.mem.allocated(addr, len, pc)
+    }
+    fn freed(&mut self, addr: u32, pc: u32) -> MemoryResult<()> {
+        self.mem.freed(addr, pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(size: u32, ways: u32, replacement: Replacement) -> Cache {
+        Cache::new(CacheConfig::new(size, ways, 16, replacement).unwrap())
+    }
+    fn pc_counts<M>(m: &CacheSim<M>, pc: u32) -> PcCounts {
+        m.sim.borrow().by_pc.get(&pc).copied().unwrap_or_default()
+    }
+    fn misses(c: &mut Cache, addrs: &[u32]) -> Vec<bool> {
+        addrs.iter().map(|a| !c.access(*a)).collect()
+    }
+
+    #[test]
+    fn replacement() {
+        // one set of two ways: a, b, a, c, a
+        let addrs = [0x00, 0x10, 0x04, 0x20, 0x08];
+        assert_eq!(misses(&mut cache(32, 2, Replacement::Lru), &addrs), [true, true, false, true, false]);
+        assert_eq!(misses(&mut cache(32, 2, Replacement::Fifo), &addrs), [true, true, false, true, true]);
+        // direct mapped, so 0x00 and 0x20 fight over a set and 0x10 keeps its own
+        let mut c = cache(32, 1, Replacement::Lru);
+        assert_eq!(misses(&mut c, &[0x00, 0x10, 0x20, 0x14, 0x00]), [true, true, true, false, true]);
+        assert_eq!(c.counts, Counts { accesses: 5, misses: 4 });
+
+        let mut a = cache(64, 4, Replacement::Random);
+        let mut b = cache(64, 4, Replacement::Random);
+        let addrs: Vec<u32> = (0..100).map(|i| (i * 7 % 11) * 16).collect();
+        assert_eq!(misses(&mut a, &addrs), misses(&mut b, &addrs));
+    }
+
+    #[test]
+    fn configs() {
+        assert_eq!("32k:8:64".parse(), Ok(CacheConfig { size: 32 << 10, ways: 8, line: 64, replacement: Replacement::Lru }));
+        assert_eq!("1m:16:128:fifo".parse::<CacheConfig>().map(|c| (c.size, c.replacement)), Ok((1 << 20, Replacement::Fifo)));
+        for bad in ["32k:8", "32k:8:48", "100:8:64", "32k:0:64", "32k:8:64:mru", "32k:8:64:lru:x", "x:8:64"] {
+            assert!(bad.parse::<CacheConfig>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn conformance() {
+        let layout = conformance::Layout { backed: 0xf00..0x2100, seams: vec![0x1000, 0x2000], uninit: Some(0x5003), end: None };
+        conformance::check(|| CacheSim::new(btreemem::BTreeMemory::new(), Hierarchy::default()), &layout);
+    }
+
+    #[test]
+    fn per_pc() {
+        use crate::io::IoHandler;
+        use crate::vm::VM;
+        use crate::vm::instruction::encode::*;
+        use crate::vm::tests::program;
+
+        // walk r9 over 64 words, 16 bytes apart
+        let words = [
+            imm_upper(8, 0, 0x4000), arith_imm(10, 0, 0, 64),
+            lw(9, 8, 0), arith_imm(8, 8, 0, 16), arith_imm(10, 10, 0, -1), skip_imm(0, 10, 22, 0), arith_imm(2, 0, 0, 4),
+        ];
+        let mut mem = program(&words);
+        mem.fill(0x4000, 0, 0x400).unwrap();
+        let l2 = CacheConfig::new(1 << 10, 2, 64, Replacement::Lru);
+        let mut m = CacheSim::new(mem, Hierarchy { l2, ..Hierarchy::default() });
+        let mut io = IoHandler::new();
+        let mut vm = VM::new();
+        vm.set_icache(false);
+        for _ in 0..2 + 64 * 5 - 1 {
+            vm.cycle(&mut io, &mut m).unwrap();
+        }
+        // four loads to a line, and the whole loop is in one line of code
+        let lw = pc_counts(&m, 8);
+        assert_eq!((lw.fetch, lw.data), (Counts { accesses: 64, misses: 0 }, Counts { accesses: 64, misses: 16 }));
+        assert_eq!(lw.l2, Counts { accesses: 16, misses: 16 });
+        assert_eq!(pc_counts(&m, 12).data, Counts::default());
+        let (l1i, l1d, l2) = m.totals();
+        assert_eq!((l1i.accesses, l1i.misses), (2 + 64 * 5 - 1, 1));
+        assert_eq!(l1d, Counts { accesses: 64, misses: 16 });
+        assert_eq!(l2, Some(Counts { accesses: 17, misses: 17 }));
+        assert!(m.report(10).contains("misses by pc:\n  0x00000008  fetch 0/64  data 16/64  l2 16/16\n"));
+    }
+
+    #[test]
+    fn memcheck() {
+        use crate::io::IoHandler;
+        use crate::vm::VM;
+        use crate::vm::instruction::encode::*;
+        use crate::vm::tests::program;
+
+        // memcheck looks over the page a store allocates, which mustn't show up as accesses
+        let words = [imm_upper(8, 0, 0x4000), sw(8, 8)];
+        let mut m = CacheSim::new(program(&words), Hierarchy::default());
+        let mut io = IoHandler::new();
+        let mut vm = VM::new();
+        vm.set_icache(false);
+        vm.enable_shadow();
+        for _ in 0..2 {
+            vm.cycle(&mut io, &mut m).unwrap();
+        }
+        assert_eq!(m.totals().1, Counts { accesses: 1, misses: 1 });
+    }
+}
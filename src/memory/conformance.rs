@@ -0,0 +1,238 @@
+//! checks any `Memory` should pass, for backends to run from their own tests
+//!
+//! everything is against a fresh memory from `new`, so the checks don't depend on each other.
+//! built for the tests here, or with the `conformance` feature for backends kept elsewhere
+
+use std::ops::Range;
+use super::*;
+
+/// what a backend looks like from outside
+pub struct Layout {
+    /// addresses that can be written and then read back
+    pub backed: Range<u32>,
+    /// addresses in `backed` where the memory changes storage, which accesses have to cross cleanly
+    pub seams: Vec<u32>,
+    /// an address that reads as uninitialized until something is stored there
+    pub uninit: Option<u32>,
+    /// the first address past the end, if there is one
+    pub end: Option<u32>
+}
+
+pub fn check<M: Memory>(new: impl Fn() -> M, layout: &Layout) {
+    alignment(&mut new(), layout);
+    little_endian(&mut new(), layout);
+    seams(&mut new(), layout);
+    if let Some(a) = layout.uninit {
+        uninit(&mut new(), a)
+    }
+    if let Some(end) = layout.end {
+        past_end(&mut new(), end)
+    }
+    against_model(&mut new(), layout);
+}
+
+fn init<M: Memory>(m: &mut M, layout: &Layout) {
+    let Range { start, end } = layout.backed;
+    m.write_from(start, &(start..end).map(|a| a as u8 ^ 0x5a).collect::<Vec<_>>()).unwrap();
+}
+
+pub fn alignment<M: Memory>(m: &mut M, layout: &Layout) {
+    init(m, layout);
+    let a = layout.backed.start;
+    let before = m.read_u32(a);
+    for off in 1..4 {
+        assert_eq!(m.read_u32(a + off), Err(Unaligned), "read_u32 at {:x}", a + off);
+        assert_eq!(m.write_u32(a + off, 0), Err(Unaligned), "write_u32 at {:x}", a + off);
+    }
+    assert_eq!(m.read_u16(a + 1), Err(Unaligned));
+    assert_eq!(m.write_u16(a + 3, 0), Err(Unaligned));
+    // nothing got written by the failures
+    assert_eq!(m.read_u32(a), before);
+}
+
+pub fn little_endian<M: Memory>(m: &mut M, layout: &Layout) {
+    let a = layout.backed.start;
+    m.write_u32(a, 0x1234_5678).unwrap();
+    assert_eq!([0, 1, 2, 3].map(|i| m.read_u8(a + i)), [Ok(0x78), Ok(0x56), Ok(0x34), Ok(0x12)]);
+    assert_eq!((m.read_u16(a), m.read_u16(a + 2)), (Ok(0x5678), Ok(0x1234)));
+    m.write_u16(a + 2, 0xabcd).unwrap();
+    m.write_u8(a, 0xef).unwrap();
+    assert_eq!(m.read_u32(a), Ok(0xabcd_56ef));
+    let mut buf = [0; 4];
+    m.read_into(a, &mut buf).unwrap();
+    assert_eq!(buf, [0xef, 0x56, 0xcd, 0xab]);
+}
+
+pub fn seams<M: Memory>(m: &mut M, layout: &Layout) {
+    init(m, layout);
+    for &s in &layout.seams {
+        // words either side of the seam, then bytes straight across it
+        m.write_u32(s - 4, 0x0403_0201).unwrap();
+        m.write_u32(s, 0x0807_0605).unwrap();
+        assert_eq!(m.read_u16(s - 2), Ok(0x0403));
+        assert_eq!(m.read_u16(s), Ok(0x0605));
+
+        let mut buf = [0; 8];
+        m.read_into(s - 4, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+        m.write_from(s - 3, &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]).unwrap();
+        assert_eq!((m.read_u32(s - 4), m.read_u32(s)), (Ok(0x3322_1101), Ok(0x0866_5544)));
+
+        // read_slice may stop at the seam but never runs past what was asked for
+        let slice = m.read_slice(s - 4, 8).unwrap();
+        assert!((1..=8).contains(&slice.len()), "read_slice at {:x} gave {} bytes", s - 4, slice.len());
+        assert_eq!(slice, &[1, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 8][..slice.len()]);
+        let chunks = m.chunks(s - 4, 8).collect::<MemoryResult<Vec<_>>>().unwrap();
+        assert_eq!(chunks.concat(), [1, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 8]);
+    }
+}
+
+pub fn uninit<M: Memory>(m: &mut M, a: u32) {
+    assert_eq!(m.read_u8(a), Err(Uninit));
+    assert_eq!(m.read_u16(a & !1), Err(Uninit));
+    assert_eq!(m.read_u32(a & !3), Err(Uninit));
+    // alignment is checked before whether anything is there
+    assert_eq!(m.read_u16(a | 1), Err(Unaligned));
+    assert_eq!(m.read_u32(a | 1), Err(Unaligned));
+    assert_eq!(m.read_slice(a, 4), Err(Uninit));
+    assert_eq!(m.read_into(a, &mut [0; 4]), Err(Uninit));
+    assert_eq!(m.chunks(a, 4).next(), Some(Err(Uninit)));
+    assert_eq!(m.compare(a, a, 4), Err(Uninit));
+    assert_eq!(m.copy(a + 4, a, 4), Err(Uninit));
+
+    m.write_u8(a, 7).unwrap();
+    assert_eq!(m.read_u8(a), Ok(7));
+    m.read_u32(a & !3).unwrap();
+
+    let block = a & !(BLOCK_SIZE as u32 - 1);
+    m.discard(block, BLOCK_SIZE as u32 - 1);
+    assert_eq!(m.read_u8(a), Ok(7));
+    m.discard(block, BLOCK_SIZE as u32);
+    assert_eq!(m.read_u8(a), Err(Uninit));
+    m.write_u8(a + 1, 1).unwrap();
+    assert_eq!(m.read_u8(a), Ok(0));
+
+    // a difference settles a compare before it gets to the uninitialized block after it
+    if block.checked_add(2 * BLOCK_SIZE as u32).is_some() {
+        m.write_u8(block + 1, 1).unwrap();
+        assert_eq!(m.compare(block, block + 1, BLOCK_SIZE as u32), Ok(std::cmp::Ordering::Less));
+        assert_eq!(m.compare(block + 8, block + 8, BLOCK_SIZE as u32), Err(Uninit));
+    }
+}
+
+pub fn past_end<M: Memory>(m: &mut M, end: u32) {
+    assert_eq!(m.read_u8(end), Err(OutOfBounds));
+    assert_eq!(m.read_u32(end), Err(OutOfBounds));
+    assert_eq!(m.write_u32(end, 0), Err(OutOfBounds));
+    assert_eq!(m.write_u16(end, 0), Err(OutOfBounds));
+    assert_eq!(m.read_u16(end - 1), Err(Unaligned));
+    assert_eq!(m.read_into(end - 2, &mut [0; 4]), Err(OutOfBounds));
+    assert_eq!(m.write_from(end - 2, &[0; 4]), Err(OutOfBounds));
+    assert_eq!(m.read_slice(end, 1), Err(OutOfBounds));
+    assert_eq!(m.read_slice(end - 2, 4).map(|s| s.len()), Ok(2));
+}
+
+/// xorshift, so the same steps come up every run and a failure can be chased down
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 16) as u32
+    }
+    fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+}
+
+/// random accesses within `backed`, checked against a plain byte array
+pub fn against_model<M: Memory>(m: &mut M, layout: &Layout) {
+    init(m, layout);
+    let Range { start, end } = layout.backed;
+    let mut model: Vec<u8> = (start..end).map(|a| a as u8 ^ 0x5a).collect();
+    let mut r = Rng(0x1234_5678_9abc_def1 ^ start as u64);
+    let len = end - start;
+    // a short range anywhere in `backed`, seams included
+    let range = |r: &mut Rng| {
+        let n = r.below(len.min(0x40) + 1);
+        (start + r.below(len - n + 1), n)
+    };
+
+    for step in 0..20_000 {
+        let (a, n) = range(&mut r);
+        let i = (a - start) as usize;
+        let width = [1, 2, 4][r.below(3) as usize];
+        let fits = n >= width;
+        let aligned = a.is_multiple_of(width);
+        let word = |model: &[u8]| model[i..i + width as usize].iter().rev().fold(0, |v, b| v << 8 | *b as u32);
+        let v = r.next();
+
+        match r.below(9) {
+            0 if fits => {
+                let got = match width {
+                    1 => m.read_u8(a).map(u32::from),
+                    2 => m.read_u16(a).map(u32::from),
+                    _ => m.read_u32(a)
+                };
+                assert_eq!(got, if aligned { Ok(word(&model)) } else { Err(Unaligned) }, "step {step}: read {width} at {a:x}");
+            }
+            1 if fits => {
+                let got = match width {
+                    1 => m.write_u8(a, v as u8),
+                    2 => m.write_u16(a, v as u16),
+                    _ => m.write_u32(a, v)
+                };
+                if aligned {
+                    assert_eq!(got, Ok(()), "step {step}: write {width} at {a:x}");
+                    model[i..i + width as usize].copy_from_slice(&v.to_le_bytes()[..width as usize]);
+                }
+                else {
+                    assert_eq!(got, Err(Unaligned), "step {step}: write {width} at {a:x}");
+                }
+            }
+            2 => {
+                let mut buf = vec![0; n as usize];
+                m.read_into(a, &mut buf).unwrap();
+                assert_eq!(buf, model[i..i + n as usize], "step {step}: read_into {n} at {a:x}");
+            }
+            3 => {
+                let buf: Vec<u8> = (0..n).map(|_| r.next() as u8).collect();
+                m.write_from(a, &buf).unwrap();
+                model[i..i + n as usize].copy_from_slice(&buf);
+            }
+            4 if n > 0 => {
+                let slice = m.read_slice(a, n).unwrap();
+                assert!((1..=n as usize).contains(&slice.len()), "step {step}: read_slice {n} at {a:x}");
+                assert_eq!(slice, &model[i..i + slice.len()]);
+            }
+            5 => {
+                let chunks = m.chunks(a, n).collect::<MemoryResult<Vec<_>>>().unwrap();
+                assert_eq!(chunks.concat(), model[i..i + n as usize], "step {step}: chunks {n} at {a:x}");
+            }
+            6 => {
+                let (b, _) = range(&mut r);
+                let n = n.min(end - b);
+                m.copy(b, a, n).unwrap();
+                let j = (b - start) as usize;
+                model.copy_within(i..i + n as usize, j);
+            }
+            7 => {
+                m.fill(a, v as u8, n).unwrap();
+                model[i..i + n as usize].fill(v as u8);
+            }
+            8 => {
+                let (b, _) = range(&mut r);
+                let n = n.min(end - b);
+                let j = (b - start) as usize;
+                let want = model[i..i + n as usize].cmp(&model[j..j + n as usize]);
+                assert_eq!(m.compare(a, b, n), Ok(want), "step {step}: compare {n} at {a:x} and {b:x}");
+            }
+            _ => {}
+        }
+    }
+
+    let mut all = vec![0; len as usize];
+    m.read_into(start, &mut all).unwrap();
+    assert_eq!(all, model);
+}
@@ -0,0 +1,282 @@
+use std::cell::OnceCell;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+/// part of a host file as memory, addressed from 0
+///
+/// read only, or copy on write, where stores change the guest's view but never the file.
+/// nothing is read until the guest touches it, then a block at a time, through the region's own handle on the file,
+/// so it lives on after the descriptor it came from is closed. a block keeps what it read from then on,
+/// and bytes the file no longer has by the time they're first touched read as zero
+pub struct FileRegion {
+    /// where untouched blocks come from. regions made from bytes already have all of theirs
+    file: Option<Backing>,
+    len: u32,
+    writable: bool,
+    blocks: Vec<OnceCell<Box<[u8]>>>,
+    /// blocks stored to, which are all a snapshot needs on top of the file
+    dirty: BTreeSet<u32>,
+}
+struct Backing {
+    path: PathBuf,
+    offset: u64,
+    file: File,
+}
+
+impl FileRegion {
+    /// mappings start on a page
+    pub const ALIGN: u64 = 4096;
+
+    /// `len` bytes of the file at `path` from `offset`, which has to be a multiple of `ALIGN`.
+    /// the range has to be inside the file
+    pub fn map(path: &Path, offset: u64, len: u32, writable: bool) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        if len == 0 || !offset.is_multiple_of(Self::ALIGN) || offset + len as u64 > file.metadata()?.len() {
+            return Err(std::io::ErrorKind::InvalidInput.into())
+        }
+        let backing = Backing { path: path.to_path_buf(), offset, file };
+        Ok(Self::empty(Some(backing), len, writable))
+    }
+    pub fn owned(bytes: Vec<u8>, writable: bool) -> Self {
+        let r = Self::empty(None, bytes.len() as u32, writable);
+        for (b, chunk) in r.blocks.iter().zip(<[u8]>::chunks(&bytes, BLOCK_SIZE)) {
+            let _ = b.set(chunk.into());
+        }
+        r
+    }
+    fn empty(file: Option<Backing>, len: u32, writable: bool) -> Self {
+        let blocks = (0..len.div_ceil(BLOCK_SIZE as u32)).map(|_| OnceCell::new()).collect();
+        Self { file, len, writable, blocks, dirty: BTreeSet::new() }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    fn block_len(&self, b: u32) -> usize {
+        BLOCK_SIZE.min((self.len - b * BLOCK_SIZE as u32) as usize)
+    }
+    /// what the file holds for block `b`
+    fn read_block(&self, b: u32) -> Box<[u8]> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut buf = vec![0; self.block_len(b)].into_boxed_slice();
+        if let Some(Backing { offset, file, .. }) = &self.file {
+            // short reads and errors leave zeros, as a block can't be asked for again
+            let mut f = file;
+            if f.seek(SeekFrom::Start(offset + b as u64 * BLOCK_SIZE as u64)).is_ok() {
+                let mut at = 0;
+                while let Ok(n @ 1..) = f.read(&mut buf[at..]) {
+                    at += n
+                }
+            }
+        }
+        buf
+    }
+    fn block(&self, b: u32) -> &[u8] {
+        self.blocks[b as usize].get_or_init(|| self.read_block(b))
+    }
+    fn block_mut(&mut self, b: u32) -> MemoryResult<&mut [u8]> {
+        if !self.writable {
+            return Err(ReadOnly)
+        }
+        if self.blocks[b as usize].get().is_none() {
+            let _ = self.blocks[b as usize].set(self.read_block(b));
+        }
+        self.dirty.insert(b);
+        Ok(self.blocks[b as usize].get_mut().unwrap())
+    }
+    fn check(&self, addr: u32, len: u32) -> MemoryResult<()> {
+        if addr as u64 + len as u64 > self.len as u64 {
+            return Err(OutOfBounds)
+        }
+        Ok(())
+    }
+}
+
+impl Memory for FileRegion {
+    fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
+        check_aligned(addr, 4)?;
+        self.check(addr, 4)?;
+        let (b, byte) = split_addr(addr);
+        read_u32_from_slice(self.block(b), byte)
+    }
+    fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
+        check_aligned(addr, 2)?;
+        self.check(addr, 2)?;
+        let (b, byte) = split_addr(addr);
+        read_u16_from_slice(self.block(b), byte)
+    }
+    fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.check(addr, 1)?;
+        let (b, byte) = split_addr(addr);
+        Ok(self.block(b)[byte])
+    }
+    fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
+        self.check(addr, 1)?;
+        let (b, byte) = split_addr(addr);
+        let block = self.block(b);
+        Ok(&block[byte..block.len().min(byte + len as usize)])
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
+        check_aligned(addr, 4)?;
+        self.check(addr, 4)?;
+        let (b, byte) = split_addr(addr);
+        write_u32_to_slice(self.block_mut(b)?, byte, v)
+    }
+    fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
+        check_aligned(addr, 2)?;
+        self.check(addr, 2)?;
+        let (b, byte) = split_addr(addr);
+        write_u16_to_slice(self.block_mut(b)?, byte, v)
+    }
+    fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
+        self.check(addr, 1)?;
+        let (b, byte) = split_addr(addr);
+        self.block_mut(b)?[byte] = v;
+        Ok(())
+    }
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        self.check(addr, buf.len() as u32)?;
+        if !self.writable {
+            return Err(ReadOnly)
+        }
+        let mut at = 0;
+        for (b, lo, hi) in spans(addr, buf.len() as u32) {
+            self.block_mut(b)?[lo..hi].copy_from_slice(&buf[at..at + hi - lo]);
+            at += hi - lo;
+        }
+        Ok(())
+    }
+}
+
+/// a region from a file comes back by opening the file again, with whatever the guest stored on top.
+/// one made from bytes comes back with all of them
+impl Persist for FileRegion {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        put_u32(w, self.len)?;
+        put_u32(w, self.writable as u32)?;
+        let blocks: Vec<u32> = match &self.file {
+            Some(Backing { path, offset, .. }) => {
+                let path = path.to_str().ok_or(std::io::ErrorKind::InvalidData)?;
+                put_u32(w, 1)?;
+                put_bytes(w, path.as_bytes())?;
+                put_u64(w, *offset)?;
+                self.dirty.iter().copied().collect()
+            }
+            None => {
+                put_u32(w, 0)?;
+                (0..self.blocks.len() as u32).collect()
+            }
+        };
+        put_u32(w, blocks.len() as u32)?;
+        for b in blocks {
+            put_u32(w, b)?;
+            put_bytes(w, self.block(b))?
+        }
+        Ok(())
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let len = get_u32(r)?;
+        let writable = get_u32(r)? != 0;
+        let mut region = match get_u32(r)? {
+            0 => Self::empty(None, len, writable),
+            1 => {
+                let path = String::from_utf8(get_bytes(r)?).map_err(|_| SnapshotError::Corrupt("mapped file name isn't utf-8"))?;
+                let offset = get_u64(r)?;
+                let file = File::open(&path)?;
+                Self::empty(Some(Backing { path: path.into(), offset, file }), len, writable)
+            }
+            _ => return Err(SnapshotError::Corrupt("unknown mapped file kind"))
+        };
+        if len == 0 {
+            return Err(SnapshotError::Corrupt("empty mapped file"))
+        }
+        for _ in 0..get_u32(r)? {
+            let b = get_u32(r)?;
+            let bytes = get_bytes(r)?;
+            if b as usize >= region.blocks.len() || bytes.len() != region.block_len(b) {
+                return Err(SnapshotError::Corrupt("mapped file block out of place"))
+            }
+            let _ = region.blocks[b as usize].set(bytes.into());
+            if region.file.is_some() {
+                region.dirty.insert(b);
+            }
+        }
+        if region.file.is_none() && region.blocks.iter().any(|b| b.get().is_none()) {
+            return Err(SnapshotError::Corrupt("mapped bytes missing"))
+        }
+        Ok(region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::temp_file;
+
+    #[test]
+    fn regions() {
+        let contents: Vec<u8> = (0..0x3000u32).map(|i| (i * 7) as u8).collect();
+        let f = temp_file("regions", &contents);
+
+        let mut ro = FileRegion::map(&f.0, 0x1000, 0x1800, false).unwrap();
+        assert_eq!(ro.len(), 0x1800);
+        assert_eq!(ro.read_u32(0), Ok(u32::from_le_bytes(contents[0x1000..0x1004].try_into().unwrap())));
+        assert_eq!(ro.write_u8(0, 1), Err(ReadOnly));
+        assert_eq!(ro.read_u8(0x1800), Err(OutOfBounds));
+        assert_eq!(ro.read_u32(0x17fe), Err(Unaligned));
+        let mut buf = vec![0; 0x1800];
+        ro.read_into(0, &mut buf).unwrap();
+        assert_eq!(buf, contents[0x1000..0x2800]);
+
+        let mut cow = FileRegion::map(&f.0, 0, 0x3000, true).unwrap();
+        // nothing is read before it's touched
+        assert!(cow.blocks.iter().all(|b| b.get().is_none()));
+        cow.write_u32(0x1000, 0xdead_beef).unwrap();
+        assert_eq!(cow.read_u32(0x1000), Ok(0xdead_beef));
+        assert_eq!(cow.read_u8(0x1004), Ok(contents[0x1004]));
+        assert_eq!(cow.dirty, BTreeSet::from([1]));
+        // neither the file nor other mappings of it see the store
+        assert_eq!(ro.read_u32(0), Ok(u32::from_le_bytes(contents[0x1000..0x1004].try_into().unwrap())));
+        assert_eq!(std::fs::read(&f.0).unwrap(), contents);
+
+        // a touched block keeps what it read when the file is cut short, the rest read as zero
+        assert_eq!(cow.read_u8(0x2fff), Ok(contents[0x2fff]));
+        std::fs::write(&f.0, b"").unwrap();
+        assert_eq!(cow.read_u8(0x2fff), Ok(contents[0x2fff]));
+        assert_eq!(cow.read_u8(0), Ok(0));
+
+        let f = temp_file("short", &contents[..0x100]);
+        assert!(FileRegion::map(&f.0, 0, 0x101, false).is_err());
+        assert!(FileRegion::map(&f.0, 4, 8, false).is_err());
+        assert!(FileRegion::map(&f.0, 0, 0, false).is_err());
+    }
+
+    #[test]
+    fn persist() {
+        let contents: Vec<u8> = (0..0x3000u32).map(|i| (i * 5) as u8).collect();
+        let f = temp_file("persist", &contents);
+        let mut cow = FileRegion::map(&f.0, 0x1000, 0x2000, true).unwrap();
+        cow.write_u8(0x1fff, 1).unwrap();
+        cow.read_u8(0).unwrap();
+
+        // only the block that was stored to goes in
+        let mut buf = Vec::new();
+        cow.save(&mut buf).unwrap();
+        assert!(buf.len() < 0x1100);
+        let l = FileRegion::load(&mut &buf[..]).unwrap();
+        assert_eq!(l.read_u8(0x1fff), Ok(1));
+        assert_eq!(l.read_u8(0x1ffe), Ok(contents[0x2ffe]));
+        assert_eq!(l.read_u8(0), Ok(contents[0x1000]));
+
+        let owned = FileRegion::owned(vec![3; 0x1001], false);
+        buf.clear();
+        owned.save(&mut buf).unwrap();
+        let l = FileRegion::load(&mut &buf[..]).unwrap();
+        assert_eq!((l.len(), l.read_u8(0x1000)), (0x1001, Ok(3)));
+    }
+}
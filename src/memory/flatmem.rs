@@ -0,0 +1,182 @@
+use std::io::{Read, Write};
+
+use super::*;
+
+/// the whole 4GiB address space as one reserved mapping, which the kernel fills with zero pages as it's touched
+///
+/// a block still reads as uninitialized until something is stored to it, like the other sparse memories
+pub struct FlatMemory {
+    base: *mut u8,
+    /// a bit per block that's been stored to
+    written: Vec<u64>,
+}
+// the mapping belongs to this alone, like a Box would
+unsafe impl Send for FlatMemory {}
+
+impl FlatMemory {
+    const LEN: usize = 1 << 32;
+    const PROT_READ: usize = 1;
+    const PROT_WRITE: usize = 2;
+    const MAP_PRIVATE: usize = 2;
+    const MAP_ANONYMOUS: usize = 0x20;
+    const MAP_NORESERVE: usize = 0x4000;
+
+    pub fn new() -> Self {
+        let ptr = unsafe {
+            syscall6(9, 0, Self::LEN, Self::PROT_READ | Self::PROT_WRITE, Self::MAP_PRIVATE | Self::MAP_ANONYMOUS | Self::MAP_NORESERVE, usize::MAX, 0)
+        };
+        if ptr < 0 {
+            panic!("couldn't reserve 4GiB of address space for FlatMemory")
+        }
+        Self {
+            base: ptr as *mut u8,
+            written: vec![0; 1 << (32 - BLOCK_SIZE_LOG_2 - 6)]
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.base, Self::LEN) }
+    }
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.base, Self::LEN) }
+    }
+
+    fn is_written(&self, b: u32) -> bool {
+        self.written[b as usize >> 6] & 1 << (b & 63) != 0
+    }
+    /// fails unless every block under `[addr, addr + len)` has been stored to
+    fn check_written(&self, addr: u32, len: u32) -> MemoryResult<()> {
+        check_range(addr, len)?;
+        if spans(addr, len).all(|(b, _, _)| self.is_written(b)) {
+            Ok(())
+        }
+        else { Err(Uninit) }
+    }
+    fn mark_written(&mut self, addr: u32, len: u32) -> MemoryResult<()> {
+        check_range(addr, len)?;
+        for (b, _, _) in spans(addr, len) {
+            self.written[b as usize >> 6] |= 1 << (b & 63)
+        }
+        Ok(())
+    }
+}
+impl Drop for FlatMemory {
+    fn drop(&mut self) {
+        unsafe {
+            syscall6(11, self.base as usize, Self::LEN, 0, 0, 0, 0);
+        }
+    }
+}
+
+impl Memory for FlatMemory {
+    fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
+        check_aligned(addr, 4)?;
+        self.check_written(addr, 1)?;
+        read_u32_from_slice(self.bytes(), addr as usize)
+    }
+    fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
+        check_aligned(addr, 2)?;
+        self.check_written(addr, 1)?;
+        read_u16_from_slice(self.bytes(), addr as usize)
+    }
+    fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.check_written(addr, 1)?;
+        Ok(self.bytes()[addr as usize])
+    }
+
+    fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
+        // the next block might not have been written, so stop at the end of this one
+        let (_, byte) = split_addr(addr);
+        let len = len.min((BLOCK_SIZE - byte) as u32);
+        self.check_written(addr, len.max(1))?;
+        Ok(&self.bytes()[addr as usize..addr as usize + len as usize])
+    }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        self.check_written(addr, buf.len() as u32)?;
+        buf.copy_from_slice(&self.bytes()[addr as usize..addr as usize + buf.len()]);
+        Ok(())
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
+        write_u32_to_slice(self.bytes_mut(), addr as usize, v)?;
+        self.mark_written(addr, 4)
+    }
+    fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
+        write_u16_to_slice(self.bytes_mut(), addr as usize, v)?;
+        self.mark_written(addr, 2)
+    }
+    fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
+        self.bytes_mut()[addr as usize] = v;
+        self.mark_written(addr, 1)
+    }
+
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        self.mark_written(addr, buf.len() as u32)?;
+        self.bytes_mut()[addr as usize..addr as usize + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+    fn copy(&mut self, dst: u32, src: u32, len: u32) -> MemoryResult<()> {
+        self.check_written(src, len)?;
+        self.mark_written(dst, len)?;
+        self.bytes_mut().copy_within(src as usize..src as usize + len as usize, dst as usize);
+        Ok(())
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        self.mark_written(dst, len)?;
+        self.bytes_mut()[dst as usize..dst as usize + len as usize].fill(v);
+        Ok(())
+    }
+    fn discard(&mut self, addr: u32, len: u32) {
+        for b in whole_blocks(addr, len) {
+            self.written[b as usize >> 6] &= !(1 << (b & 63));
+            // a store brings the block back, and the rest of it should read as zero like a new one
+            let at = (b as usize) << BLOCK_SIZE_LOG_2;
+            self.bytes_mut()[at..at + BLOCK_SIZE].fill(0)
+        }
+    }
+    fn compare(&self, a: u32, b: u32, len: u32) -> MemoryResult<std::cmp::Ordering> {
+        check_range(a, len)?;
+        check_range(b, len)?;
+        let (x, y) = (&self.bytes()[a as usize..][..len as usize], &self.bytes()[b as usize..][..len as usize]);
+        // only as far as the first difference has to be there
+        let n = x.iter().zip(y).position(|(x, y)| x != y).map_or(len, |i| i as u32 + 1);
+        self.check_written(a, n)?;
+        self.check_written(b, n)?;
+        Ok(x[..n as usize].cmp(&y[..n as usize]))
+    }
+}
+
+impl Sparse for FlatMemory {
+    fn new() -> Self {
+        Self::new()
+    }
+}
+
+impl Persist for FlatMemory {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let blocks: Vec<_> = (0..1 << (32 - BLOCK_SIZE_LOG_2))
+            .filter(|b| self.is_written(*b))
+            .map(|b| {
+                let base = (b as usize) << BLOCK_SIZE_LOG_2;
+                (b, self.bytes()[base..base + BLOCK_SIZE].try_into().unwrap())
+            })
+            .collect();
+        save_blocks(w, blocks.into_iter())
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let mut m = Self::new();
+        load_blocks(r, |a, b| m.write_from(a << BLOCK_SIZE_LOG_2, b).unwrap())?;
+        Ok(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conformance() {
+        let layout = conformance::Layout { backed: 0xffff_d000..0xffff_f100, seams: vec![0xffff_e000, 0xffff_f000], uninit: Some(0x5003), end: None };
+        conformance::check(FlatMemory::new, &layout);
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use super::*;
+
+/// tells a `HeapSanitizer` the guest's allocator just handed out s2 bytes at s1, leaving
+/// `HeapSanitizer::REDZONE` bytes either side of them unused. s3 is the pc to blame, such as the
+/// allocator's return address, or 0 for the notification itself. without a sanitizer it does nothing
+pub const IO_MALLOC: u32 = 88;
+/// tells it the block at s1 was freed, blaming s3 the same way
+pub const IO_FREE: u32 = 89;
+
+/// checks every access against the blocks the guest's allocator says it handed out, like AddressSanitizer
+///
+/// the bytes either side of a live block are its redzone, and a freed block stays poisoned until
+/// `QUARANTINE` bytes have been freed after it or the allocator hands the space out again.
+/// touching either fails with `MemoryError::Heap`, as do frees of anything that isn't a live block
+pub struct HeapSanitizer<M> {
+    mem: M,
+    /// live and quarantined blocks by address, none overlapping
+    blocks: BTreeMap<u32, HeapBlock>,
+    /// quarantined blocks and the free that put them there, oldest first.
+    /// some may have been handed out again, or even freed again, since
+    quarantine: VecDeque<(u32, u64)>,
+    /// the latest free of each quarantined block
+    freed_at: BTreeMap<u32, u64>,
+    frees: u64,
+    quarantined: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HeapBlock {
+    pub addr: u32,
+    pub len: u32,
+    pub alloc_pc: u32,
+    /// None while it's live
+    pub free_pc: Option<u32>,
+}
+impl HeapBlock {
+    fn end(&self) -> u64 {
+        self.addr as u64 + self.len as u64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeapFaultKind {
+    /// an access to a live block's redzone
+    Overflow,
+    /// an access to a quarantined block
+    UseAfterFree,
+    DoubleFree,
+    /// a free of an address no block starts at
+    InvalidFree,
+    /// an allocation on top of a live block
+    Overlap,
+}
+#[derive(PartialEq, Debug)]
+pub struct HeapFault {
+    pub kind: HeapFaultKind,
+    /// the first bad byte, or the address being allocated or freed
+    pub addr: u32,
+    /// the block it ran into
+    pub block: Option<HeapBlock>,
+}
+impl std::fmt::Display for HeapFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use HeapFaultKind::*;
+        let a = self.addr;
+        match (self.kind, self.block) {
+            (Overflow, Some(b)) if a < b.addr => write!(f, "heap overflow at {:#010x}, {} bytes before", a, b.addr - a)?,
+            (Overflow, Some(b)) => write!(f, "heap overflow at {:#010x}, {} bytes after", a, a as u64 - b.end())?,
+            (UseAfterFree, Some(b)) => write!(f, "use after free at {:#010x}, {} bytes into", a, a - b.addr)?,
+            (DoubleFree, _) => write!(f, "double free of")?,
+            (Overlap, _) => write!(f, "allocation at {:#010x} overlaps", a)?,
+            _ => return write!(f, "free of {:#010x}, which isn't the start of a heap block", a)
+        }
+        if let Some(b) = self.block {
+            write!(f, " the {} byte block at {:#010x}, allocated at pc {:#010x}", b.len, b.addr, b.alloc_pc)?;
+            if let Some(pc) = b.free_pc {
+                write!(f, " and freed at pc {:#010x}", pc)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M> HeapSanitizer<M> {
+    /// bytes either side of a block that the allocator has to leave alone
+    pub const REDZONE: u32 = 16;
+    /// how many freed bytes stay poisoned
+    pub const QUARANTINE: u64 = 1 << 20;
+
+    pub fn new(mem: M) -> Self {
+        Self {
+            mem,
+            blocks: BTreeMap::new(),
+            quarantine: VecDeque::new(),
+            freed_at: BTreeMap::new(),
+            frees: 0,
+            quarantined: 0,
+        }
+    }
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    fn block_at(&self, a: u32) -> Option<&HeapBlock> {
+        self.blocks.range(..=a).next_back().map(|(_, b)| b).filter(|b| (a as u64) < b.end())
+    }
+    /// the first byte of `[lo, hi)` that no live block holds
+    fn unowned(&self, mut lo: u64, hi: u64) -> Option<u64> {
+        while lo < hi {
+            match self.block_at(lo as u32) {
+                Some(b) if b.free_pc.is_none() => lo = b.end(),
+                _ => return Some(lo)
+            }
+        }
+        None
+    }
+    /// the first byte of `[addr, addr + len)` that mustn't be touched, and why
+    fn fault(&self, addr: u32, len: u32) -> Option<HeapFault> {
+        if self.blocks.is_empty() || len == 0 {
+            return None
+        }
+        let (lo, hi) = (addr as u64, addr as u64 + len as u64);
+        let rz = Self::REDZONE as u64;
+        let top = (hi + rz - 1).min(u32::MAX as u64) as u32;
+        let mut first: Option<HeapFault> = None;
+        for b in self.blocks.range(..=top).rev().map(|(_, b)| b) {
+            // blocks don't overlap, so their ends go down too
+            if b.end() + rz <= lo {
+                break
+            }
+            let (start, end) = (b.addr as u64, b.end());
+            let hit = match b.free_pc {
+                Some(_) => Some(lo.max(start)).filter(|a| *a < hi.min(end)).map(|a| (a, HeapFaultKind::UseAfterFree)),
+                None => self.unowned(start.saturating_sub(rz).max(lo), start.min(hi))
+                    .or_else(|| self.unowned(end.max(lo), (end + rz).min(hi)))
+                    .map(|a| (a, HeapFaultKind::Overflow))
+            };
+            if let Some((a, kind)) = hit {
+                if first.as_ref().is_none_or(|f| a < f.addr as u64) {
+                    first = Some(HeapFault { kind, addr: a as u32, block: Some(*b) })
+                }
+            }
+        }
+        first
+    }
+    fn check(&self, addr: u32, len: u32) -> MemoryResult<()> {
+        match self.fault(addr, len) {
+            Some(f) => Err(Heap(Box::new(f))),
+            None => Ok(())
+        }
+    }
+}
+
+impl<M: Memory> Memory for HeapSanitizer<M> {
+    fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
+        self.check(addr, 4)?;
+        self.mem.read_u32(addr)
+    }
+    fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
+        self.check(addr, 2)?;
+        self.mem.read_u16(addr)
+    }
+    fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.check(addr, 1)?;
+        self.mem.read_u8(addr)
+    }
+    fn peek_u8(&self, addr: u32) -> MemoryResult<u8> {
+        self.mem.peek_u8(addr)
+    }
+    fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
+        let s = self.mem.read_slice(addr, len)?;
+        match self.fault(addr, s.len() as u32) {
+            Some(f) if f.addr == addr => Err(Heap(Box::new(f))),
+            // stop short of it, the next chunk will start with it
+            Some(f) => Ok(&s[..(f.addr - addr) as usize]),
+            None => Ok(s)
+        }
+    }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        self.check(addr, buf.len() as u32)?;
+        self.mem.read_into(addr, buf)
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
+        self.check(addr, 4)?;
+        self.mem.write_u32(addr, v)
+    }
+    fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
+        self.check(addr, 2)?;
+        self.mem.write_u16(addr, v)
+    }
+    fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
+        self.check(addr, 1)?;
+        self.mem.write_u8(addr, v)
+    }
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        self.check(addr, buf.len() as u32)?;
+        self.mem.write_from(addr, buf)
+    }
+
+    fn copy(&mut self, dst: u32, src: u32, len: u32) -> MemoryResult<()> {
+        self.check(src, len)?;
+        self.check(dst, len)?;
+        self.mem.copy(dst, src, len)
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        self.check(dst, len)?;
+        self.mem.fill(dst, v, len)
+    }
+    fn discard(&mut self, addr: u32, len: u32) {
+        self.mem.discard(addr, len)
+    }
+    fn compare(&self, a: u32, b: u32, len: u32) -> MemoryResult<std::cmp::Ordering> {
+        self.check(a, len)?;
+        self.check(b, len)?;
+        self.mem.compare(a, b, len)
+    }
+
+    fn fetch(&self, pc: u32) -> MemoryResult<u32> {
+        self.check(pc, 4)?;
+        self.mem.fetch(pc)
+    }
+    fn map(&mut self, region: FileRegion) -> Option<u32> {
+        self.mem.map(region)
+    }
+
+    fn allocated(&mut self, addr: u32, len: u32, pc: u32) -> MemoryResult<()> {
+        check_range(addr, len)?;
+        let end = addr as u64 + len as u64;
+        // an empty block still can't share its address with another
+        let over: Vec<HeapBlock> = self.blocks.range(..=(end.max(addr as u64 + 1) - 1) as u32).rev()
+            .map(|(_, b)| *b)
+            .take_while(|b| b.end() > addr as u64 || b.addr == addr)
+            .collect();
+        if let Some(b) = over.iter().find(|b| b.free_pc.is_none()) {
+            return Err(Heap(Box::new(HeapFault { kind: HeapFaultKind::Overlap, addr, block: Some(*b) })))
+        }
+        // the allocator has handed quarantined space out again, so it's fair game
+        for b in over {
+            self.blocks.remove(&b.addr);
+            self.freed_at.remove(&b.addr);
+            self.quarantined -= b.len as u64;
+        }
+        self.blocks.insert(addr, HeapBlock { addr, len, alloc_pc: pc, free_pc: None });
+        Ok(())
+    }
+    fn freed(&mut self, addr: u32, pc: u32) -> MemoryResult<()> {
+        let fault = |kind, block| Err(Heap(Box::new(HeapFault { kind, addr, block })));
+        match self.blocks.get_mut(&addr) {
+            Some(b) if b.free_pc.is_none() => {
+                b.free_pc = Some(pc);
+                self.quarantined += b.len as u64;
+                self.frees += 1;
+                self.quarantine.push_back((addr, self.frees));
+                self.freed_at.insert(addr, self.frees);
+            }
+            Some(b) => return fault(HeapFaultKind::DoubleFree, Some(*b)),
+            None => return fault(HeapFaultKind::InvalidFree, None)
+        }
+        while self.quarantined > Self::QUARANTINE {
+            let Some((a, n)) = self.quarantine.pop_front() else { break };
+            // skip entries for blocks that have been handed out since
+            if self.freed_at.get(&a) == Some(&n) {
+                self.freed_at.remove(&a);
+                if let Some(b) = self.blocks.remove(&a) {
+                    self.quarantined -= b.len as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use HeapFaultKind::*;
+
+    fn fault(kind: HeapFaultKind, addr: u32, block: Option<HeapBlock>) -> MemoryError {
+        Heap(Box::new(HeapFault { kind, addr, block }))
+    }
+
+    #[test]
+    fn conformance() {
+        let layout = conformance::Layout { backed: 0xf00..0x2100, seams: vec![0x1000, 0x2000], uninit: Some(0x5003), end: None };
+        conformance::check(|| HeapSanitizer::new(btreemem::BTreeMemory::new()), &layout);
+    }
+
+    #[test]
+    fn heap() {
+        let mut m = HeapSanitizer::new(btreemem::BTreeMemory::new());
+        m.fill(0, 0, 0x1000).unwrap();
+        m.allocated(0x100, 0x10, 8).unwrap();
+        m.allocated(0x130, 0x10, 12).unwrap();
+        let a = HeapBlock { addr: 0x100, len: 0x10, alloc_pc: 8, free_pc: None };
+
+        m.write_u32(0x10c, 1).unwrap();
+        assert_eq!(m.read_u32(0x110), Err(fault(Overflow, 0x110, Some(a))));
+        assert_eq!(m.write_u8(0xff, 1), Err(fault(Overflow, 0xff, Some(a))));
+        assert_eq!(m.fill(0xe0, 0, 0x80), Err(fault(Overflow, 0xf0, Some(a))));
+        // redzones only reach so far
+        assert_eq!(m.read_u32(0xec), Ok(0));
+        assert_eq!(m.read_u32(0x120), Err(fault(Overflow, 0x120, Some(HeapBlock { addr: 0x130, len: 0x10, alloc_pc: 12, free_pc: None }))));
+        // reads stop short of a redzone, rather than fail
+        assert_eq!(m.read_slice(0xe0, 0x100).map(|s| s.len()), Ok(0x10));
+        let mut buf = [0; 0x20];
+        assert_eq!(m.read_into(0xe0, &mut buf), Err(fault(Overflow, 0xf0, Some(a))));
+
+        m.freed(0x100, 20).unwrap();
+        let freed = HeapBlock { free_pc: Some(20), ..a };
+        assert_eq!(m.read_u8(0x104), Err(fault(UseAfterFree, 0x104, Some(freed))));
+        assert_eq!(m.copy(0x800, 0xf8, 0x10), Err(fault(UseAfterFree, 0x100, Some(freed))));
+        assert_eq!(m.freed(0x100, 24), Err(fault(DoubleFree, 0x100, Some(freed))));
+        assert_eq!(m.freed(0x134, 24), Err(fault(InvalidFree, 0x134, None)));
+        assert_eq!(m.allocated(0x13c, 4, 28), Err(fault(Overlap, 0x13c, Some(HeapBlock { addr: 0x130, len: 0x10, alloc_pc: 12, free_pc: None }))));
+
+        // handing the space out again takes it out of quarantine
+        m.allocated(0x108, 8, 32).unwrap();
+        assert_eq!(m.read_u32(0x108), Ok(0));
+        assert_eq!(m.read_u32(0x104), Err(fault(Overflow, 0x104, Some(HeapBlock { addr: 0x108, len: 8, alloc_pc: 32, free_pc: None }))));
+        // and enough freeing after a block lets it go
+        m.freed(0x130, 36).unwrap();
+        for i in 0..0x11 {
+            let addr = 0x1_0000 * (i + 1);
+            m.allocated(addr, 0x1_0000 - 0x20, 40).unwrap();
+            m.freed(addr, 44).unwrap();
+        }
+        assert_eq!(m.read_u32(0x130), Ok(0));
+    }
+
+    #[test]
+    fn refreed() {
+        let mut m = HeapSanitizer::new(btreemem::BTreeMemory::new());
+        let q = HeapSanitizer::<()>::QUARANTINE as u32;
+        m.allocated(0x100, 0x10, 4).unwrap();
+        m.freed(0x100, 8).unwrap();
+        m.allocated(0x1000, q - 0x18, 4).unwrap();
+        m.freed(0x1000, 8).unwrap();
+        // the first free's entry mustn't count for the second
+        m.allocated(0x100, 0x10, 12).unwrap();
+        m.freed(0x100, 16).unwrap();
+        m.allocated(0x200, 0x10, 4).unwrap();
+        m.freed(0x200, 8).unwrap();
+        let b = HeapBlock { addr: 0x100, len: 0x10, alloc_pc: 12, free_pc: Some(16) };
+        assert_eq!(m.read_u8(0x100), Err(fault(UseAfterFree, 0x100, Some(b))));
+    }
+
+    #[test]
+    fn vm() {
+        use crate::io::IoHandler;
+        use crate::vm::{VM, VMError};
+        use crate::vm::instruction::encode::*;
+        use crate::vm::tests::program;
+
+        let words = [
+            arith_imm(8, 0, 0, 0x400), io_imm(0, 8, IO_MALLOC, 8),
+            sw(8, 8), store_imm(8, 8, 0, 8),
+        ];
+        let mut m = HeapSanitizer::new(program(&words));
+        let mut io = IoHandler::new();
+        let mut vm = VM::new();
+        for _ in 0..3 {
+            vm.cycle(&mut io, &mut m).unwrap();
+        }
+        let block = Some(HeapBlock { addr: 0x400, len: 8, alloc_pc: 4, free_pc: None });
+        let err = vm.cycle(&mut io, &mut m).unwrap_err();
+        assert_eq!(err, VMError::Heap(Box::new(HeapFault { kind: Overflow, addr: 0x408, block })));
+        assert_eq!(vm.pc(), 12);
+        assert_eq!(err.to_string(), "heap overflow at 0x00000408, 0 bytes after the 8 byte block at 0x00000400, allocated at pc 0x00000004");
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{Read, Write};
+
+use super::*;
+
+/// a two level page table over blocks, so finding one is two indexes rather than a tree walk
+pub struct PageTableMemory {
+    /// indexed by the top bits of the block number, then the rest
+    dirs: Vec<Option<Dir>>,
+}
+type Dir = Box<[Option<Box<Block>>]>;
+impl PageTableMemory {
+    const DIR_BITS: usize = 10;
+    const DIR_SIZE: usize = 1 << Self::DIR_BITS;
+
+    pub fn new() -> Self {
+        Self {
+            dirs: vec![None; 1 << (32 - BLOCK_SIZE_LOG_2 - Self::DIR_BITS)]
+        }
+    }
+    fn split_block(b: u32) -> (usize, usize) {
+        (b as usize >> Self::DIR_BITS, b as usize & (Self::DIR_SIZE - 1))
+    }
+
+    fn block(&self, b: u32) -> MemoryResult<&Block> {
+        let (dir, page) = Self::split_block(b);
+        self.dirs[dir].as_ref().and_then(|d| d[page].as_deref()).ok_or(Uninit)
+    }
+    fn block_mut(&mut self, b: u32) -> &mut Block {
+        let (dir, page) = Self::split_block(b);
+        let dir = self.dirs[dir].get_or_insert_with(|| vec![None; Self::DIR_SIZE].into_boxed_slice());
+        dir[page].get_or_insert_with(|| Box::new([0; BLOCK_SIZE]))
+    }
+}
+
+impl Memory for PageTableMemory {
+    fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
+        check_aligned(addr, 4)?;
+        let (b, byte) = split_addr(addr);
+        read_u32_from_slice(self.block(b)?, byte)
+    }
+    fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
+        check_aligned(addr, 2)?;
+        let (b, byte) = split_addr(addr);
+        read_u16_from_slice(self.block(b)?, byte)
+    }
+    fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
+        let (b, byte) = split_addr(addr);
+        Ok(self.block(b)?[byte])
+    }
+
+    fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
+        let (b, byte) = split_addr(addr);
+        let block = self.block(b)?;
+        Ok(&block[byte..BLOCK_SIZE.min(byte + len as usize)])
+    }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let mut at = 0;
+        for (b, lo, hi) in spans(addr, buf.len() as u32) {
+            buf[at..at + hi - lo].copy_from_slice(&self.block(b)?[lo..hi]);
+            at += hi - lo;
+        }
+        Ok(())
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
+        let (b, byte) = split_addr(addr);
+        write_u32_to_slice(self.block_mut(b), byte, v)
+    }
+    fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
+        let (b, byte) = split_addr(addr);
+        write_u16_to_slice(self.block_mut(b), byte, v)
+    }
+    fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
+        let (b, byte) = split_addr(addr);
+        self.block_mut(b)[byte] = v;
+        Ok(())
+    }
+
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let mut at = 0;
+        for (b, lo, hi) in spans(addr, buf.len() as u32) {
+            self.block_mut(b)[lo..hi].copy_from_slice(&buf[at..at + hi - lo]);
+            at += hi - lo;
+        }
+        Ok(())
+    }
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        check_range(dst, len)?;
+        for (b, lo, hi) in spans(dst, len) {
+            self.block_mut(b)[lo..hi].fill(v)
+        }
+        Ok(())
+    }
+    fn discard(&mut self, addr: u32, len: u32) {
+        for b in whole_blocks(addr, len) {
+            let (dir, page) = Self::split_block(b);
+            if let Some(d) = &mut self.dirs[dir] {
+                d[page] = None
+            }
+        }
+    }
+}
+
+impl Sparse for PageTableMemory {
+    fn new() -> Self {
+        Self::new()
+    }
+}
+
+impl Persist for PageTableMemory {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let blocks: Vec<_> = self.dirs.iter().enumerate()
+            .filter_map(|(i, d)| Some((i, d.as_ref()?)))
+            .flat_map(|(i, d)| d.iter().enumerate().filter_map(move |(j, b)| {
+                Some((((i << Self::DIR_BITS) | j) as u32, &**b.as_ref()?))
+            }))
+            .collect();
+        save_blocks(w, blocks.into_iter())
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let mut m = Self::new();
+        load_blocks(r, |a, b| *m.block_mut(a) = *b)?;
+        Ok(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conformance() {
+        let layout = conformance::Layout { backed: 0x3ff_f000..0x400_1100, seams: vec![0x400_0000, 0x400_1000], uninit: Some(0xffff_f000), end: None };
+        conformance::check(PageTableMemory::new, &layout);
+    }
+}
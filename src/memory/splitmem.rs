@@ -1,12 +1,16 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
 use super::*;
-use btreemem::BTreeMemory;
-use crate::utils::*;
 
-pub struct SplitMemory {
+pub struct SplitMemory<D> {
     object: Vec<u8>,
-    data: BTreeMemory
+    data: D,
+    /// mapped files by base address, which take priority over data
+    regions: BTreeMap<u32, FileRegion>,
+    /// where the next mapping goes
+    next_map: u64
 }
-impl SplitMemory {
+impl<D: Sparse> SplitMemory<D> {
     pub fn new(object: Vec<u8>) -> MemoryResult<Self> {
         if object.len() % 4 != 0 {
             Err(Unaligned)
@@ -14,67 +18,226 @@ impl SplitMemory {
         else {
             Ok(Self {
                 object,
-                data: BTreeMemory::new()
+                data: D::new(),
+                regions: BTreeMap::new(),
+                next_map: Self::MAP_BASE
             })
         }
     }
 }
-impl Memory for SplitMemory {
+
+/// which storage a run of addresses is in. regions go by their base address
+enum Part {
+    Object,
+    Region(u32),
+    Data
+}
+impl<D> SplitMemory<D> {
+    /// mapped files go upwards from here, past anywhere a program is likely to keep data
+    const MAP_BASE: u64 = 0xc000_0000;
+
+    /// the part `addr` is in, and how much of `len` stays in it
+    fn part(&self, addr: u32, len: u32) -> (Part, u32) {
+        let obj = self.object.len() as u32;
+        if addr < obj {
+            return (Part::Object, len.min(obj - addr))
+        }
+        if self.regions.is_empty() {
+            return (Part::Data, len)
+        }
+        if let Some((&base, r)) = self.regions.range(..=addr).next_back() {
+            if addr - base < r.len() {
+                return (Part::Region(base), len.min(r.len() - (addr - base)))
+            }
+        }
+        let next = self.regions.range(addr..).next().map_or(len, |(&base, _)| base - addr);
+        (Part::Data, len.min(next))
+    }
+}
+impl<D: Memory> Memory for SplitMemory<D> {
     fn read_u32(&self, addr: u32) -> MemoryResult<u32> {
-        if addr >= self.object.len() as u32 {
-            self.data.read_u32(addr)
-        }
-        else {
-            self.object.read_u32(addr)
+        match self.part(addr, 4).0 {
+            Part::Object => self.object.read_u32(addr),
+            Part::Region(b) => self.regions[&b].read_u32(addr - b),
+            Part::Data => self.data.read_u32(addr)
         }
     }
     fn read_u16(&self, addr: u32) -> MemoryResult<u16> {
-        if addr >= self.object.len() as u32 {
-            self.data.read_u16(addr)
-        }
-        else {
-            self.object.read_u16(addr)
+        match self.part(addr, 2).0 {
+            Part::Object => self.object.read_u16(addr),
+            Part::Region(b) => self.regions[&b].read_u16(addr - b),
+            Part::Data => self.data.read_u16(addr)
         }
     }
     fn read_u8(&self, addr: u32) -> MemoryResult<u8> {
-        if addr >= self.object.len() as u32 {
-            self.data.read_u8(addr)
-        }
-        else {
-            Ok(self.object[addr as usize])
+        match self.part(addr, 1).0 {
+            Part::Object => Ok(self.object[addr as usize]),
+            Part::Region(b) => self.regions[&b].read_u8(addr - b),
+            Part::Data => self.data.read_u8(addr)
         }
     }
     fn read_slice(&self, addr: u32, len: u32) -> MemoryResult<&[u8]> {
-        if addr < self.object.len() as u32 {
-            self.object.read_slice(addr, len)
-        }
-        else {
-            self.data.read_slice(addr, len)
+        match self.part(addr, len) {
+            (Part::Object, n) => self.object.read_slice(addr, n),
+            (Part::Region(b), n) => self.regions[&b].read_slice(addr - b, n),
+            (Part::Data, n) => self.data.read_slice(addr, n)
         }
     }
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let (mut addr, mut at) = (addr, 0);
+        while at < buf.len() {
+            let (part, n) = self.part(addr, (buf.len() - at) as u32);
+            let out = &mut buf[at..at + n as usize];
+            match part {
+                Part::Object => out.copy_from_slice(&self.object[addr as usize..addr as usize + out.len()]),
+                Part::Region(b) => self.regions[&b].read_into(addr - b, out)?,
+                Part::Data => self.data.read_into(addr, out)?
+            }
+            addr = addr.wrapping_add(n);
+            at += n as usize;
+        }
+        Ok(())
+    }
 
     fn write_u32(&mut self, addr: u32, v: u32) -> MemoryResult<()> {
-        if addr < self.object.len() as u32 {
-            self.object.write_u32(addr, v)
-        }
-        else {
-            self.data.write_u32(addr, v)
+        match self.part(addr, 4).0 {
+            Part::Object => self.object.write_u32(addr, v),
+            Part::Region(b) => self.regions.get_mut(&b).unwrap().write_u32(addr - b, v),
+            Part::Data => self.data.write_u32(addr, v)
         }
     }
     fn write_u16(&mut self, addr: u32, v: u16) -> MemoryResult<()> {
-        if addr < self.object.len() as u32 {
-            self.object.write_u16(addr, v)
-        }
-        else {
-            self.data.write_u16(addr, v)
+        match self.part(addr, 2).0 {
+            Part::Object => self.object.write_u16(addr, v),
+            Part::Region(b) => self.regions.get_mut(&b).unwrap().write_u16(addr - b, v),
+            Part::Data => self.data.write_u16(addr, v)
         }
     }
     fn write_u8(&mut self, addr: u32, v: u8) -> MemoryResult<()> {
-        if addr < self.object.len() as u32 {
-            self.object.write_u8(addr, v)
-        }
-        else {
-            self.data.write_u8(addr, v)
+        match self.part(addr, 1).0 {
+            Part::Object => self.object.write_u8(addr, v),
+            Part::Region(b) => self.regions.get_mut(&b).unwrap().write_u8(addr - b, v),
+            Part::Data => self.data.write_u8(addr, v)
         }
     }
+
+    fn write_from(&mut self, addr: u32, buf: &[u8]) -> MemoryResult<()> {
+        check_range(addr, buf.len() as u32)?;
+        let (mut addr, mut at) = (addr, 0);
+        while at < buf.len() {
+            let (part, n) = self.part(addr, (buf.len() - at) as u32);
+            let src = &buf[at..at + n as usize];
+            match part {
+                Part::Object => self.object[addr as usize..addr as usize + src.len()].copy_from_slice(src),
+                Part::Region(b) => self.regions.get_mut(&b).unwrap().write_from(addr - b, src)?,
+                Part::Data => self.data.write_from(addr, src)?
+            }
+            addr = addr.wrapping_add(n);
+            at += n as usize;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, dst: u32, v: u8, len: u32) -> MemoryResult<()> {
+        check_range(dst, len)?;
+        let (mut addr, mut left) = (dst, len);
+        while left > 0 {
+            let (part, n) = self.part(addr, left);
+            match part {
+                Part::Object => self.object[addr as usize..(addr + n) as usize].fill(v),
+                Part::Region(b) => self.regions.get_mut(&b).unwrap().fill(addr - b, v, n)?,
+                Part::Data => self.data.fill(addr, v, n)?
+            }
+            addr = addr.wrapping_add(n);
+            left -= n;
+        }
+        Ok(())
+    }
+
+    /// data never holds anything under the object or a region, so it can all go
+    fn discard(&mut self, addr: u32, len: u32) {
+        self.data.discard(addr, len)
+    }
+
+    fn map(&mut self, region: FileRegion) -> Option<u32> {
+        let base = self.next_map.max((self.object.len() as u64).next_multiple_of(FileRegion::ALIGN));
+        let end = base + (region.len() as u64).next_multiple_of(FileRegion::ALIGN);
+        if end > 1 << 32 {
+            return None
+        }
+        self.regions.insert(base as u32, region);
+        self.next_map = end;
+        Some(base as u32)
+    }
+}
+
+/// mapped files go in as where they came from plus whatever the guest stored to them
+impl<D: Persist> Persist for SplitMemory<D> {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        put_bytes(w, &self.object)?;
+        self.data.save(w)?;
+        put_u32(w, self.regions.len() as u32)?;
+        for (base, r) in &self.regions {
+            put_u32(w, *base)?;
+            r.save(w)?
+        }
+        Ok(())
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let object = get_bytes(r)?;
+        if object.len() % 4 != 0 {
+            return Err(SnapshotError::Corrupt("unaligned object region"))
+        }
+        let data = D::load(r)?;
+        let mut regions = BTreeMap::new();
+        // they were saved in order, so each starts after the last one ends
+        let mut end = object.len() as u64;
+        for _ in 0..get_u32(r)? {
+            let base = get_u32(r)?;
+            let region = FileRegion::load(r)?;
+            if (base as u64) < end || base as u64 + region.len() as u64 > 1 << 32 {
+                return Err(SnapshotError::Corrupt("overlapping mapped files"))
+            }
+            end = base as u64 + (region.len() as u64).next_multiple_of(FileRegion::ALIGN);
+            regions.insert(base, region);
+        }
+        let next_map = end.max(Self::MAP_BASE);
+        Ok(Self { object, data, regions, next_map })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conformance() {
+        // the object ends part way into the first data block
+        let layout = conformance::Layout { backed: 0..0x2100, seams: vec![0x800, 0x1000, 0x2000], uninit: Some(0x5003), end: None };
+        conformance::check(|| MainMemory::new(vec![0; 0x800]).unwrap(), &layout);
+    }
+
+    #[test]
+    fn regions() {
+        let mut m = MainMemory::new(vec![0; 8]).unwrap();
+        let a = m.map(FileRegion::owned(vec![1, 2, 3, 4, 5], false)).unwrap();
+        let b = m.map(FileRegion::owned(vec![6; 0x1001], true)).unwrap();
+        assert_eq!(b - a, 0x1000);
+        m.write_u32(a - 4, 0x0807_0605).unwrap();
+        assert_eq!(m.read_u32(a), Ok(0x0403_0201));
+        assert_eq!(m.read_u8(a + 5), Err(Uninit));
+        assert_eq!(m.write_u8(a, 0), Err(ReadOnly));
+        m.fill(b + 0xffe, 9, 2).unwrap();
+        let chunks: Vec<_> = m.chunks(a - 4, 9).map(|c| c.unwrap().len()).collect();
+        assert_eq!(chunks, [4, 5]);
+
+        let mut buf = Vec::new();
+        m.save(&mut buf).unwrap();
+        let mut l = MainMemory::load(&mut &buf[..]).unwrap();
+        assert_eq!(l.read_u32(a), Ok(0x0403_0201));
+        assert_eq!(l.read_u32(b + 0xffc), Ok(0x0909_0606));
+        assert_eq!(l.write_u8(a, 0), Err(ReadOnly));
+        assert_eq!(l.map(FileRegion::owned(vec![0], false)), Some(b + 0x2000));
+    }
 }
@@ -19,32 +19,29 @@ impl<T> ResultInner<T> for Result<T, T> {
 macro_rules! read_t_from_slice {
     ($name:ident, $t:ty) => {
         pub fn $name(s: &[u8], idx: usize) -> MemoryResult<$t> {
-            if s.len() < idx + std::mem::size_of::<$t>() {
-                return Err(OutOfBounds)
-            }
+            // alignment first, so an unaligned access says so even where it would cross the end
             if idx % std::mem::size_of::<$t>() != 0 {
                 return Err(Unaligned)
             }
-            unsafe {
-                let wptr = s.as_ptr().offset(idx as isize) as *mut $t;
-                Ok(<$t>::from_le(*wptr))
+            if s.len() < idx + std::mem::size_of::<$t>() {
+                return Err(OutOfBounds)
             }
+            // blocks are byte arrays, so the slice itself may not be aligned for $t
+            let bytes = s[idx..idx + std::mem::size_of::<$t>()].try_into().unwrap();
+            Ok(<$t>::from_le_bytes(bytes))
         }
     };
 }
 macro_rules! write_t_to_slice {
     ($name:ident, $t:ty) => {
         pub fn $name(s: &mut [u8], idx: usize, v: $t) -> MemoryResult<()> {
-            if s.len() < idx + std::mem::size_of::<$t>() {
-                return Err(OutOfBounds)
-            }
             if idx % std::mem::size_of::<$t>() != 0 {
                 return Err(Unaligned)
             }
-            unsafe {
-                let wptr = s.as_mut_ptr().offset(idx as isize) as *mut $t;
-                *wptr = v.to_le()
+            if s.len() < idx + std::mem::size_of::<$t>() {
+                return Err(OutOfBounds)
             }
+            s[idx..idx + std::mem::size_of::<$t>()].copy_from_slice(&v.to_le_bytes());
             Ok(())
         }
     };
@@ -54,3 +51,18 @@ read_t_from_slice!(read_u32_from_slice, u32);
 read_t_from_slice!(read_u16_from_slice, u16);
 write_t_to_slice!(write_u32_to_slice, u32);
 write_t_to_slice!(write_u16_to_slice, u16);
+
+/// there's no libc dependency, so mmap and friends go straight to the kernel
+#[cfg(all(any(test, feature = "jit", feature = "mem-flat"), target_arch = "x86_64", target_os = "linux"))]
+pub unsafe fn syscall6(n: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> isize {
+    let ret: isize;
+    std::arch::asm!(
+        "syscall",
+        inlateout("rax") n as isize => ret,
+        in("rdi") a1, in("rsi") a2, in("rdx") a3,
+        in("r10") a4, in("r8") a5, in("r9") a6,
+        lateout("rcx") _, lateout("r11") _,
+        options(nostack)
+    );
+    ret
+}
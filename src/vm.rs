@@ -7,21 +7,199 @@ use crate::memory;
 
 
 pub mod instruction;
+pub mod snapshot;
+pub mod trace;
+pub mod replay;
+pub mod history;
+pub mod aot;
+pub mod machine;
+pub mod shadow;
+pub mod timing;
+pub mod predict;
+pub mod profile;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+mod icache;
 mod registers;
 
-struct VM {
+/// size of a full-width instruction in bytes
+pub const ILEN: u32 = 4;
+
+pub struct VM {
     registers: registers::Registers,
+    tracer: Option<trace::Tracer>,
+    io_log: Option<replay::IoLog>,
+    history: Option<history::History>,
+    shadow: Option<shadow::Shadow>,
+    timing: Option<timing::Timing>,
+    branches: Option<predict::BranchSim>,
+    profile: Option<profile::Profile>,
+    icache: Option<icache::ICache>,
+    /// instructions executed so far
+    cycles: u64,
+    /// id within `harts`, which are the other vms in a `Machine`
+    hart: u32,
+    harts: std::sync::Arc<machine::Harts>,
+    /// set by the stop io funct, ends the current cycle with an exit
+    stopped: bool,
 }
 impl VM {
-    fn new() -> Self {
-        todo!()
+    pub fn new() -> Self {
+        Self {
+            registers: registers::Registers::new(),
+            tracer: None,
+            io_log: None,
+            history: None,
+            shadow: None,
+            timing: None,
+            branches: None,
+            profile: None,
+            icache: Some(icache::ICache::new()),
+            cycles: 0,
+            hart: 0,
+            harts: std::sync::Arc::new(machine::Harts::new(1)),
+            stopped: false,
+        }
+    }
+
+    pub fn set_hart(&mut self, id: u32, harts: std::sync::Arc<machine::Harts>) {
+        self.hart = id;
+        self.harts = harts;
+    }
+    /// fresh registers, with the pc at `pc` and `arg` in r1
+    pub fn start(&mut self, pc: u32, arg: u32) {
+        self.registers = registers::Registers::new();
+        self.registers.write(RS::PC, pc);
+        self.registers.write(RS::new(1).unwrap(), arg);
+    }
+
+    /// start or stop emitting a trace record for every cycle
+    pub fn set_tracer(&mut self, t: Option<trace::Tracer>) {
+        self.tracer = t
+    }
+    pub fn flush_trace(&mut self) -> std::io::Result<()> {
+        match &mut self.tracer {
+            Some(t) => t.flush(),
+            None => Ok(())
+        }
+    }
+
+    /// record every value io hands back to the guest, or serve them from an earlier recording
+    pub fn set_io_log(&mut self, l: Option<replay::IoLog>) {
+        self.io_log = l
+    }
+    /// flushes a recording, or checks that a replay used up the whole log
+    pub fn finish_io_log(&mut self) -> Result<(), VMError> {
+        match &mut self.io_log {
+            Some(replay::IoLog::Record(r)) => r.flush().map_err(|_| VMError::IoLog),
+            Some(replay::IoLog::Replay(r)) => r.finish().map_err(VMError::Diverged),
+            None => Ok(())
+        }
+    }
+
+    /// instructions executed so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    /// the next instruction to run, or the one that just failed
+    pub fn pc(&self) -> u32 {
+        self.registers.read(RS::PC)
+    }
+    /// register `r` of the current window
+    pub fn register(&self, r: u8) -> Option<u32> {
+        RS::new(r).map(|r| self.registers.read(r))
+    }
+
+    /// the decoded instruction cache is on by default
+    pub fn set_icache(&mut self, enabled: bool) {
+        self.icache = if enabled { Some(icache::ICache::new()) } else { None }
+    }
+    /// needed after modifying code in memory without going through this vm
+    pub fn invalidate_code(&mut self, addr: u32, len: u32) {
+        if let Some(c) = &mut self.icache {
+            c.invalidate(addr, len)
+        }
+    }
+
+    /// start keeping an undo log, with a full checkpoint every `interval` cycles and only the last `keep` of them kept
+    pub fn enable_history(&mut self, interval: u64, keep: usize) {
+        self.history = Some(history::History::new(interval, keep, &self.registers))
+    }
+
+    /// start tracking which bits of registers and memory are undefined, reporting reads and branches that use them
+    ///
+    /// reverse execution doesn't take the tracking back with it
+    pub fn enable_shadow(&mut self) {
+        self.shadow = Some(shadow::Shadow::new())
+    }
+    pub fn shadow_reports(&self) -> &[shadow::Report] {
+        self.shadow.as_ref().map_or(&[], |s| s.reports())
+    }
+
+    /// start counting the cycles an in-order pipeline would need for what runs from here on
+    pub fn enable_timing(&mut self, config: timing::Config) {
+        self.timing = Some(timing::Timing::new(config))
+    }
+    pub fn timing(&self) -> Option<&timing::Timing> {
+        self.timing.as_ref()
+    }
+
+    /// start predicting skips with `p`, and returns with a return address stack, keeping count of how it goes
+    pub fn enable_branch_sim(&mut self, p: Box<dyn predict::Predictor>) {
+        self.branches = Some(predict::BranchSim::new(p))
+    }
+    pub fn branch_sim(&self) -> Option<&predict::BranchSim> {
+        self.branches.as_ref()
+    }
+
+    /// start counting the instructions run at each pc and in each function
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(profile::Profile::new())
+    }
+    pub fn profile(&self) -> Option<&profile::Profile> {
+        self.profile.as_ref()
+    }
+
+    /// undoes the last cycle, returning the pc it ran at
+    pub fn reverse_step<M: memory::Memory>(&mut self, memory: &mut M) -> Option<u32> {
+        let pc = self.history.as_mut()?.undo(&mut self.registers, memory)?;
+        self.cycles -= 1;
+        self.invalidate_code(0, u32::MAX);
+        Some(pc)
+    }
+    /// steps back until the next instruction to run is on a breakpoint
+    pub fn reverse_continue<M: memory::Memory>(&mut self, memory: &mut M, breakpoints: &std::collections::BTreeSet<u32>) -> Option<u32> {
+        let h = self.history.as_mut()?;
+        let before = h.len();
+        let pc = h.reverse_continue(breakpoints, &mut self.registers, memory);
+        self.cycles -= before - h.len();
+        self.invalidate_code(0, u32::MAX);
+        pc
+    }
+    /// goes back to the state before the `step`th recorded cycle
+    pub fn rewind_to<M: memory::Memory>(&mut self, memory: &mut M, step: u64) {
+        if let Some(h) = &mut self.history {
+            let before = h.len();
+            h.rewind_to(step, &mut self.registers, memory);
+            self.cycles -= before - h.len();
+            self.invalidate_code(0, u32::MAX);
+        }
+    }
+    /// which recorded cycle last stored to `addr`, as (step, pc)
+    pub fn last_write(&self, addr: u32) -> Option<(u64, u32)> {
+        self.history.as_ref()?.last_write(addr)
     }
 
     /// returns true on exit command
-    fn cycle<M: memory::Memory>(&mut self, io: &mut io::IoHandler, memory: &mut M) -> Result<bool, VMError> {
+    pub fn cycle<M: memory::Memory>(&mut self, io: &mut io::IoHandler, memory: &mut M) -> Result<bool, VMError> {
         let pc = self.registers.read(RS::PC);
-        let iw = memory.read_u32(pc)?;
-        let i = Instruction::from_iword(iw);
+        let (iw, i) = match &mut self.icache {
+            Some(c) => c.fetch(pc, memory)?,
+            None => {
+                let iw = memory.fetch(pc)?;
+                (iw, Instruction::from_iword(iw))
+            }
+        };
 
         let s1 = self.registers.read(i.rs1);
         let s2 = i.select_source_2(self.registers.read(i.rs2));
@@ -29,66 +207,138 @@ impl VM {
 
         let idata = InsData::new(s1, s2, s3);
 
-        let mut next_pc = pc; // actually set to one less than the next address to execute because it gets incremented at the end of the cycle
+        let mut next_pc = pc; // actually set to one instruction before the next address to execute because it gets incremented at the end of the cycle
         let mut exec_result = 0; // all instructions return a value
+        let mut window = None;
+        let mut popped = None;
 
         use Opcode::*;
+        let store = instruction::mem::store_target(i.opcode, i.funct, s1, s2, s3);
+        let store_undo = match (&mut self.history, store) {
+            (Some(h), Some((addr, w))) => Some(h.capture_store(addr, w, memory)),
+            _ => None
+        };
+        let effect = self.shadow.as_mut().map(|s| s.before(pc, &i, idata, memory));
+        // failed cas and sc don't store
+        let mut stored = store.is_some();
+        let old_fcsr = self.registers.fcsr;
         if let Io = i.opcode {
-            todo!()
+            exec_result = self.io(io, i.funct, idata, pc, memory).map_err(|e| self.fault(pc, iw, &i, idata, e))?;
+        }
+        else if i.opcode == Ld && instruction::mem::is_atomic(i.funct) {
+            (exec_result, stored) = self.atomic(i.funct, idata, memory).map_err(|e| self.fault(pc, iw, &i, idata, e))?;
+        }
+        else if i.opcode == Arith && instruction::float::is_float(i.funct) {
+            exec_result = instruction::float::float(s1, s2, s3, i.funct, &mut self.registers.fcsr)
+                .map_err(|e| self.fault(pc, iw, &i, idata, e.into()))?;
         }
         else {
-            let res = Self::exec_instruction(i.opcode, idata, i.funct, pc, memory)?;
+            let res = Self::exec_instruction(i.opcode, idata, i.funct, pc, memory).map_err(|e| self.fault(pc, iw, &i, idata, e))?;
             match res {
                 Exec::Normal(v) => {
                     exec_result = v;
                 }
                 Exec::Skip(v) => {
                     exec_result = v;
-                    next_pc += 1;
+                    next_pc = next_pc.wrapping_add(ILEN);
                 }
                 Exec::Call(ret, pc) => { // jump destinations ARE incremented
                     // hence call 0 is save and return pc is restore
                     self.registers.call();
                     exec_result = ret; // return value is written AFTER window shift
                     next_pc = pc;
+                    window = Some(trace::WindowEvent::Call);
+                }
+                Exec::Jump(ret, pc) => {
+                    exec_result = ret;
+                    next_pc = pc;
                 }
                 Exec::Return(pc) => {
-                    self.registers.ret(); // return value is read BEFORE register shift
+                    popped = self.registers.ret(); // return value is read BEFORE register shift
                     next_pc = pc;
+                    window = Some(trace::WindowEvent::Return);
                 }
             }
         }
-        self.registers.write(RS::PC, next_pc + 1);
+        if let (true, Some((addr, w))) = (stored, store) {
+            // self modifying code
+            if let Some(c) = &mut self.icache {
+                c.invalidate(addr, w)
+            }
+            self.harts.stored(addr, w)
+        }
+        self.registers.write(RS::PC, next_pc.wrapping_add(ILEN));
         if i.rd == RS::PC {
-            exec_result += 1 // increment!
+            exec_result = exec_result.wrapping_add(ILEN) // increment!
         }
+        let old_rd = self.registers.read(i.rd);
         self.registers.write(i.rd, exec_result); // write result after incrementing pc, to allow jumping with arithmetic instructions
+        if let (Some(s), Some(e)) = (&mut self.shadow, effect) {
+            s.after(pc, &i, e, exec_result, stored, window)
+        }
+        if let Some(t) = &mut self.timing {
+            t.retire(&i, idata, next_pc != pc || i.rd == RS::PC, window)
+        }
+        if let Some(b) = &mut self.branches {
+            b.observe(pc, &i, window, self.registers.read(RS::PC))
+        }
+        if let Some(p) = &mut self.profile {
+            p.observe(pc, window, self.registers.read(RS::PC))
+        }
+
+        if let Some(h) = &mut self.history {
+            let w = match window {
+                Some(trace::WindowEvent::Call) => Some(history::WindowUndo::Call),
+                Some(trace::WindowEvent::Return) => popped.map(history::WindowUndo::Return),
+                None => None
+            };
+            let regs = history::RegUndo { rd: i.rd, old_rd, old_fcsr };
+            h.push(pc, regs, w, store_undo, &self.registers);
+        }
+
+        if let Some(t) = &mut self.tracer {
+            let r = trace::TraceRecord {
+                pc, iword: iw,
+                opcode: i.opcode, funct: i.funct,
+                s1, s2, s3,
+                rd: i.rd.inner(), value: exec_result,
+                mem: Self::traced_access(&i, idata, exec_result, stored), window,
+                fault: false
+            };
+            t.record(&r).map_err(|_| VMError::Trace)?;
+        }
+        self.cycles += 1;
         
-        Ok(false)
+        Ok(std::mem::take(&mut self.stopped))
     }
 
     fn exec_instruction<M: memory::Memory>(opcode: Opcode, d: InsData, funct: u32, pc: u32, memory: &mut M) -> Result<Exec, VMError> {
         use Opcode::*;
         Ok(match opcode {
-            Arith => instruction::arithmetic::arithmetic(d.s1, d.s2, funct).map(Exec::Normal).ok_or(VMError::Arith)?,
-            ArithSkip => instruction::arithmetic::arithmetic(d.s1, d.s2, funct)
+            Arith => instruction::arithmetic::arithmetic(d.s1, d.s2, d.s3, funct).map(Exec::Normal).ok_or(VMError::Arith)?,
+            ArithSkip => instruction::arithmetic::arithmetic(d.s1, d.s2, d.s3, funct)
                 .map(|v| if v != 0 { Exec::Skip(v) } else { Exec::Normal(v) })
                 .ok_or(VMError::Arith)?,
             ImmUpper => instruction::immupper::imm_upper(d.s1, d.s2, funct).map(Exec::Normal).ok_or(VMError::ImmUpper)?,
+            Ld if funct == instruction::mem::COMPARE => Exec::Normal(instruction::mem::compare(d.s1, d.s2, d.s3, memory)?),
             Ld => instruction::mem::load(d.s1, d.s2, funct, memory).map(Exec::Normal)?,
             St => {
                 instruction::mem::store(d.s1, d.s2, d.s3, funct, memory)?;
                 Exec::Normal(0)
             },
             Func => { // increments happen inside the main exec loop
-                if funct != 0 { // return
-                    Exec::Return(d.s2)
-                }
-                else { // call
-                    let old_pc = pc; // gets incremented on return
-                    let pc = pc.wrapping_add_signed(d.s2 as i32);
-
-                    Exec::Call(old_pc, pc)
+                use instruction::func::*;
+                let old_pc = pc; // gets incremented on return
+                let relative = pc.wrapping_add_signed(d.s2 as i32);
+                // backed off by one instruction so it lands exactly on s1 + s2
+                let indirect = d.s1.wrapping_add(d.s2).wrapping_sub(ILEN);
+                match funct {
+                    RET => Exec::Return(d.s2),
+                    CALL => Exec::Call(old_pc, relative),
+                    CALL_INDIRECT => Exec::Call(old_pc, indirect),
+                    TAIL => Exec::Jump(old_pc, relative),
+                    TAIL_INDIRECT => Exec::Jump(old_pc, indirect),
+                    _ => return Err(VMError::Func)
                 }
             }
             Comp => return Err(VMError::Compressed),
@@ -96,8 +346,103 @@ impl VM {
         })
     }
 
-    fn io(&mut self, s1: u32, s2: u32, s3: u32, funct: u32) -> Result<u32, VMError> {
-        Ok(0)
+    fn io<M: memory::Memory>(&mut self, io: &mut io::IoHandler, funct: u32, d: InsData, pc: u32, memory: &mut M) -> Result<u32, VMError> {
+        use replay::*;
+        use machine::*;
+        if let IO_HART_ID | IO_HART_START | IO_HART_STOP = funct {
+            // these only depend on the schedule, so they stay out of io logs
+            return Ok(self.hart_io(funct, d)?)
+        }
+        if let memory::IO_MALLOC | memory::IO_FREE = funct {
+            // nothing outside the guest is involved either
+            let site = if d.s3 != 0 { d.s3 } else { pc };
+            if funct == memory::IO_MALLOC {
+                memory.allocated(d.s1, d.s2, site)?
+            }
+            else {
+                memory.freed(d.s1, site)?
+            }
+            return Ok(0)
+        }
+        let res = match &mut self.io_log {
+            None => io.io(funct, d, memory),
+            Some(IoLog::Record(r)) => {
+                let res = io.io(funct, d, memory);
+                let mut e = IoEvent::new(self.cycles, pc, funct, d, res);
+                if let (io::IO_MMAP | io::IO_MMAP_COW, Ok(at)) = (funct, res) {
+                    // peeked, so recording doesn't show up in cache statistics
+                    e.mapped = Some((0..d.s3).map(|i| memory.peek_u8(at + i)).collect::<Result<_, _>>()?)
+                }
+                r.record(&e).map_err(|_| VMError::IoLog)?;
+                res
+            }
+            Some(IoLog::Replay(r)) => {
+                r.replay(IoEvent::new(self.cycles, pc, funct, d, Ok(0)), memory).map_err(VMError::Diverged)?
+            }
+        };
+        Ok(res?)
+    }
+
+    fn hart_io(&mut self, funct: u32, d: InsData) -> io::IoResult<u32> {
+        match funct {
+            machine::IO_HART_ID => Ok(self.hart),
+            machine::IO_HART_STOP => {
+                self.stopped = true;
+                Ok(0)
+            }
+            _ => self.harts.start(d.s2, d.s1, d.s3).map(|started| !started as u32),
+        }
+    }
+
+    /// runs an atomic, keeping track of load-reserved reservations. returns rd and whether memory was written
+    /// the memory access to trace for `i`, given what went to rd and whether it stored
+    fn traced_access(i: &Instruction, d: InsData, result: u32, stored: bool) -> Option<trace::MemAccess> {
+        use Opcode::*;
+        use instruction::mem;
+        use trace::MemAccess;
+        let word = |addr, len, value, store| Some(MemAccess { addr, len, value, store, src: None });
+        match i.opcode {
+            // the old value is already in rd, so a store shows what replaced it
+            Ld if mem::is_atomic(i.funct) && stored => word(d.s1, 4, if i.funct == mem::FETCH_ADD { result.wrapping_add(d.s3) } else { d.s3 }, true),
+            // a failed sc doesn't look at memory at all
+            Ld if i.funct == mem::SC => None,
+            Ld if mem::is_atomic(i.funct) => word(d.s1, 4, result, false),
+            // what it found goes to rd
+            Ld if i.funct == mem::COMPARE => Some(MemAccess { addr: d.s1, len: d.s3, value: 0, store: false, src: Some(d.s2) }),
+            Ld => word(mem::load_address(d.s1, d.s2, i.funct), mem::load_width(i.funct).unwrap_or(0), result, false),
+            St if i.funct == mem::COPY => Some(MemAccess { addr: d.s1, len: d.s3, value: 0, store: true, src: Some(d.s2) }),
+            St if i.funct == mem::FILL => word(d.s1, d.s3, d.s2 & 0xff, true),
+            St => word(mem::store_address(d.s1, d.s2, i.funct), mem::store_width(i.funct).unwrap_or(0), d.s3, true),
+            _ => None
+        }
+    }
+    /// traces `i` failing with `e`, as that's the record a trace most needs, then hands `e` back
+    ///
+    /// nothing it would have written happened, so rd's value is 0
+    fn fault(&mut self, pc: u32, iw: u32, i: &Instruction, d: InsData, e: VMError) -> VMError {
+        if let Some(t) = &mut self.tracer {
+            let r = trace::TraceRecord {
+                pc, iword: iw,
+                opcode: i.opcode, funct: i.funct,
+                s1: d.s1, s2: d.s2, s3: d.s3,
+                rd: i.rd.inner(), value: 0,
+                mem: Self::traced_access(i, d, 0, false), window: None,
+                fault: true
+            };
+            // the fault is what the caller needs to hear about, a broken trace shows up when it's flushed
+            let _ = t.record(&r);
+        }
+        e
+    }
+
+    fn atomic<M: memory::Memory>(&mut self, funct: u32, d: InsData, memory: &mut M) -> Result<(u32, bool), VMError> {
+        use instruction::mem;
+        let reserved = funct == mem::SC && self.harts.take_reservation(self.hart, d.s1);
+        let res = mem::atomic(d.s1, d.s2, d.s3, funct, reserved, memory)?;
+        if funct == mem::LR {
+            self.harts.reserve(self.hart, d.s1)
+        }
+        Ok(res)
     }
 }
 
@@ -108,11 +453,17 @@ pub enum Exec {
     /// (return, new_pc)
     Call(u32, u32),
     Return(u32),
+    /// a call without the window shift: (return, new_pc)
+    Jump(u32, u32),
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    extern crate test;
     use super::*;
+    use snapshot::Persist;
+    use memory::Memory;
+    use instruction::encode::*;
     #[test]
     fn sanity() {
         
@@ -133,30 +484,511 @@ mod tests {
 
         assert_eq!(VM::exec_instruction(Opcode::Func, idata, 0, 0, &mut mem), Ok(Exec::Call(0, 4)));
     }
+
+    pub fn program(words: &[u32]) -> memory::MainMemory {
+        memory::MainMemory::new(words.iter().flat_map(|w| w.to_le_bytes()).collect()).unwrap()
+    }
+
+    /// a host file holding `contents`, removed again when this is dropped
+    pub struct TempFile(pub std::path::PathBuf);
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+    pub fn temp_file(name: &str, contents: &[u8]) -> TempFile {
+        let path = std::env::temp_dir().join(format!("raven-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+
+    /// xorshift, so failures are reproducible
+    pub struct Rng(pub u64);
+    impl Rng {
+        pub fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 16) as u32
+        }
+        pub fn below(&mut self, n: u32) -> u32 {
+            self.next() % n
+        }
+        pub fn pick<T: Copy>(&mut self, xs: &[T]) -> T {
+            xs[self.below(xs.len() as u32) as usize]
+        }
+    }
+
+    /// straight line code that can't fault, with loads and stores within 0x100 bytes of r1
+    pub fn random_instruction(r: &mut Rng) -> u32 {
+        // r1 holds the data pointer and r2 is the pc, so neither gets written
+        let rd = 3 + r.below(29);
+        let rs1 = r.below(32);
+        let rs2 = r.below(32);
+        let alu = [0, 2, 4, 5, 6, 7, 8, 9, 10, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+        let shifts = [16, 17, 18, 19, 20, 21];
+        match r.below(10) {
+            0 => arith_imm(rd, rs1, r.pick(&alu), r.next() as i32 >> 19),
+            1 => arith_reg(rd, rs1, rs2, r.pick(&alu)),
+            // shift amounts of 32 and up panic in the interpreter
+            2 => arith_imm(rd, rs1, r.pick(&shifts), r.below(32) as i32),
+            3 => skip_imm(rd, rs1, r.pick(&alu[9..]), r.next() as i32 >> 19),
+            4 => skip_reg(rd, rs1, rs2, r.pick(&alu[9..])),
+            5 => imm_upper(rd, 4 + r.below(4), r.next()),
+            6 => {
+                let funct = r.below(5);
+                let w = instruction::mem::load_width(funct).unwrap();
+                load_imm(rd, 1, funct, (r.below(0x100 / w) * w) as i32)
+            }
+            7 => match r.below(5) {
+                0 => arith_reg(rd, rs1, rs2, 11),
+                1 => arith_reg3(rd, rs1, rs2, r.below(32), 64 + r.below(10)),
+                2 => arith_reg3(rd, rs1, rs2, r.below(32), 96 + r.below(10)),
+                3 => arith_reg3(rd, rs1, rs2, r.below(32), 128 + r.below(30)),
+                _ => arith_imm(rd, rs1, r.pick(&[1, 3]), r.below(1024) as i32),
+            }
+            _ => {
+                let funct = r.below(3);
+                let w = instruction::mem::store_width(funct).unwrap();
+                store_imm(1, rs2, funct, (r.below(0x100 / w) * w) as i32)
+            }
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traced_cycles() {
+        use trace::*;
+        let mut mem = program(&[arith_imm(8, 0, 0, 0x40), sw(8, 8), lw(9, 8, 0)]);
+        let mut io = io::IoHandler::new();
+        let buf = SharedBuf::default();
+
+        let mut vm = VM::new();
+        vm.set_tracer(Some(Tracer::new(Box::new(buf.clone()), TraceFormat::Binary).unwrap()));
+        for _ in 0..3 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+
+        let data = buf.0.lock().unwrap();
+        let recs: Vec<_> = TraceReader::new(data.as_slice()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(recs.len(), 3);
+        assert_eq!((recs[0].pc, recs[0].opcode, recs[0].rd, recs[0].value), (0, Opcode::Arith, 8, 0x40));
+        assert_eq!(recs[1].mem, Some(MemAccess { addr: 0x40, len: 4, value: 0x40, store: true, src: None }));
+        assert_eq!(recs[2].mem, Some(MemAccess { addr: 0x40, len: 4, value: 0x40, store: false, src: None }));
+        assert_eq!((recs[2].pc, recs[2].rd, recs[2].value), (8, 9, 0x40));
+        assert!(recs.iter().all(|r| !r.fault));
+    }
+
+    #[test]
+    fn traced_atomics() {
+        use trace::*;
+        use instruction::mem::{SWAP, FETCH_ADD, CAS, LR, SC};
+        let words = [
+            arith_imm(8, 0, 0, 0x40), arith_imm(9, 0, 0, 5), sw(8, 9),
+            load_reg(10, 8, 0, 9, FETCH_ADD), load_reg(11, 8, 0, 9, SWAP),
+            // cas against the wrong value, then the right one
+            load_reg(12, 8, 0, 9, CAS), load_reg(12, 8, 9, 0, CAS),
+            load_reg(13, 8, 0, 0, LR), load_reg(13, 8, 0, 9, SC), load_reg(13, 8, 0, 9, SC),
+        ];
+        let mut mem = program(&words);
+        let mut io = io::IoHandler::new();
+        let buf = SharedBuf::default();
+
+        let mut vm = VM::new();
+        vm.set_tracer(Some(Tracer::new(Box::new(buf.clone()), TraceFormat::Binary).unwrap()));
+        for _ in words {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+
+        let data = buf.0.lock().unwrap();
+        let recs: Vec<_> = TraceReader::new(data.as_slice()).unwrap().map(Result::unwrap).collect();
+        let access = |addr, value, store| Some(MemAccess { addr, len: 4, value, store, src: None });
+        let got: Vec<_> = recs[3..].iter().map(|r| (r.value, r.mem)).collect();
+        assert_eq!(got, [
+            (5, access(0x40, 10, true)), (10, access(0x40, 5, true)),
+            (5, access(0x40, 5, false)), (5, access(0x40, 0, true)),
+            (0, access(0x40, 0, false)), (0, access(0x40, 5, true)), (1, None),
+        ]);
+    }
+
+    #[test]
+    fn traced_bulk() {
+        use trace::*;
+        use instruction::mem::{COPY, FILL, COMPARE};
+        let words = [
+            arith_imm(8, 0, 0, 0x40), arith_imm(9, 0, 0, 0x80), arith_imm(10, 0, 0, 0x1ab), arith_imm(11, 0, 0, 0x10),
+            store_reg(8, 10, 11, FILL), store_reg(9, 8, 11, COPY), load_reg(12, 8, 9, 11, COMPARE),
+        ];
+        let mut mem = program(&words);
+        let mut io = io::IoHandler::new();
+        let buf = SharedBuf::default();
+
+        let mut vm = VM::new();
+        vm.set_tracer(Some(Tracer::new(Box::new(buf.clone()), TraceFormat::Binary).unwrap()));
+        for _ in words {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+
+        let data = buf.0.lock().unwrap();
+        let recs: Vec<_> = TraceReader::new(data.as_slice()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(recs[4].mem, Some(MemAccess { addr: 0x40, len: 0x10, value: 0xab, store: true, src: None }));
+        assert_eq!(recs[5].mem, Some(MemAccess { addr: 0x80, len: 0x10, value: 0, store: true, src: Some(0x40) }));
+        assert_eq!(recs[6].mem, Some(MemAccess { addr: 0x40, len: 0x10, value: 0, store: false, src: Some(0x80) }));
+        assert_eq!(recs[6].value, 0);
+        assert!(recs[5].to_string().ends_with(" st [00000080] <- [00000040] len=00000010"));
+        assert!(recs[4].to_string().ends_with(" st [00000040] <- ab len=00000010"));
+    }
+
+    #[test]
+    fn traced_fault() {
+        use trace::*;
+        // the second load is unaligned
+        let mut mem = program(&[arith_imm(8, 0, 0, 0x41), lw(9, 8, 0)]);
+        let mut io = io::IoHandler::new();
+        let buf = SharedBuf::default();
+
+        let mut vm = VM::new();
+        vm.set_tracer(Some(Tracer::new(Box::new(buf.clone()), TraceFormat::Binary).unwrap()));
+        vm.cycle(&mut io, &mut mem).unwrap();
+        assert!(vm.cycle(&mut io, &mut mem).is_err());
+        vm.flush_trace().unwrap();
+
+        let data = buf.0.lock().unwrap();
+        let recs: Vec<_> = TraceReader::new(data.as_slice()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(recs.len(), 2);
+        assert!(!recs[0].fault);
+        assert_eq!((recs[1].pc, recs[1].fault, recs[1].value), (4, true, 0));
+        assert_eq!(recs[1].mem.map(|m| m.addr), Some(0x41));
+    }
+
+    #[test]
+    fn record_replay() {
+        use replay::*;
+        // write 'h' then 'i' to stdout
+        let words = [arith_imm(8, 0, 0, 'h' as i32), io_imm(0, 8, 64, 1), arith_imm(8, 0, 0, 'i' as i32), io_imm(0, 8, 64, 1)];
+        let buf = SharedBuf::default();
+
+        let mut vm = VM::new();
+        vm.set_io_log(Some(IoLog::Record(Recorder::new(Box::new(buf.clone())).unwrap())));
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        for _ in 0..4 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        vm.finish_io_log().unwrap();
+
+        let log = buf.0.lock().unwrap().clone();
+        let mut vm = VM::new();
+        vm.set_io_log(Some(IoLog::Replay(Replayer::new(log.as_slice()).unwrap())));
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        for _ in 0..4 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(vm.finish_io_log(), Ok(()));
+        let mut replayed = Vec::new();
+        io.save(&mut replayed).unwrap();
+        let mut fresh = Vec::new();
+        io::IoHandler::new().save(&mut fresh).unwrap();
+        assert_eq!(replayed, fresh, "replay touched the io handler");
+
+        // a different character is a different request
+        let mut vm = VM::new();
+        vm.set_io_log(Some(IoLog::Replay(Replayer::new(log.as_slice()).unwrap())));
+        let mut mem = program(&[arith_imm(8, 0, 0, 'j' as i32), io_imm(0, 8, 64, 1)]);
+        vm.cycle(&mut io, &mut mem).unwrap();
+        match vm.cycle(&mut io, &mut mem) {
+            Err(VMError::Diverged(d)) => {
+                assert_eq!(d.index, 0);
+                assert_eq!(d.actual.unwrap().args[0], 'j' as u32);
+            }
+            r => panic!("expected divergence, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn record_replay_mmap() {
+        use replay::*;
+        let f = temp_file("replay-mmap", b"abcd");
+        let name = f.0.file_name().unwrap().to_str().unwrap().as_bytes().to_vec();
+        let io_reg = |rd, rs1, rs2: u32, rs3: u32, funct| io_imm(rd, rs1, funct, rs2 | rs3 << 5) & !1;
+        // open the file, map it and load from the mapping
+        let mut words = vec![
+            arith_imm(8, 0, 0, 0x40), arith_imm(10, 0, 0, name.len() as i32), io_reg(9, 8, 0, 10, io::IO_OPEN),
+            arith_imm(10, 0, 0, 4), io_reg(13, 0, 9, 10, io::IO_MMAP), lw(12, 13, 0),
+        ];
+        words.resize(0x10, 0);
+        words.extend(<[u8]>::chunks(&name, 4).map(|c| c.iter().rev().fold(0, |w, b| w << 8 | *b as u32)));
+        let buf = SharedBuf::default();
+
+        let mut vm = VM::new();
+        vm.set_io_log(Some(IoLog::Record(Recorder::new(Box::new(buf.clone())).unwrap())));
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        io.allow_host_files(f.0.parent().unwrap()).unwrap();
+        for _ in 0..6 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        vm.finish_io_log().unwrap();
+        let at = vm.register(13).unwrap();
+        assert_eq!(vm.register(12), Some(u32::from_le_bytes(*b"abcd")));
+
+        // the file is gone and nothing may be opened, but the mapping comes back from the log
+        drop(f);
+        let log = buf.0.lock().unwrap().clone();
+        let mut vm = VM::new();
+        vm.set_io_log(Some(IoLog::Replay(Replayer::new(log.as_slice()).unwrap())));
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        for _ in 0..6 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(vm.finish_io_log(), Ok(()));
+        assert_eq!((vm.register(13), vm.register(12)), (Some(at), Some(u32::from_le_bytes(*b"abcd"))));
+        assert_eq!(mem.write_u8(at, 0), Err(memory::MemoryError::ReadOnly));
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let words = [
+            arith_imm(8, 0, 0, 0x100), sw(8, 8), arith_imm(9, 0, 0, 7), call(16, 8),
+            sw(8, 9), arith_imm(10, 0, 0, 1),
+            arith_imm(8, 0, 0, 0x200), sw(8, 8), ret(16),
+        ];
+        let state = |vm: &VM, mem: &memory::MainMemory| {
+            (vm.registers.clone(), mem.read_u32(0x100).ok(), mem.read_u32(0x200).ok())
+        };
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_history(3, 100);
+
+        let mut states = Vec::new();
+        for _ in 0..9 {
+            states.push(state(&vm, &mem));
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(vm.registers.read(RS::PC), 24);
+        assert_eq!(vm.last_write(0x100), Some((7, 16)));
+        assert_eq!(vm.last_write(0x201), Some((5, 28)));
+        assert_eq!(vm.last_write(0x204), None);
+
+        // step all the way back through the return and the call
+        let end = state(&vm, &mem);
+        for s in states.iter().rev() {
+            assert!(vm.reverse_step(&mut mem).is_some());
+            assert_eq!(&state(&vm, &mem), s);
+        }
+        assert_eq!(vm.reverse_step(&mut mem), None);
+
+        for _ in 0..9 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(state(&vm, &mem), end);
+        assert_eq!(vm.cycles, 9);
+
+        vm.rewind_to(&mut mem, 4);
+        assert_eq!(state(&vm, &mem), states[4]);
+        assert_eq!(vm.cycles, 4);
+
+        vm.cycle(&mut io, &mut mem).unwrap();
+        vm.rewind_to(&mut mem, 1);
+        assert_eq!(state(&vm, &mem), states[1]);
+
+        for _ in 0..8 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        let bps = [4, 28].into_iter().collect();
+        assert_eq!(vm.reverse_continue(&mut mem, &bps), Some(28));
+        assert_eq!(state(&vm, &mem), states[5]);
+        assert_eq!(vm.reverse_continue(&mut mem, &bps), Some(4));
+        assert_eq!(state(&vm, &mem), states[1]);
+        assert_eq!(vm.reverse_continue(&mut mem, &bps), None);
+        assert_eq!(state(&vm, &mem), states[0]);
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut words = vec![arith_imm(8, 0, 0, 0x100)];
+        for _ in 0..10 {
+            words.extend([arith_imm(9, 9, 0, 1), sw(8, 9)]);
+        }
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_history(4, 2);
+        for _ in 0..21 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        // checkpoints at 16 and 20 are kept, so steps before 16 are gone
+        assert_eq!(vm.last_write(0x100), Some((20, 80)));
+        vm.rewind_to(&mut mem, 0);
+        assert_eq!(vm.cycles, 16);
+        assert_eq!(mem.read_u32(0x100), Ok(7));
+        assert_eq!(vm.last_write(0x100), None);
+        assert_eq!(vm.reverse_step(&mut mem), None);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let mut words = vec![arith_imm(9, 0, 0, 5), lw(10, 0, 32), sw(0, 10), arith_imm(2, 0, 0, -4)];
+        words.resize(8, 0);
+        words.push(arith_imm(9, 0, 0, 6));
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+
+        let mut vm = VM::new();
+        for _ in 0..4 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(vm.registers.read(RS::PC), 0);
+        assert_eq!(vm.registers.read(RS::new(9).unwrap()), 5);
+        vm.cycle(&mut io, &mut mem).unwrap();
+        assert_eq!(vm.registers.read(RS::new(9).unwrap()), 6);
+    }
+
+    #[test]
+    fn float_ops() {
+        use instruction::float::*;
+        let words = [
+            lw(8, 0, 32), lw(9, 0, 36), lw(10, 0, 40),
+            arith_reg3(11, 8, 9, 10, FMADD), arith_reg(12, 8, 0, FDIV), arith_reg(13, 0, 0, FRCSR),
+            arith_imm(14, 0, 0, (RTZ << 5) as i32), arith_reg(15, 14, 0, FSCSR),
+            1.5f32.to_bits(), 2f32.to_bits(), 0.25f32.to_bits(),
+        ];
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_history(100, 100);
+        for _ in 0..8 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        let r = |vm: &VM, n| vm.registers.read(RS::new(n).unwrap());
+        assert_eq!(f32::from_bits(r(&vm, 11)), 3.25);
+        assert_eq!(f32::from_bits(r(&vm, 12)), f32::INFINITY);
+        assert_eq!((r(&vm, 13), r(&vm, 15)), (DZ, DZ));
+        assert_eq!(vm.registers.fcsr, RTZ << 5);
+
+        // flags are part of what gets undone
+        vm.reverse_step(&mut mem);
+        assert_eq!(vm.registers.fcsr, DZ);
+        for _ in 0..3 {
+            vm.reverse_step(&mut mem);
+        }
+        assert_eq!(vm.registers.fcsr, 0);
+
+        vm.registers.fcsr = 5 << 5;
+        vm.registers.write(RS::PC, 12);
+        assert_eq!(vm.cycle(&mut io, &mut mem), Err(VMError::RoundingMode));
+    }
+
+    #[test]
+    fn bad_io() {
+        let (mut mem, mut io) = (program(&[io_imm(0, 0, 65, 0)]), io::IoHandler::new());
+        assert_eq!(VM::new().cycle(&mut io, &mut mem), Err(VMError::IoFunct));
+        // past the virtual descriptors, but never opened
+        assert_eq!(io.io(64, InsData::new(b'x' as u32, 40, 0), &mut mem), Err(io::IoError::BadFd));
+    }
+
+    #[test]
+    fn indirect_and_tail_calls() {
+        use instruction::func::*;
+        let f = 24;
+        let words = [
+            // sum 1..=1000 with a tail recursive f, called through a pointer in r10
+            arith_imm(8, 0, 0, 1000), arith_imm(10, 0, 0, f), func_reg(16, 10, 0, CALL_INDIRECT),
+            // then jump to the end through a pointer, with no window change
+            arith_imm(11, 0, 0, 44), func_reg(0, 11, 0, TAIL_INDIRECT), 0,
+
+            // f: r25 += r24, and go again unless r24 reaches 0
+            arith_reg(25, 25, 24, 0), arith_imm(24, 24, 0, -1), skip_imm(0, 24, 22, 0),
+            tail(0, -16), ret(16),
+            0,
+        ];
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        let mut vm = VM::new();
+        while vm.registers.read(RS::PC) != 44 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(vm.registers.read(RS::new(9).unwrap()), 500_500);
+        assert_eq!(vm.registers.read(RS::new(8).unwrap()), 0);
+        // back down to the two windows we started with
+        assert_eq!(std::iter::from_fn(|| vm.registers.ret()).count(), 2);
+
+        assert_eq!(VM::exec_instruction(Opcode::Func, InsData::new(0, 0, 0), 3, 0, &mut mem), Err(VMError::Func));
+    }
+
+    /// count up forever, with a skip that's never taken
+    fn spin(b: &mut test::Bencher, icache: bool) {
+        let words = [arith_imm(8, 8, 0, 1), skip_imm(0, 8, 22, 0), arith_imm(2, 0, 0, -4)];
+        let (mut mem, mut io) = (program(&words), io::IoHandler::new());
+        let mut vm = VM::new();
+        vm.set_icache(icache);
+        b.iter(|| {
+            for _ in 0..30_000 {
+                vm.cycle(&mut io, &mut mem).unwrap();
+            }
+            vm.registers.read(RS::new(8).unwrap())
+        })
+    }
+    #[bench]
+    fn spin_icache(b: &mut test::Bencher) {
+        spin(b, true)
+    }
+    #[bench]
+    fn spin_no_icache(b: &mut test::Bencher) {
+        spin(b, false)
+    }
 }
 
 #[derive(Debug, Error, PartialEq)]
-enum VMError {
+pub enum VMError {
     #[error("memory error: {0:?}")]
     Mem(memory::MemoryError),
+    #[error("{0}")]
+    Heap(Box<memory::HeapFault>),
     #[error("invalid arithmetic funct")]
     Arith,
+    #[error("invalid rounding mode")]
+    RoundingMode,
     #[error("invalid imm_upper funct")]
     ImmUpper,
     #[error("invalid load funct")]
     Ld,
     #[error("invalid store funct")]
     St,
+    #[error("invalid func funct")]
+    Func,
     #[error("invalid io funct")]
     IoFunct,
-    #[error("failed io operation")]
-    Io,
+    #[error("failed io operation: {0:?}")]
+    Io(io::IoError),
     #[error("compressed instructions dont exist yet")]
     Compressed,
+    #[error("failed to write trace")]
+    Trace,
+    #[error("failed to write io log")]
+    IoLog,
+    #[error("{0}")]
+    Diverged(Box<replay::Divergence>),
 }
 impl From<memory::MemoryError> for VMError {
     fn from(value: memory::MemoryError) -> Self {
-        Self::Mem(value)
+        match value {
+            memory::MemoryError::Heap(f) => Self::Heap(f),
+            m => Self::Mem(m)
+        }
+    }
+}
+impl From<instruction::float::FloatError> for VMError {
+    fn from(value: instruction::float::FloatError) -> Self {
+        use instruction::float::FloatError;
+        match value {
+            FloatError::Funct => Self::Arith,
+            FloatError::RoundingMode => Self::RoundingMode
+        }
     }
 }
 impl From<instruction::mem::LoadError> for VMError {
@@ -179,6 +1011,9 @@ impl From<instruction::mem::StoreError> for VMError {
 }
 impl From<io::IoError> for VMError {
     fn from(value: io::IoError) -> Self {
-        todo!()
+        match value {
+            io::IoError::Funct => Self::IoFunct,
+            e => Self::Io(e)
+        }
     }
 }
@@ -0,0 +1,635 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use thiserror::Error;
+
+use crate::io::{self, IoError};
+use crate::memory::{self, MemoryError};
+use super::{VMError, ILEN, machine};
+use super::instruction::{Instruction, Opcode, float, func, mem, packed};
+use super::registers::RegisterSelector as RS;
+
+const RUNTIME: &str = include_str!("aot/runtime.c");
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AotError {
+    #[error("bad object: {0:?}")]
+    Object(MemoryError),
+    /// rounding modes and fcsr flags would have to match the interpreter bit for bit
+    #[error("{0:#010x}: floating point isn't supported ahead of time")]
+    Float(u32),
+}
+
+/// translates an object into a standalone C program with the same behaviour as `raven run`
+///
+/// code is found by following control flow from address 0 through skips, calls, returns and jumps
+/// to constant addresses. anything else that writes the pc goes through a switch over the block leaders,
+/// so a computed jump into the middle of a block, or to code the walk never found, stops the program.
+/// that includes an indirect call to a function that is never called or jumped to directly.
+/// stores into translated code do too. reachable float ops are refused outright
+pub fn translate(object: &[u8]) -> Result<String, AotError> {
+    if !object.len().is_multiple_of(4) {
+        return Err(AotError::Object(MemoryError::Unaligned))
+    }
+    let words: Vec<u32> = object.chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+    let fetch = |pc: u32| words.get((pc / ILEN) as usize).filter(|_| pc.is_multiple_of(ILEN)).map(|w| Instruction::from_iword(*w));
+
+    // walk the code, finding block leaders as we go
+    let mut reached = BTreeSet::new();
+    let mut leaders = BTreeSet::from([0]);
+    let mut todo = vec![0];
+    while let Some(pc) = todo.pop() {
+        let Some(i) = fetch(pc) else { continue };
+        if !reached.insert(pc) {
+            continue
+        }
+        if i.opcode == Opcode::Arith && float::is_float(i.funct) {
+            return Err(AotError::Float(pc))
+        }
+        let next = pc.wrapping_add(ILEN);
+        let targets = match flow(pc, &i) {
+            Flow::Next => vec![next],
+            Flow::Skip => {
+                leaders.extend([next, next.wrapping_add(ILEN)]);
+                vec![next, next.wrapping_add(ILEN)]
+            }
+            Flow::Jump(t) => {
+                leaders.insert(t);
+                vec![t]
+            }
+            Flow::Call(t) => {
+                // the return lands after the call
+                leaders.insert(next);
+                leaders.extend(t);
+                t.into_iter().chain([next]).collect()
+            }
+            Flow::Dynamic | Flow::Stop => vec![]
+        };
+        todo.extend(targets)
+    }
+    let leaders: BTreeSet<u32> = leaders.intersection(&reached).copied().collect();
+
+    let mut c = String::new();
+    writeln!(c, "/* translated by raven aot */").unwrap();
+    writeln!(c, "#define OBJECT_LEN {}u", object.len()).unwrap();
+    let errors = [
+        ("UNINIT", VMError::Mem(MemoryError::Uninit)),
+        ("UNALIGNED", VMError::Mem(MemoryError::Unaligned)),
+        ("OUT_OF_BOUNDS", VMError::Mem(MemoryError::OutOfBounds)),
+        ("BAD_FD", VMError::Io(IoError::BadFd)),
+        ("IO_FUNCT", VMError::IoFunct),
+        ("INVALID_PARAMS", VMError::Io(IoError::InvalidParams)),
+        ("ARITH", VMError::Arith),
+        ("IMM_UPPER", VMError::ImmUpper),
+        ("LD", VMError::Ld),
+        ("ST", VMError::St),
+        ("FUNC", VMError::Func),
+        ("COMPRESSED", VMError::Compressed),
+    ];
+    for (name, e) in errors {
+        writeln!(c, "#define ERR_{} \"{}\"", name, e).unwrap();
+    }
+    for (name, f) in [("ID", machine::IO_HART_ID), ("START", machine::IO_HART_START), ("STOP", machine::IO_HART_STOP)] {
+        writeln!(c, "#define IO_HART_{} {}u", name, f).unwrap();
+    }
+    writeln!(c, "#define IO_MMAP {}u\n#define IO_MMAP_COW {}u", io::IO_MMAP, io::IO_MMAP_COW).unwrap();
+    writeln!(c, "#define IO_OPEN {}u\n#define IO_CLOSE {}u", io::IO_OPEN, io::IO_CLOSE).unwrap();
+    writeln!(c, "#define IO_MALLOC {}u\n#define IO_FREE {}u", memory::IO_MALLOC, memory::IO_FREE).unwrap();
+    writeln!(c, "#include <stdint.h>").unwrap();
+    bytes(&mut c, "uint8_t object", object);
+    let code_map: Vec<u8> = (0..words.len() as u32).map(|w| reached.contains(&(w * ILEN)) as u8).collect();
+    bytes(&mut c, "const uint8_t code_map", &code_map);
+    c.push_str(RUNTIME);
+
+    writeln!(c, "\nstatic void run(void) {{").unwrap();
+    writeln!(c, "    uint32_t pc, a, b, s3, v, t;").unwrap();
+    writeln!(c, "    (void)a; (void)b; (void)s3; (void)v; (void)t; (void)g;").unwrap();
+    if reached.is_empty() {
+        writeln!(c, "    pc = 0;").unwrap()
+    }
+    else {
+        writeln!(c, "    goto L_{:08x};", 0).unwrap()
+    }
+    writeln!(c, "dispatch:\n    switch (pc) {{").unwrap();
+    for l in &leaders {
+        writeln!(c, "    case 0x{:x}u: goto L_{:08x};", l, l).unwrap();
+    }
+    writeln!(c, "    default: rv_bad_jump(pc);\n    }}").unwrap();
+
+    let t = Translator { leaders: &leaders };
+    for &pc in &reached {
+        let i = fetch(pc).unwrap();
+        if leaders.contains(&pc) {
+            writeln!(c, "L_{:08x}:", pc).unwrap();
+        }
+        writeln!(c, "    /* {:08x}: {:08x} */", pc, words[(pc / ILEN) as usize]).unwrap();
+        let falls = t.instruction(&mut c, pc, &i);
+        let next = pc.wrapping_add(ILEN);
+        if falls && !reached.contains(&next) {
+            writeln!(c, "    {}", t.jump(next)).unwrap();
+        }
+    }
+    writeln!(c, "}}").unwrap();
+    Ok(c)
+}
+
+/// where control can go after an instruction, as far as can be told without running it
+enum Flow {
+    Next,
+    /// to the next instruction, or the one after
+    Skip,
+    Jump(u32),
+    /// to the target if it's known, and later back to the next instruction
+    Call(Option<u32>),
+    Dynamic,
+    /// always faults
+    Stop,
+}
+
+fn flow(pc: u32, i: &Instruction) -> Flow {
+    use Opcode::*;
+    let valid = match i.opcode {
+        Arith | ArithSkip => arith_op(i.funct).is_some(),
+        ImmUpper => imm_upper_op(i.funct).is_some(),
+        Ld => load_op(i.funct).is_some(),
+        St => store_op(i.funct).is_some(),
+        Io => true,
+        Func => func::is_valid(i.funct),
+        Comp => false,
+    };
+    if !valid {
+        return Flow::Stop
+    }
+    if i.rd == RS::PC {
+        return match constant_target(pc, i) {
+            Some(t) => Flow::Jump(t),
+            None => Flow::Dynamic
+        }
+    }
+    match (i.opcode, i.funct) {
+        (ArithSkip, _) => Flow::Skip,
+        (Func, func::RET) => Flow::Dynamic,
+        (Func, f @ (func::CALL | func::CALL_INDIRECT)) => Flow::Call(func_target(pc, i, f)),
+        (Func, f) => func_target(pc, i, f).map_or(Flow::Dynamic, Flow::Jump),
+        _ => Flow::Next
+    }
+}
+
+/// where an instruction writing the pc sends it, if that doesn't depend on any register
+fn constant_target(pc: u32, i: &Instruction) -> Option<u32> {
+    let v = match (i.opcode, i.funct) {
+        (Opcode::Arith, 0) => constant_base(pc, i.rs1)?.wrapping_add(i.immediate()?),
+        (Opcode::ImmUpper, 0) => pc.wrapping_add(i.immediate()?),
+        // returns write 0
+        (Opcode::Func, func::RET) => 0,
+        // a call or tail call that leaves the old pc in the pc, so at most it shifts the window
+        (Opcode::Func, _) => pc,
+        _ => return None
+    };
+    Some(v.wrapping_add(ILEN))
+}
+fn constant_base(pc: u32, rs: RS) -> Option<u32> {
+    match rs.inner() {
+        0 => Some(0),
+        2 => Some(pc),
+        _ => None
+    }
+}
+/// where a call or tail call goes, if that doesn't depend on any register
+fn func_target(pc: u32, i: &Instruction, funct: u32) -> Option<u32> {
+    if func::is_indirect(funct) {
+        let b = i.immediate().or_else(|| (i.rs2 == RS::ZERO).then_some(0))?;
+        Some(constant_base(pc, i.rs1)?.wrapping_add(b))
+    }
+    else {
+        i.immediate().map(|o| call_target(pc, o))
+    }
+}
+
+fn call_target(pc: u32, offset: u32) -> u32 {
+    pc.wrapping_add(offset).wrapping_add(ILEN)
+}
+
+/// C for each operation, over `a` (s1) and `b` (s2)
+fn arith_op(funct: u32) -> Option<String> {
+    if packed::packed(0, 0, 0, funct).is_some() {
+        return Some(format!("rv_packed({}u, a, b, s3)", funct))
+    }
+    Some(match funct {
+        0 => "a + b",
+        1 => "rv_extract(a, b, 0)",
+        2 => "b - a",
+        3 => "rv_extract(a, b, 1)",
+        4 => "a & b",
+        5 => "a | b",
+        6 => "a ^ b",
+        7 => "~a",
+
+        8 => "(uint32_t)((uint64_t)a * b)",
+        9 => "(uint32_t)(((uint64_t)a * b) >> 32)",
+        10 => "a * b",
+        11 => "(uint32_t)(((int64_t)(int32_t)a * (int32_t)b) >> 32)",
+
+        12 => "b ? a / b : 0xffffffffu",
+        13 => "b ? a % b : a",
+        14 => "b == 0 || (a == 0x80000000u && b == 0xffffffffu) ? 0xffffffffu : (uint32_t)((int32_t)a / (int32_t)b)",
+        15 => "b == 0 || (a == 0x80000000u && b == 0xffffffffu) ? a : (uint32_t)((int32_t)a % (int32_t)b)",
+
+        // the interpreter masks shift amounts in release builds
+        16 | 18 => "a << (b & 31)",
+        17 => "a >> (b & 31)",
+        19 => "(uint32_t)((int32_t)a >> (b & 31))",
+        20 => "a << (b & 31) | a >> (-b & 31)",
+        21 => "a >> (b & 31) | a << (-b & 31)",
+
+        22 => "a == b",
+        23 => "a != b",
+
+        24 => "a > b",
+        25 => "a >= b",
+        26 => "(int32_t)a > (int32_t)b",
+        27 => "(int32_t)a >= (int32_t)b",
+
+        28 => "a < b",
+        29 => "a <= b",
+        30 => "(int32_t)a < (int32_t)b",
+        31 => "(int32_t)a <= (int32_t)b",
+
+        96 => "a ? (uint32_t)__builtin_clz(a) : 32",
+        97 => "a ? (uint32_t)__builtin_ctz(a) : 32",
+        98 => "(uint32_t)__builtin_popcount(a)",
+        99 => "__builtin_bswap32(a)",
+        100 => "rv_brev(a)",
+        101 => "(int32_t)a < (int32_t)b ? a : b",
+        102 => "(int32_t)a > (int32_t)b ? a : b",
+        103 => "a < b ? a : b",
+        104 => "a > b ? a : b",
+        105 => "rv_insert(a, b, s3)",
+
+        64 => "a + b + (s3 != 0)",
+        65 => "(uint32_t)(((uint64_t)a + b + (s3 != 0)) >> 32)",
+        66 => "a - b - (s3 != 0)",
+        67 => "(uint64_t)a < (uint64_t)b + (s3 != 0)",
+        68 => "rv_div64(68, a, b, s3)",
+        69 => "rv_div64(69, a, b, s3)",
+        70 => "rv_div64(70, a, b, s3)",
+        71 => "rv_div64(71, a, b, s3)",
+        72 => "rv_div64(72, a, b, s3)",
+        73 => "rv_div64(73, a, b, s3)",
+
+        _ => return None
+    }.into())
+}
+fn imm_upper_op(funct: u32) -> Option<&'static str> {
+    Some(match funct {
+        0 => "a + b",
+        1 => "a - b",
+
+        4 => "a & b",
+        5 => "a | b",
+        6 => "a ^ b",
+        7 => "(a & 0x1fffu) | b",
+
+        _ => return None
+    })
+}
+fn load_op(funct: u32) -> Option<&'static str> {
+    Some(match funct {
+        0 => "rv_lw(a + b)",
+        1 => "rv_lh(a + b)",
+        2 => "(uint32_t)(int16_t)rv_lh(a + b)",
+        3 => "rv_lb(a + b)",
+        4 => "(uint32_t)(int8_t)rv_lb(a + b)",
+        mem::LR => "rv_lr(a)",
+        mem::SC => "rv_sc(a, s3)",
+        mem::SWAP => "rv_swap(a, s3)",
+        mem::FETCH_ADD => "rv_fetch_add(a, s3)",
+        mem::CAS => "rv_cas(a, b, s3)",
+        mem::COMPARE => "rv_compare(a, b, s3)",
+        _ => return None
+    })
+}
+fn store_op(funct: u32) -> Option<&'static str> {
+    Some(match funct {
+        0 => "rv_sw(a + b, s3)",
+        1 => "rv_sh(a + b, s3)",
+        2 => "rv_sb(a + b, s3)",
+        mem::COPY => "rv_copy(a, b, s3)",
+        mem::FILL => "rv_fill(a, b, s3)",
+        _ => return None
+    })
+}
+
+/// C lvalue for a register, or its value for r0 and the pc
+fn reg(rs: RS, pc: u32) -> String {
+    match rs.inner() {
+        0 => "0u".into(),
+        2 => format!("0x{:x}u", pc),
+        n @ 1..=7 => format!("g[{}]", n),
+        n @ 8..=15 => format!("TOP[{}]", n - 8),
+        n @ 16..=23 => format!("LOCAL[{}]", n - 16),
+        n => format!("SHARED[{}]", n - 24),
+    }
+}
+
+fn bytes(c: &mut String, decl: &str, b: &[u8]) {
+    // an empty object still needs an array
+    writeln!(c, "static {}[{}] = {{", decl, b.len().max(1)).unwrap();
+    for line in b.chunks(16) {
+        c.push_str("   ");
+        for x in line {
+            write!(c, " 0x{:02x},", x).unwrap();
+        }
+        c.push('\n');
+    }
+    writeln!(c, "}};").unwrap();
+}
+
+struct Translator<'a> {
+    leaders: &'a BTreeSet<u32>,
+}
+impl Translator<'_> {
+    fn jump(&self, target: u32) -> String {
+        if self.leaders.contains(&target) {
+            format!("goto L_{:08x};", target)
+        }
+        else {
+            format!("pc = 0x{:x}u; goto dispatch;", target)
+        }
+    }
+
+    /// same order of events as `VM::cycle`. returns true if it can fall through to the next instruction
+    fn instruction(&self, c: &mut String, pc: u32, i: &Instruction) -> bool {
+        use Opcode::*;
+        let fault = |c: &mut String, e: &str| {
+            writeln!(c, "    rv_fault(ERR_{});", e).unwrap();
+            false
+        };
+        let op = match i.opcode {
+            Arith | ArithSkip => arith_op(i.funct).ok_or("ARITH"),
+            ImmUpper => imm_upper_op(i.funct).map(String::from).ok_or("IMM_UPPER"),
+            Ld => load_op(i.funct).map(String::from).ok_or("LD"),
+            St => store_op(i.funct).map(String::from).ok_or("ST"),
+            Io => Ok(format!("rv_io(0x{:x}u, a, b, s3)", i.funct)),
+            Func if func::is_valid(i.funct) => Ok(String::new()),
+            Func => Err("FUNC"),
+            Comp => Err("COMPRESSED"),
+        };
+        let op = match op {
+            Ok(op) => op,
+            Err(e) => return fault(c, e)
+        };
+
+        let b = i.immediate().map(|imm| format!("0x{:x}u", imm)).unwrap_or_else(|| reg(i.rs2, pc));
+        writeln!(c, "    a = {}; b = {};", reg(i.rs1, pc), b).unwrap();
+        let three = match i.opcode {
+            St | Io => true,
+            Ld => mem::is_atomic(i.funct) || i.funct == mem::COMPARE,
+            Arith => i.immediate().is_none() && i.funct >= 32,
+            _ => false
+        };
+        if three {
+            writeln!(c, "    s3 = {};", reg(i.rs3, pc)).unwrap();
+        }
+
+        let mut next = None;
+        match (i.opcode, i.funct) {
+            (St, _) => writeln!(c, "    {};", op).unwrap(),
+            (Func, func::RET) => {
+                writeln!(c, "    t = b + {}u;\n    rv_ret();\n    v = 0;", ILEN).unwrap();
+                next = Some("pc = t; goto dispatch;".into());
+            }
+            (Func, f) => {
+                // the target is worked out before the window shifts and the old pc written after
+                match func::is_indirect(f) {
+                    true => writeln!(c, "    t = a + b;").unwrap(),
+                    false => writeln!(c, "    t = 0x{:x}u + b;", pc.wrapping_add(ILEN)).unwrap(),
+                }
+                if matches!(f, func::CALL | func::CALL_INDIRECT) {
+                    writeln!(c, "    rv_call();").unwrap();
+                }
+                writeln!(c, "    v = 0x{:x}u;", pc).unwrap();
+                next = Some(match func_target(pc, i, f) {
+                    Some(t) => self.jump(t),
+                    None => "pc = t; goto dispatch;".into()
+                });
+            }
+            _ => writeln!(c, "    v = {};", op).unwrap(),
+        }
+
+        if i.rd == RS::PC {
+            let j = match constant_target(pc, i) {
+                Some(t) => self.jump(t),
+                None => format!("pc = v + {}u; goto dispatch;", ILEN)
+            };
+            writeln!(c, "    {}", j).unwrap();
+            return false
+        }
+        if i.rd != RS::ZERO {
+            writeln!(c, "    {} = v;", reg(i.rd, pc)).unwrap();
+        }
+        if let Some(j) = next {
+            writeln!(c, "    {}", j).unwrap();
+            return false
+        }
+        if i.opcode == ArithSkip {
+            writeln!(c, "    if (v) {}", self.jump(pc.wrapping_add(2 * ILEN))).unwrap();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::io::IoHandler;
+    use crate::memory::MainMemory;
+    use crate::vm::VM;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::{Rng, random_instruction};
+
+    const DATA: u32 = 0x2000;
+
+    /// code at 0, then 0x100 bytes of data at `DATA`
+    fn object(words: &[u32]) -> Vec<u8> {
+        let mut o: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert!(o.len() <= DATA as usize);
+        o.resize(DATA as usize, 0);
+        o.extend((0..0x100u32).map(|b| b.wrapping_mul(0x9d) as u8));
+        o
+    }
+
+    /// what `raven run` would print, as (stdout, stderr)
+    fn interpret(object: &[u8]) -> (Vec<u8>, String) {
+        let mut mem = MainMemory::new(object.to_vec()).unwrap();
+        let mut io = IoHandler::new();
+        let mut vm = VM::new();
+        for _ in 0..1_000_000 {
+            match vm.cycle(&mut io, &mut mem) {
+                Ok(false) => {}
+                Ok(true) => return (io.take_stdout(), String::new()),
+                Err(e) => return (io.take_stdout(), format!("raven: {}\n", e))
+            }
+        }
+        panic!("program didn't stop")
+    }
+
+    /// None if there's no C compiler around
+    fn compile_and_run(object: &[u8], name: &str) -> Option<(Vec<u8>, String)> {
+        Command::new("cc").arg("--version").output().ok()?;
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!("raven-aot-{}-{}.c", name, std::process::id()));
+        let exe = dir.join(format!("raven-aot-{}-{}", name, std::process::id()));
+        std::fs::write(&src, translate(object).unwrap()).unwrap();
+        let cc = Command::new("cc").args(["-O1", "-w", "-o"]).arg(&exe).arg(&src).output().unwrap();
+        assert!(cc.status.success(), "{}", String::from_utf8_lossy(&cc.stderr));
+        let out = Command::new(&exe).output().unwrap();
+        let _ = std::fs::remove_file(&src);
+        let _ = std::fs::remove_file(&exe);
+        Some((out.stdout, String::from_utf8(out.stderr).unwrap()))
+    }
+
+    fn assert_same(words: &[u32], name: &str) {
+        let o = object(words);
+        if let Some(native) = compile_and_run(&o, name) {
+            assert_eq!(native, interpret(&o));
+        }
+    }
+
+    /// writes every byte of r3..=r31 to stdout, using r1
+    fn dump() -> Vec<u32> {
+        (3..32).flat_map(|r| [
+            io_imm(0, r, 64, 1),
+            arith_imm(1, r, 17, 8), io_imm(0, 1, 64, 1),
+            arith_imm(1, r, 17, 16), io_imm(0, 1, 64, 1),
+            arith_imm(1, r, 17, 24), io_imm(0, 1, 64, 1),
+        ]).collect()
+    }
+
+    #[test]
+    fn random_programs() {
+        let mut r = Rng(0x9e37_79b9_7f4a_7c15);
+        for n in 0..6 {
+            let mut words = vec![imm_upper(1, 5, DATA)];
+            words.extend((0..200).map(|_| random_instruction(&mut r)));
+            // somewhere for a skip on the last instruction to land
+            words.extend([arith_imm(0, 0, 0, 0), arith_imm(0, 0, 0, 0)]);
+            words.extend(dump());
+            assert_same(&words, &format!("random{}", n));
+        }
+    }
+
+    #[test]
+    fn indirect_and_tail_calls() {
+        let f = 28;
+        let words = [
+            // f sums 1..=r8 into r9 by tail calling itself. the indirect call only
+            // finds f because it was called directly first
+            arith_imm(8, 0, 0, 5), call(16, f - 8),
+            arith_imm(8, 0, 0, 3), arith_imm(10, 0, 0, f), func_reg(16, 10, 0, func::CALL_INDIRECT),
+            io_imm(0, 9, 64, 1), func_reg(0, 0, 0, 3),
+
+            arith_reg(25, 25, 24, 0), arith_imm(24, 24, 0, -1), skip_imm(0, 24, 22, 0),
+            tail(0, -16), ret(16),
+        ];
+        assert_same(&words, "calls");
+        assert_eq!(interpret(&object(&words)), (vec![21], "raven: invalid func funct\n".into()));
+    }
+
+    #[test]
+    fn recursion() {
+        let f = 24;
+        let words = [
+            arith_imm(8, 0, 0, 10), arith_imm(9, 0, 0, 10),
+            call(16, f - 8 - 4),
+            // sum of 1..=10 is '7'
+            io_imm(0, 8, 64, 1), io_imm(0, 9, 64, 1),
+            io_imm(0, 9, 64, 5),
+
+            // f: r24 += f(r24 - 1), unless r24 is 0
+            skip_imm(0, 24, 22, 0), arith_imm(2, 2, 0, 4), ret(16),
+            arith_imm(8, 24, 0, -1), call(16, -20), arith_reg(24, 24, 8, 0), ret(16),
+        ];
+        assert_same(&words, "recursion");
+        assert_eq!(interpret(&object(&words)), (b"7\n".to_vec(), "raven: failed io operation: BadFd\n".into()));
+    }
+
+    #[test]
+    fn floats() {
+        let words = [arith_imm(3, 0, 0, 1), arith_reg(4, 3, 3, float::FADD), io_imm(0, 4, 64, 1)];
+        assert_eq!(translate(&object(&words)), Err(AotError::Float(4)));
+        // a skip never runs float ops, so that funct is left to agree with the interpreter
+        assert_same(&[arith_imm(3, 0, 0, 1), skip_reg(4, 3, 3, float::FADD)], "float_skip");
+    }
+
+    #[test]
+    fn memory_faults() {
+        let words = [
+            imm_upper(3, 5, 0x10000), store_imm(3, 3, 0, 0), load_imm(4, 3, 1, 2), io_imm(0, 4, 64, 1),
+            lw(5, 3, 2),
+        ];
+        assert_same(&words, "unaligned");
+        assert_same(&[lw(5, 0, 0x7fc), arith_imm(6, 0, 0, 1)], "uninit");
+        assert_same(&[], "empty");
+    }
+
+    #[test]
+    fn lone_hart() {
+        use machine::*;
+        let words = [io_imm(3, 0, IO_HART_ID, 0), arith_imm(3, 3, 0, 'a' as i32), io_imm(0, 3, 64, 1), io_imm(0, 0, IO_HART_STOP, 0)];
+        assert_same(&words, "stop");
+        assert_same(&[io_imm(0, 0, IO_HART_START, 1)], "start");
+    }
+
+    #[test]
+    fn heap_notifications() {
+        let words = [
+            arith_imm(8, 0, 0, 0x400), io_imm(3, 8, memory::IO_MALLOC, 8), io_imm(4, 8, memory::IO_FREE, 0),
+            arith_reg(3, 3, 4, 0), arith_imm(3, 3, 0, 'a' as i32), io_imm(0, 3, 64, 1),
+        ];
+        assert_same(&words, "heap");
+    }
+
+    #[test]
+    fn atomics() {
+        let words = [
+            imm_upper(9, 5, DATA), arith_imm(10, 0, 0, 'a' as i32),
+            load_reg(3, 9, 0, 10, mem::FETCH_ADD), load_reg(4, 9, 0, 10, mem::SWAP),
+            load_reg(5, 9, 0, 0, mem::LR), store_imm(9, 10, 2, 3), load_reg(6, 9, 0, 10, mem::SC),
+            load_reg(7, 9, 0, 0, mem::LR), load_reg(8, 9, 0, 10, mem::SC),
+            lw(11, 9, 0), load_reg(12, 9, 11, 10, mem::CAS), load_reg(13, 9, 10, 0, mem::CAS),
+            io_imm(0, 3, 64, 1), io_imm(0, 4, 64, 1), io_imm(0, 5, 64, 1), io_imm(0, 6, 64, 1),
+            io_imm(0, 7, 64, 1), io_imm(0, 8, 64, 1), io_imm(0, 12, 64, 1), io_imm(0, 13, 64, 1),
+            load_reg(0, 10, 0, 0, mem::LR),
+        ];
+        assert_same(&words, "atomics");
+    }
+
+    #[test]
+    fn bulk() {
+        let words = [
+            imm_upper(9, 5, DATA), arith_imm(10, 0, 0, 'A' as i32), arith_imm(11, 0, 0, 6), arith_imm(12, 9, 0, 3),
+            store_reg(9, 10, 11, mem::FILL), store_reg(12, 9, 11, mem::COPY), store_imm(9, 11, mem::FILL, 'B' as i32),
+            load_reg(3, 9, 12, 11, mem::COMPARE), load_reg(4, 12, 9, 11, mem::COMPARE), load_reg(5, 9, 9, 11, mem::COMPARE),
+            io_imm(0, 3, 64, 1), io_imm(0, 4, 64, 1), io_imm(0, 5, 64, 1),
+            lw(6, 9, 4), lw(7, 9, 8), io_imm(0, 6, 64, 1), io_imm(0, 7, 64, 1),
+            imm_upper(13, 5, 0x10_0000), load_reg(3, 13, 9, 11, mem::COMPARE),
+        ];
+        assert_same(&words, "bulk");
+        let (out, err) = interpret(&object(&words));
+        assert_eq!((out, err.as_str()), (vec![1, 0xff, 0, b'B', b'A'], "raven: memory error: Uninit\n"));
+    }
+
+    #[test]
+    fn computed_jumps() {
+        // lands on the return point after the call, which is a leader
+        let words = [arith_imm(3, 0, 0, 4), call(4, 4), arith_reg(2, 3, 0, 0), io_imm(0, 3, 64, 9)];
+        assert_same(&words, "leader");
+
+        // lands in the middle of a block, which only the interpreter can do
+        let words = [arith_imm(3, 0, 0, 4), arith_reg(2, 3, 0, 0), io_imm(0, 3, 64, 9), io_imm(0, 3, 64, 9)];
+        if let Some((_, err)) = compile_and_run(&object(&words), "middle") {
+            assert_eq!(err, "raven: jump to untranslated code at 00000008\n");
+        }
+    }
+}
@@ -0,0 +1,321 @@
+/* runtime for programs translated by `raven aot`
+ *
+ * the translator defines OBJECT_LEN, object, code_map, the IO_HART_, IO_MMAP, IO_OPEN, IO_CLOSE, IO_MALLOC and IO_FREE functs and the ERR_ strings before this,
+ * and run() after it */
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static void rv_fault(const char *msg) {
+    /* same order the interpreter flushes in: console output first, then the error */
+    fflush(stdout);
+    fprintf(stderr, "raven: %s\n", msg);
+    fflush(stderr);
+    exit(1);
+}
+
+/* memory works like SplitMemory: the object, then zeroed 4KiB blocks that only exist once stored to */
+#define BLOCK_BITS 12
+#define BLOCK_SIZE (1u << BLOCK_BITS)
+static uint8_t *blocks[1u << (32 - BLOCK_BITS)];
+
+/* the one reservation a lone hart can hold, which any store to the word breaks */
+static int reserved;
+static uint32_t reservation;
+
+static uint8_t *rv_access(uint32_t addr, uint32_t width, int store) {
+    uint8_t *base;
+    uint32_t len, off;
+    if (addr < OBJECT_LEN) {
+        base = object;
+        len = OBJECT_LEN;
+        off = addr;
+    }
+    else {
+        uint8_t **b = &blocks[addr >> BLOCK_BITS];
+        if (!*b) {
+            if (!store)
+                rv_fault(ERR_UNINIT);
+            *b = calloc(BLOCK_SIZE, 1);
+            if (!*b)
+                rv_fault("out of host memory");
+        }
+        base = *b;
+        len = BLOCK_SIZE;
+        off = addr & (BLOCK_SIZE - 1);
+    }
+    if (off % width)
+        rv_fault(ERR_UNALIGNED);
+    if (len - off < width)
+        rv_fault(ERR_OUT_OF_BOUNDS);
+    /* the translated code can't change along with the words it came from */
+    if (store && addr < OBJECT_LEN && code_map[addr >> 2])
+        rv_fault("store to translated code, self modifying programs need the interpreter");
+    if (store && (addr & ~3u) == reservation)
+        reserved = 0;
+    return base + off;
+}
+
+static inline uint32_t rv_lw(uint32_t addr) {
+    uint8_t *p = rv_access(addr, 4, 0);
+    return p[0] | p[1] << 8 | (uint32_t)p[2] << 16 | (uint32_t)p[3] << 24;
+}
+static inline uint32_t rv_lh(uint32_t addr) {
+    uint8_t *p = rv_access(addr, 2, 0);
+    return p[0] | p[1] << 8;
+}
+static inline uint32_t rv_lb(uint32_t addr) {
+    return *rv_access(addr, 1, 0);
+}
+static inline void rv_sw(uint32_t addr, uint32_t v) {
+    uint8_t *p = rv_access(addr, 4, 1);
+    p[0] = v;
+    p[1] = v >> 8;
+    p[2] = v >> 16;
+    p[3] = v >> 24;
+}
+static inline void rv_sh(uint32_t addr, uint32_t v) {
+    uint8_t *p = rv_access(addr, 2, 1);
+    p[0] = v;
+    p[1] = v >> 8;
+}
+static inline void rv_sb(uint32_t addr, uint32_t v) {
+    *rv_access(addr, 1, 1) = v;
+}
+
+/* bulk ops read their whole source before writing anything, see Memory::copy */
+static inline void rv_check_range(uint32_t addr, uint32_t len) {
+    if ((uint64_t)addr + len > (uint64_t)1 << 32)
+        rv_fault(ERR_OUT_OF_BOUNDS);
+}
+static uint8_t *rv_read_range(uint32_t addr, uint32_t len) {
+    uint8_t *buf = malloc(len ? len : 1);
+    if (!buf)
+        rv_fault("out of host memory");
+    for (uint32_t i = 0; i < len; i++)
+        buf[i] = *rv_access(addr + i, 1, 0);
+    return buf;
+}
+static void rv_copy(uint32_t dst, uint32_t src, uint32_t len) {
+    rv_check_range(dst, len);
+    rv_check_range(src, len);
+    uint8_t *buf = rv_read_range(src, len);
+    for (uint32_t i = 0; i < len; i++)
+        *rv_access(dst + i, 1, 1) = buf[i];
+    free(buf);
+}
+static void rv_fill(uint32_t dst, uint32_t v, uint32_t len) {
+    rv_check_range(dst, len);
+    for (uint32_t i = 0; i < len; i++)
+        *rv_access(dst + i, 1, 1) = v;
+}
+static uint32_t rv_compare(uint32_t a, uint32_t b, uint32_t len) {
+    rv_check_range(a, len);
+    rv_check_range(b, len);
+    uint8_t *x = rv_read_range(a, len), *y = rv_read_range(b, len);
+    int c = memcmp(x, y, len);
+    free(x);
+    free(y);
+    return c < 0 ? 0xffffffffu : c > 0;
+}
+
+/* atomics take their address from s1 alone, see instruction::mem */
+static inline uint32_t rv_atomic_addr(uint32_t addr) {
+    if (addr % 4)
+        rv_fault(ERR_UNALIGNED);
+    return addr;
+}
+static inline uint32_t rv_lr(uint32_t addr) {
+    uint32_t v = rv_lw(rv_atomic_addr(addr));
+    reserved = 1;
+    reservation = addr;
+    return v;
+}
+static inline uint32_t rv_sc(uint32_t addr, uint32_t v) {
+    int held;
+    addr = rv_atomic_addr(addr);
+    held = reserved && reservation == addr;
+    reserved = 0;
+    if (!held)
+        return 1;
+    rv_sw(addr, v);
+    return 0;
+}
+static inline uint32_t rv_swap(uint32_t addr, uint32_t v) {
+    uint32_t old = rv_lw(rv_atomic_addr(addr));
+    rv_sw(addr, v);
+    return old;
+}
+static inline uint32_t rv_fetch_add(uint32_t addr, uint32_t v) {
+    uint32_t old = rv_lw(rv_atomic_addr(addr));
+    rv_sw(addr, old + v);
+    return old;
+}
+static inline uint32_t rv_cas(uint32_t addr, uint32_t expected, uint32_t v) {
+    uint32_t old = rv_lw(rv_atomic_addr(addr));
+    if (old == expected)
+        rv_sw(addr, v);
+    return old;
+}
+
+/* bit fields are pos | (width - 1) << 5, see instruction::arithmetic */
+static inline uint64_t rv_field_mask(uint32_t spec) {
+    return ((uint64_t)1 << (((spec >> 5) & 31) + 1)) - 1;
+}
+static inline uint32_t rv_extract(uint32_t v, uint32_t spec, int sign) {
+    uint64_t mask = rv_field_mask(spec);
+    uint32_t field = (uint32_t)((v >> (spec & 31)) & mask), shift = 31 - ((spec >> 5) & 31);
+    return sign && shift ? (uint32_t)((int32_t)(field << shift) >> shift) : field;
+}
+static inline uint32_t rv_insert(uint32_t base, uint32_t v, uint32_t spec) {
+    uint64_t mask = rv_field_mask(spec) << (spec & 31);
+    return (base & ~(uint32_t)mask) | (uint32_t)(((uint64_t)v << (spec & 31)) & mask);
+}
+static inline uint32_t rv_brev(uint32_t v) {
+    uint32_t r = 0;
+    for (int i = 0; i < 32; i++)
+        r |= ((v >> i) & 1) << (31 - i);
+    return r;
+}
+
+/* see instruction::packed. funct bit 0 picks u16 lanes over u8, and funct 156 is the shuffle */
+static uint32_t rv_packed(uint32_t funct, uint32_t a, uint32_t b, uint32_t c) {
+    uint32_t bits = funct & 1 ? 16 : 8, mask = (1u << bits) - 1, lanes = 32 / bits, r = 0;
+    int64_t lo = -((int64_t)1 << (bits - 1)), hi = ((int64_t)1 << (bits - 1)) - 1;
+    for (uint32_t i = 0; i < lanes; i++) {
+        uint32_t x = (a >> (i * bits)) & mask, y = (b >> (i * bits)) & mask, v, sel;
+        int64_t sx = (int32_t)(x << (32 - bits)) >> (32 - bits), sy = (int32_t)(y << (32 - bits)) >> (32 - bits), s;
+        switch (funct & ~1u) {
+        case 128: v = x + y; break;
+        case 130: v = x - y; break;
+        case 132: v = x + y > mask ? mask : x + y; break;
+        case 134: s = sx + sy; v = (uint32_t)(s < lo ? lo : s > hi ? hi : s); break;
+        case 136: v = x > y ? x - y : 0; break;
+        case 138: s = sx - sy; v = (uint32_t)(s < lo ? lo : s > hi ? hi : s); break;
+        case 140: v = x == y ? mask : 0; break;
+        case 142: v = x < y ? mask : 0; break;
+        case 144: v = sx < sy ? mask : 0; break;
+        case 146: v = x < y ? x : y; break;
+        case 148: v = x > y ? x : y; break;
+        case 150: v = (uint32_t)(sx < sy ? sx : sy); break;
+        case 152: v = (uint32_t)(sx > sy ? sx : sy); break;
+        case 154: v = (x + y + 1) >> 1; break;
+        default:
+            sel = y & (2 * lanes - 1);
+            v = y >> (bits - 1) ? 0 : sel < lanes ? (a >> (sel * bits)) & mask : (c >> ((sel - lanes) * bits)) & mask;
+            break;
+        }
+        r |= (v & mask) << (i * bits);
+    }
+    return r;
+}
+
+/* s2:s1 over s3, with the interpreter's answers for / 0 and overflow. 69 and 72 want the high half */
+static inline uint32_t rv_div64(uint32_t funct, uint32_t lo, uint32_t hi, uint32_t d) {
+    uint64_t n = (uint64_t)hi << 32 | lo, q;
+    int64_t sn = (int64_t)n, sd = (int32_t)d;
+    int bad = d == 0 || (sn == INT64_MIN && sd == -1);
+    switch (funct) {
+    case 68: case 69: q = d ? n / d : UINT64_MAX; break;
+    case 70: q = d ? n % d : n; break;
+    case 71: case 72: q = bad ? UINT64_MAX : (uint64_t)(sn / sd); break;
+    default: q = bad ? n : (uint64_t)(sn % sd); break;
+    }
+    return funct == 69 || funct == 72 ? (uint32_t)(q >> 32) : (uint32_t)q;
+}
+
+/* register windows, see registers.rs. TOP is r8-15, LOCAL r16-23 and SHARED r24-31 */
+struct window {
+    uint32_t shared[8];
+    uint32_t local[8];
+};
+static uint32_t g[8];
+static struct window *win;
+static uint32_t depth, capacity;
+#define TOP (win[depth - 1].shared)
+#define LOCAL (win[depth - 2].local)
+#define SHARED (win[depth - 2].shared)
+
+static void rv_call(void) {
+    if (depth == capacity) {
+        capacity = capacity ? capacity * 2 : 64;
+        win = realloc(win, capacity * sizeof *win);
+        if (!win)
+            rv_fault("out of host memory");
+    }
+    memset(&win[depth++], 0, sizeof *win);
+}
+/* the interpreter only fails once a missing window is used, this fails straight away */
+static inline void rv_ret(void) {
+    if (depth <= 2)
+        rv_fault("returned from the outermost register window");
+    depth--;
+}
+
+/* same virtual fds as IoHandler */
+#define NUM_VIO 32u
+
+static uint32_t rv_io(uint32_t funct, uint32_t s1, uint32_t s2, uint32_t s3) {
+    (void)s3;
+    switch (funct) {
+    case 64: /* write_one */
+        if (s2 == 1)
+            putchar((uint8_t)s1);
+        else if (s2 == 2)
+            fputc((uint8_t)s1, stderr);
+        else if (s2 >= NUM_VIO)
+            rv_fault("host files aren't supported ahead of time");
+        else
+            rv_fault(ERR_BAD_FD);
+        return 0;
+    case IO_MMAP:
+    case IO_MMAP_COW:
+    case IO_CLOSE:
+        if (s2 < NUM_VIO)
+            rv_fault(ERR_BAD_FD);
+        rv_fault("host files aren't supported ahead of time");
+        return 0;
+    case IO_OPEN:
+        rv_fault("host files aren't supported ahead of time");
+        return 0;
+    /* there's no heap checking ahead of time, like running without a sanitizer */
+    case IO_MALLOC:
+    case IO_FREE:
+        return 0;
+    /* a translated program is always a lone hart */
+    case IO_HART_ID:
+        return 0;
+    case IO_HART_START:
+        rv_fault(ERR_INVALID_PARAMS);
+        return 0;
+    case IO_HART_STOP:
+        fflush(stdout);
+        fflush(stderr);
+        exit(0);
+    default:
+        rv_fault(ERR_IO_FUNCT);
+        return 0;
+    }
+}
+
+/* a jump the translator couldn't follow, to somewhere it has no label for */
+static void rv_bad_jump(uint32_t pc) {
+    char msg[64];
+    /* fail the same way the interpreter's fetch would, if it would */
+    rv_lw(pc);
+    snprintf(msg, sizeof msg, "jump to untranslated code at %08x", pc);
+    rv_fault(msg);
+}
+
+static void run(void);
+
+int main(void) {
+    /* guest stderr is buffered too, so it comes out after stdout like in the interpreter */
+    setvbuf(stderr, NULL, _IOFBF, BUFSIZ);
+    rv_call();
+    rv_call();
+    run();
+    return 0;
+}
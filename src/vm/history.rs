@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::memory::{Memory, MemoryError};
+use super::registers::{Registers, RegisterSelector as RS, LocalSet};
+
+/// undo log and periodic checkpoints for stepping backwards
+///
+/// io side effects on the host can't be taken back, and nothing from before the oldest kept checkpoint can be either
+pub struct History {
+    /// oldest first, starting at step `first`
+    steps: VecDeque<Step>,
+    first: u64,
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    /// checkpoints to keep, dropping the oldest and every step before the next one past that
+    keep: usize,
+}
+
+/// everything one instruction changed, enough to put it back
+struct Step {
+    pc: u32,
+    regs: RegUndo,
+    window: Option<WindowUndo>,
+    store: Option<StoreUndo>,
+}
+/// what rd held, and fcsr since float ops set flags as well as rd
+pub struct RegUndo {
+    pub rd: RS,
+    pub old_rd: u32,
+    pub old_fcsr: u32,
+}
+pub enum WindowUndo {
+    Call,
+    /// the window the return dropped
+    Return(LocalSet),
+}
+pub struct StoreUndo {
+    addr: u32,
+    old: Vec<Saved>,
+}
+/// a byte as it was before something stored to it
+#[derive(Clone, Copy)]
+enum Saved {
+    Byte(u8),
+    Uninit,
+    /// out of bounds or guarded, so nothing could have stored to it either
+    Unreadable,
+}
+
+/// machine state before step `step` ran
+///
+/// blocks only holds what memory blocks looked like before they were first stored to after `step`,
+/// so getting back here means applying every later checkpoint's blocks too
+struct Checkpoint {
+    step: u64,
+    registers: Registers,
+    blocks: BTreeMap<u32, Vec<Saved>>,
+}
+
+/// same granularity as `BTreeMemory`
+const BLOCK_SIZE_LOG_2: u32 = 12;
+
+impl History {
+    pub fn new(interval: u64, keep: usize, registers: &Registers) -> Self {
+        Self {
+            steps: VecDeque::new(),
+            first: 0,
+            checkpoints: vec![Checkpoint::new(0, registers)],
+            interval: interval.max(1),
+            keep: keep.max(2),
+        }
+    }
+
+    /// steps recorded so far, counting ones that have since been dropped
+    pub fn len(&self) -> u64 {
+        self.first + self.steps.len() as u64
+    }
+
+    /// call before a store happens, then hand the result to `push`
+    pub fn capture_store<M: Memory>(&mut self, addr: u32, width: u32, memory: &M) -> StoreUndo {
+        let c = self.checkpoints.last_mut().expect("there is always a checkpoint at step 0");
+        for b in addr >> BLOCK_SIZE_LOG_2..=addr.wrapping_add(width - 1) >> BLOCK_SIZE_LOG_2 {
+            c.blocks.entry(b).or_insert_with(|| {
+                let base = b << BLOCK_SIZE_LOG_2;
+                (0..1 << BLOCK_SIZE_LOG_2).map(|i| save(memory, base + i)).collect()
+            });
+        }
+        StoreUndo {
+            addr,
+            old: (0..width).map(|i| save(memory, addr.wrapping_add(i))).collect()
+        }
+    }
+
+    /// `registers` is the state after the step
+    pub fn push(&mut self, pc: u32, regs: RegUndo, window: Option<WindowUndo>, store: Option<StoreUndo>, registers: &Registers) {
+        self.steps.push_back(Step { pc, regs, window, store });
+        if self.len().is_multiple_of(self.interval) {
+            self.checkpoints.push(Checkpoint::new(self.len(), registers))
+        }
+        if self.checkpoints.len() > self.keep {
+            self.checkpoints.remove(0);
+            let oldest = self.checkpoints[0].step;
+            self.steps.drain(..(oldest - self.first) as usize);
+            self.first = oldest
+        }
+    }
+
+    /// undoes the last step, returning the pc it ran at
+    pub fn undo<M: Memory>(&mut self, registers: &mut Registers, memory: &mut M) -> Option<u32> {
+        let s = self.steps.pop_back()?;
+        // reverse order of VM::cycle: rd was written last, inside the new window
+        registers.write(s.regs.rd, s.regs.old_rd);
+        registers.fcsr = s.regs.old_fcsr;
+        match s.window {
+            Some(WindowUndo::Call) => {
+                registers.ret();
+            }
+            Some(WindowUndo::Return(set)) => registers.unret(set),
+            None => {}
+        }
+        registers.write(RS::PC, s.pc);
+        if let Some(st) = s.store {
+            restore(memory, st.addr, &st.old)
+        }
+
+        while self.checkpoints.last().is_some_and(|c| c.step > self.len()) {
+            self.checkpoints.pop();
+        }
+        Some(s.pc)
+    }
+
+    /// goes straight back to the state before step `target`, via the nearest checkpoint,
+    /// or as far back as the log goes if that's further
+    pub fn rewind_to<M: Memory>(&mut self, target: u64, registers: &mut Registers, memory: &mut M) {
+        if target >= self.len() {
+            return
+        }
+        let target = target.max(self.first);
+        // with no checkpoint since the target, it's only a few steps back anyway
+        if let Some(c) = self.checkpoints.iter().position(|c| c.step >= target) {
+            // newest first, so each block ends up as the oldest checkpoint that saved it saw it
+            for later in self.checkpoints.drain(c + 1..).rev() {
+                for (b, data) in later.blocks {
+                    restore(memory, b << BLOCK_SIZE_LOG_2, &data)
+                }
+            }
+            for (b, data) in std::mem::take(&mut self.checkpoints[c].blocks) {
+                restore(memory, b << BLOCK_SIZE_LOG_2, &data)
+            }
+            let c = &self.checkpoints[c];
+            *registers = c.registers.clone();
+            self.steps.truncate((c.step - self.first) as usize);
+        }
+        while self.len() > target {
+            self.undo(registers, memory);
+        }
+    }
+
+    /// steps back until the pc about to run is a breakpoint, returning it
+    pub fn reverse_continue<M: Memory>(&mut self, breakpoints: &BTreeSet<u32>, registers: &mut Registers, memory: &mut M) -> Option<u32> {
+        while let Some(pc) = self.undo(registers, memory) {
+            if breakpoints.contains(&pc) {
+                return Some(pc)
+            }
+        }
+        None
+    }
+
+    /// the most recent store that touched `addr`, as (step, pc)
+    pub fn last_write(&self, addr: u32) -> Option<(u64, u32)> {
+        self.steps.iter().enumerate().rev().find_map(|(i, s)| {
+            let st = s.store.as_ref()?;
+            if addr.wrapping_sub(st.addr) < st.old.len() as u32 {
+                Some((self.first + i as u64, s.pc))
+            }
+            else { None }
+        })
+    }
+}
+
+impl Checkpoint {
+    fn new(step: u64, registers: &Registers) -> Self {
+        Self {
+            step,
+            registers: registers.clone(),
+            blocks: BTreeMap::new(),
+        }
+    }
+}
+
+fn save<M: Memory>(memory: &M, addr: u32) -> Saved {
+    match memory.read_u8(addr) {
+        Ok(b) => Saved::Byte(b),
+        Err(MemoryError::Uninit) => Saved::Uninit,
+        Err(_) => Saved::Unreadable
+    }
+}
+
+fn restore<M: Memory>(memory: &mut M, addr: u32, old: &[Saved]) {
+    let mut discarded = None;
+    for (i, s) in old.iter().enumerate() {
+        let a = addr.wrapping_add(i as u32);
+        match *s {
+            // anything that can't be written back wasn't writeable to begin with
+            Saved::Byte(b) => { let _ = memory.write_u8(a, b); }
+            // memory comes into being a block at a time, so none of the block was there yet
+            Saved::Uninit if discarded != Some(a >> BLOCK_SIZE_LOG_2) => {
+                discarded = Some(a >> BLOCK_SIZE_LOG_2);
+                memory.discard(a & !((1 << BLOCK_SIZE_LOG_2) - 1), 1 << BLOCK_SIZE_LOG_2)
+            }
+            Saved::Uninit | Saved::Unreadable => {}
+        }
+    }
+}
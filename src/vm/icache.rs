@@ -0,0 +1,61 @@
+use crate::memory::{Memory, MemoryResult};
+use super::instruction::Instruction;
+
+/// direct mapped cache of decoded instructions, tagged by pc
+///
+/// stores that go through the vm invalidate it themselves,
+/// anything else writing to code has to call `invalidate` (or `clear`)
+pub struct ICache {
+    entries: Box<[Option<(u32, u32, Instruction)>]>,
+}
+impl ICache {
+    const SIZE_LOG_2: u32 = 12;
+
+    pub fn new() -> Self {
+        Self {
+            entries: vec![None; 1 << Self::SIZE_LOG_2].into_boxed_slice()
+        }
+    }
+
+    fn index(pc: u32) -> usize {
+        ((pc >> 2) & ((1 << Self::SIZE_LOG_2) - 1)) as usize
+    }
+
+    /// returns the raw instruction word alongside the decoded instruction
+    pub fn fetch<M: Memory>(&mut self, pc: u32, memory: &M) -> MemoryResult<(u32, Instruction)> {
+        let e = &mut self.entries[Self::index(pc)];
+        match e {
+            Some((tag, iw, i)) if *tag == pc => Ok((*iw, *i)),
+            _ => {
+                let iw = memory.fetch(pc)?;
+                let i = Instruction::from_iword(iw);
+                *e = Some((pc, iw, i));
+                Ok((iw, i))
+            }
+        }
+    }
+
+    /// drops any instruction overlapping `len` bytes at `addr`
+    pub fn invalidate(&mut self, addr: u32, len: u32) {
+        if len as usize >= self.entries.len() * 4 {
+            return self.clear()
+        }
+        let first = addr & !3;
+        let last = addr.wrapping_add(len.max(1) - 1) & !3;
+        let mut a = first;
+        loop {
+            let e = &mut self.entries[Self::index(a)];
+            if e.is_some_and(|(tag, _, _)| tag == a) {
+                *e = None
+            }
+            if a == last {
+                break
+            }
+            a = a.wrapping_add(4);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.fill(None)
+    }
+}
@@ -2,9 +2,15 @@ use super::registers::RegisterSelector;
 use crate::utils::*;
 
 pub mod arithmetic;
+pub mod float;
+pub mod func;
 pub mod immupper;
 pub mod mem;
+pub mod packed;
+#[cfg(test)]
+pub mod encode;
 
+#[derive(Clone, Copy)]
 pub struct Instruction {
     pub opcode: Opcode,
     pub funct: u32,
@@ -26,6 +32,13 @@ impl Instruction {
         }
         else { regv }
     }
+    /// None for the register form
+    pub fn immediate(&self) -> Option<u32> {
+        if self.is_imm {
+            Some(self.primary_immediate)
+        }
+        else { None }
+    }
 
     /// infallible - every bit pattern is a valid instruction
     /// 
@@ -63,7 +76,7 @@ impl InsData {
 }
 
 use Opcode::*;
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Opcode {
     Func,
     Arith, ArithSkip,
@@ -103,12 +116,16 @@ impl Opcode {
     }
     fn extract_funct(&self, i: u32, is_imm: bool) -> u32 {
         let funct5a = extract_5_bits(i, 9);
-        let funct3 = i & !(2u32.pow(29) - 1) << 5;
+        let funct3 = (i >> 29) << 5;
         match self {
             Arith => {
-                if !is_imm {
+                // with funct3 set, rs3 is a third operand rather than more funct
+                if !is_imm && funct3 == 0 {
                     let f5b = extract_5_bits(i, 24) << 8;
-                    funct5a | funct3 | f5b
+                    funct5a | f5b
+                }
+                else if !is_imm {
+                    funct5a | funct3
                 }
                 else {
                     funct5a
@@ -116,7 +133,7 @@ impl Opcode {
             }
             Io => funct5a | funct3,
             ImmUpper => funct5a & 0b1111,
-            Func => funct5a & 1,
+            Func => funct5a & 0b111,
             _ => funct5a
         }
     }
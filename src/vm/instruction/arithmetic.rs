@@ -1,7 +1,10 @@
-pub fn arithmetic(s1: u32, s2: u32, funct: u32) -> Option<u32> {
+/// s3 is only used by register-form ops with funct3 set, where rs3 is an operand
+pub fn arithmetic(s1: u32, s2: u32, s3: u32, funct: u32) -> Option<u32> {
     Some(match funct {
         0 => s1.wrapping_add(s2), // add
+        1 => extract(s1, s2, false), // ext.u: the field s2 describes, see `field`
         2 => s2.wrapping_sub(s1), // sub: s2 - s1. for reg - imm, add a negative immediate
+        3 => extract(s1, s2, true), // ext.i: the same, sign extended
         4 => s1 & s2, // and
         5 => s1 | s2, // or
         6 => s1 ^ s2, // xor
@@ -10,7 +13,7 @@ pub fn arithmetic(s1: u32, s2: u32, funct: u32) -> Option<u32> {
         8 => s1.widening_mul(s2).0, // mull.u
         9 => s1.widening_mul(s2).1, // mulh.u
         10 => (s1 as i32).wrapping_mul(s2 as i32) as u32, // mul.i
-        11 => todo!("mulh.i is harder than it sounds ok"), // mulh.i
+        11 => (s1 as i32).widening_mul(s2 as i32).1 as u32, // mulh.i
 
         12 => s1.checked_div(s2).unwrap_or(-1i32 as u32),
         13 => s1.checked_rem(s2).unwrap_or(s1),
@@ -43,6 +46,187 @@ pub fn arithmetic(s1: u32, s2: u32, funct: u32) -> Option<u32> {
             }) as u32
         }
 
+        // bit manipulation
+        96 => s1.leading_zeros(), // clz
+        97 => s1.trailing_zeros(), // ctz
+        98 => s1.count_ones(), // cpop
+        99 => s1.swap_bytes(), // bswap
+        100 => s1.reverse_bits(), // brev
+        101 => (s1 as i32).min(s2 as i32) as u32, // min.i
+        102 => (s1 as i32).max(s2 as i32) as u32, // max.i
+        103 => s1.min(s2), // min.u
+        104 => s1.max(s2), // max.u
+        105 => { // ins: s1 with the low bits of s2 put in the field s3 describes
+            let (pos, mask) = field(s3);
+            (s1 & !(mask << pos) as u32) | ((s2 as u64 & mask) << pos) as u32
+        }
+
+        128..=159 => return super::packed::packed(s1, s2, s3, funct),
+
+        // multi-word: the carry or borrow comes in as s3 != 0, and goes out as 0 or 1
+        64 => (s1 as u64 + s2 as u64 + (s3 != 0) as u64) as u32, // adc: s1 + s2 + carry
+        65 => ((s1 as u64 + s2 as u64 + (s3 != 0) as u64) >> 32) as u32, // adc.c: carry out
+        66 => s1.wrapping_sub(s2).wrapping_sub((s3 != 0) as u32), // sbb: s1 - s2 - borrow
+        67 => ((s1 as u64) < s2 as u64 + (s3 != 0) as u64) as u32, // sbb.b: borrow out
+
+        // 64 bit dividend s2:s1 over a 32 bit divisor s3, with the same answers for / 0 as above
+        68..=73 => {
+            let n = (s2 as u64) << 32 | s1 as u64;
+            let q = match funct {
+                68 | 69 => n.checked_div(s3 as u64).unwrap_or(u64::MAX),
+                70 => n.checked_rem(s3 as u64).unwrap_or(n),
+                71 | 72 => (n as i64).checked_div(s3 as i32 as i64).unwrap_or(-1) as u64,
+                73 => (n as i64).checked_rem(s3 as i32 as i64).unwrap_or(n as i64) as u64,
+                _ => unreachable!()
+            };
+            // 69 and 72 are the high half of the quotient
+            if funct == 69 || funct == 72 { (q >> 32) as u32 } else { q as u32 }
+        }
+
         _ => return None
     })
 }
+
+/// a bit field as `pos | (width - 1) << 5`, which fits an immediate. bits past 31 are dropped
+fn field(spec: u32) -> (u32, u64) {
+    let pos = spec & 31;
+    let width = ((spec >> 5) & 31) + 1;
+    (pos, (1u64 << width) - 1)
+}
+fn extract(s1: u32, spec: u32, signed: bool) -> u32 {
+    let (pos, mask) = field(spec);
+    let v = (s1 as u64 >> pos) & mask;
+    let width = mask.count_ones();
+    if signed && width < 32 {
+        (((v as u32) << (32 - width)) as i32 >> (32 - width)) as u32
+    }
+    else { v as u32 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDGES: [u32; 12] = [
+        0, 1, 2, 3, 0x7fff_ffff, 0x8000_0000, 0x8000_0001,
+        0xffff_fffe, 0xffff_ffff, 0x1234_5678, 0xdead_beef, 0x0001_0000,
+    ];
+
+    #[test]
+    fn mulh_i() {
+        for a in EDGES {
+            for b in EDGES {
+                let wide = a as i32 as i64 * b as i32 as i64;
+                assert_eq!(arithmetic(a, b, 0, 11), Some((wide >> 32) as u32), "{:x} * {:x}", a, b);
+                assert_eq!(arithmetic(a, b, 0, 10), Some(wide as u32));
+            }
+        }
+    }
+
+    #[test]
+    fn carries() {
+        for a in EDGES {
+            for b in EDGES {
+                for c in [0, 1, 7] {
+                    let cin = (c != 0) as u64;
+                    let sum = a as u64 + b as u64 + cin;
+                    assert_eq!(arithmetic(a, b, c, 64), Some(sum as u32));
+                    assert_eq!(arithmetic(a, b, c, 65), Some((sum >> 32) as u32));
+
+                    let diff = a as i64 - b as i64 - cin as i64;
+                    assert_eq!(arithmetic(a, b, c, 66), Some(diff as u32));
+                    assert_eq!(arithmetic(a, b, c, 67), Some((diff < 0) as u32));
+                }
+            }
+        }
+
+        // 64 bit add and subtract out of 32 bit halves
+        let (x, y) = (0x0000_0001_ffff_ffffu64, 0xffff_ffff_0000_0001u64);
+        let add = |a: u64, b: u64| {
+            let c = arithmetic(a as u32, b as u32, 0, 65).unwrap();
+            let lo = arithmetic(a as u32, b as u32, 0, 64).unwrap();
+            let hi = arithmetic((a >> 32) as u32, (b >> 32) as u32, c, 64).unwrap();
+            (hi as u64) << 32 | lo as u64
+        };
+        let sub = |a: u64, b: u64| {
+            let br = arithmetic(a as u32, b as u32, 0, 67).unwrap();
+            let lo = arithmetic(a as u32, b as u32, 0, 66).unwrap();
+            let hi = arithmetic((a >> 32) as u32, (b >> 32) as u32, br, 66).unwrap();
+            (hi as u64) << 32 | lo as u64
+        };
+        assert_eq!(add(x, y), x.wrapping_add(y));
+        assert_eq!(sub(x, y), x.wrapping_sub(y));
+        assert_eq!(sub(y, x), y.wrapping_sub(x));
+    }
+
+    #[test]
+    fn divide_64() {
+        for lo in EDGES {
+            for hi in EDGES {
+                for d in EDGES {
+                    let n = (hi as u64) << 32 | lo as u64;
+                    let div = |f| arithmetic(lo, hi, d, f).unwrap();
+                    let quot = |f_lo, f_hi| (div(f_hi) as u64) << 32 | div(f_lo) as u64;
+
+                    let (q, r) = match d {
+                        0 => (u64::MAX, n),
+                        _ => (n / d as u64, n % d as u64)
+                    };
+                    assert_eq!(quot(68, 69), q, "{:x} / {:x}", n, d);
+                    assert_eq!(div(70), r as u32);
+
+                    let (n, d) = (n as i64, d as i32 as i64);
+                    let (q, r) = match (n, d) {
+                        (_, 0) | (i64::MIN, -1) => (-1, n),
+                        _ => (n / d, n % d)
+                    };
+                    assert_eq!(quot(71, 72), q as u64, "{} / {}", n, d);
+                    assert_eq!(div(73), r as u32);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bits() {
+        let reference_clz = |x: u32| (0..32).rev().take_while(|b| x >> b & 1 == 0).count() as u32;
+        let reference_ctz = |x: u32| (0..32).take_while(|b| x >> b & 1 == 0).count() as u32;
+        for a in EDGES {
+            assert_eq!(arithmetic(a, 0, 0, 96), Some(reference_clz(a)));
+            assert_eq!(arithmetic(a, 0, 0, 97), Some(reference_ctz(a)));
+            assert_eq!(arithmetic(a, 0, 0, 98), Some((0..32).filter(|b| a >> b & 1 != 0).count() as u32));
+            let brev = (0..32).fold(0, |v, b| v | (a >> b & 1) << (31 - b));
+            assert_eq!(arithmetic(a, 0, 0, 100), Some(brev));
+            for b in EDGES {
+                assert_eq!(arithmetic(a, b, 0, 101), Some((a as i32 as i64).min(b as i32 as i64) as u32));
+                assert_eq!(arithmetic(a, b, 0, 102), Some((a as i32 as i64).max(b as i32 as i64) as u32));
+                assert_eq!(arithmetic(a, b, 0, 103), Some((a as u64).min(b as u64) as u32));
+                assert_eq!(arithmetic(a, b, 0, 104), Some((a as u64).max(b as u64) as u32));
+            }
+        }
+        assert_eq!(arithmetic(0x1234_5678, 0, 0, 99), Some(0x7856_3412));
+    }
+
+    #[test]
+    fn fields() {
+        for a in EDGES {
+            for pos in 0..32 {
+                for width in 1..=32 {
+                    let spec = pos | (width - 1) << 5;
+                    let bits: Vec<u32> = (pos..pos + width).map(|b| if b < 32 { a >> b & 1 } else { 0 }).collect();
+                    let unsigned = bits.iter().enumerate().fold(0, |v, (i, b)| v | b << i);
+                    assert_eq!(arithmetic(a, spec, 0, 1), Some(unsigned));
+
+                    let top = bits[width as usize - 1];
+                    let signed = (width..32).fold(unsigned, |v, i| v | top << i);
+                    assert_eq!(arithmetic(a, spec, 0, 3), Some(signed), "{:x} {} {}", a, pos, width);
+
+                    // putting back what came out changes nothing, and putting in all ones sets just the field
+                    assert_eq!(arithmetic(a, unsigned, spec, 105), Some(a));
+                    let ones = (pos..(pos + width).min(32)).fold(0, |v, b| v | 1 << b);
+                    assert_eq!(arithmetic(a, u32::MAX, spec, 105), Some(a | ones));
+                }
+            }
+        }
+    }
+}
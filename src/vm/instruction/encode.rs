@@ -0,0 +1,92 @@
+// assembles single instruction words for tests
+// register and funct arguments aren't range checked
+
+pub fn arith_imm(rd: u32, rs1: u32, funct: u32, imm: i32) -> u32 {
+    1 | 4 << 1 | rd << 4 | funct << 9 | rs1 << 14 | (imm as u32) << 19
+}
+/// the whole register-form funct: funct5a, then funct3 at bit 5 and funct5b at bit 8
+pub fn arith_reg(rd: u32, rs1: u32, rs2: u32, funct: u32) -> u32 {
+    4 << 1 | rd << 4 | (funct & 31) << 9 | rs1 << 14 | rs2 << 19 | ((funct >> 8) & 31) << 24 | ((funct >> 5) & 7) << 29
+}
+/// three operands: funct has funct3 set, and is below 256
+pub fn arith_reg3(rd: u32, rs1: u32, rs2: u32, rs3: u32, funct: u32) -> u32 {
+    arith_reg(rd, rs1, rs2, funct) | rs3 << 24
+}
+pub fn skip_imm(rd: u32, rs1: u32, funct: u32, imm: i32) -> u32 {
+    arith_imm(rd, rs1, funct, imm) ^ (4 << 1) ^ (6 << 1)
+}
+pub fn skip_reg(rd: u32, rs1: u32, rs2: u32, funct: u32) -> u32 {
+    arith_reg(rd, rs1, rs2, funct) ^ (4 << 1) ^ (6 << 1)
+}
+pub fn imm_upper(rd: u32, funct: u32, imm: u32) -> u32 {
+    1 | 7 << 1 | rd << 4 | funct << 9 | (imm & !((1 << 13) - 1))
+}
+
+pub fn load_imm(rd: u32, rs1: u32, funct: u32, imm: i32) -> u32 {
+    1 | 1 << 1 | rd << 4 | funct << 9 | rs1 << 14 | (imm as u32) << 19
+}
+/// the register form, which is the only one with room for rs3
+pub fn load_reg(rd: u32, rs1: u32, rs2: u32, rs3: u32, funct: u32) -> u32 {
+    1 << 1 | rd << 4 | funct << 9 | rs1 << 14 | rs2 << 19 | rs3 << 24
+}
+pub fn lw(rd: u32, rs1: u32, imm: i32) -> u32 {
+    load_imm(rd, rs1, 0, imm)
+}
+/// stores rs3 to rs1 + imm, imm is 13 bits signed
+pub fn store_imm(rs1: u32, rs3: u32, funct: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    1 | 3 << 1 | (imm & 31) << 4 | funct << 9 | rs1 << 14 | ((imm >> 5) & 31) << 19 | rs3 << 24 | ((imm >> 10) & 7) << 29
+}
+pub fn store_reg(rs1: u32, rs2: u32, rs3: u32, funct: u32) -> u32 {
+    3 << 1 | funct << 9 | rs1 << 14 | rs2 << 19 | rs3 << 24
+}
+pub fn sw(rs1: u32, rs3: u32) -> u32 {
+    store_imm(rs1, rs3, 0, 0)
+}
+
+pub fn io_imm(rd: u32, rs1: u32, funct: u32, imm: u32) -> u32 {
+    1 | 5 << 1 | rd << 4 | (funct & 31) << 9 | rs1 << 14 | imm << 19 | (funct >> 5) << 29
+}
+pub fn call(rd: u32, offset: i32) -> u32 {
+    1 | 2 << 1 | rd << 4 | (offset as u32) << 13
+}
+pub fn ret(rs2: u32) -> u32 {
+    2 << 1 | 1 << 9 | rs2 << 19
+}
+pub fn tail(rd: u32, offset: i32) -> u32 {
+    call(rd, offset) | 4 << 9
+}
+/// for the indirect forms, which go to s1 + s2
+pub fn func_reg(rd: u32, rs1: u32, rs2: u32, funct: u32) -> u32 {
+    2 << 1 | rd << 4 | funct << 9 | rs1 << 14 | rs2 << 19
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::instruction::{Instruction, Opcode, packed};
+
+    #[test]
+    fn round_trip() {
+        let funct = 0b10101 << 8 | 0b00100;
+        let i = Instruction::from_iword(arith_reg(5, 6, 7, funct));
+        assert_eq!((i.opcode, i.funct, i.rd.inner(), i.rs1.inner(), i.rs2.inner()), (Opcode::Arith, funct, 5, 6, 7));
+
+        let funct = 0b011 << 5 | 0b00100;
+        let i = Instruction::from_iword(arith_reg3(5, 6, 7, 8, funct));
+        assert_eq!((i.opcode, i.funct, i.rs2.inner(), i.rs3.inner()), (Opcode::Arith, funct, 7, 8));
+
+        let funct = packed::SHUF | packed::H;
+        let i = Instruction::from_iword(arith_reg3(9, 10, 11, 12, funct));
+        assert_eq!((i.opcode, i.funct, i.rs1.inner(), i.rs3.inner()), (Opcode::Arith, funct, 10, 12));
+
+        let i = Instruction::from_iword(skip_reg(1, 2, 3, 4));
+        assert_eq!((i.opcode, i.funct, i.rd.inner(), i.rs1.inner(), i.rs2.inner()), (Opcode::ArithSkip, 4, 1, 2, 3));
+
+        let i = Instruction::from_iword(store_imm(3, 4, 1, -700));
+        assert_eq!((i.opcode, i.funct, i.rs1.inner(), i.rs3.inner(), i.immediate()), (Opcode::St, 1, 3, 4, Some(-700i32 as u32)));
+
+        let i = Instruction::from_iword(imm_upper(9, 7, 0xdead_b000));
+        assert_eq!((i.opcode, i.funct, i.rs1.inner(), i.immediate()), (Opcode::ImmUpper, 7, 9, Some(0xdead_a000)));
+    }
+}
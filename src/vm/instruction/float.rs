@@ -0,0 +1,401 @@
+/// single precision floats, kept in the ordinary registers as their bit patterns
+///
+/// these take the arithmetic functs with funct3 = 1, register form only, so rs3 is free for fused multiply-add.
+/// results are rounded as the rounding mode in fcsr says, and anything inexact, invalid and so on sets
+/// a flag in fcsr that stays set until fcsr is written. every nan that comes out is the canonical one
+pub const FADD: u32 = 32;
+/// s1 - s2, unlike the integer sub
+pub const FSUB: u32 = 33;
+pub const FMUL: u32 = 34;
+pub const FDIV: u32 = 35;
+pub const FSQRT: u32 = 36;
+pub const FMIN: u32 = 37;
+pub const FMAX: u32 = 38;
+pub const FEQ: u32 = 39;
+pub const FLT: u32 = 40;
+pub const FLE: u32 = 41;
+/// from a signed int
+pub const FCVT_S_W: u32 = 42;
+/// from an unsigned int
+pub const FCVT_S_WU: u32 = 43;
+/// to a signed int, saturating
+pub const FCVT_W_S: u32 = 44;
+/// to an unsigned int, saturating
+pub const FCVT_WU_S: u32 = 45;
+/// s1 * s2 + s3, rounded once
+pub const FMADD: u32 = 46;
+/// s1 * s2 - s3, rounded once
+pub const FMSUB: u32 = 47;
+/// -(s1 * s2) + s3, rounded once
+pub const FNMADD: u32 = 48;
+/// returns fcsr
+pub const FRCSR: u32 = 56;
+/// returns fcsr and replaces it with s1
+pub const FSCSR: u32 = 57;
+
+/// rounding modes, in bits 5-7 of fcsr
+pub const RNE: u32 = 0;
+pub const RTZ: u32 = 1;
+pub const RDN: u32 = 2;
+pub const RUP: u32 = 3;
+/// to nearest, ties away from zero
+pub const RMM: u32 = 4;
+
+/// exception flags, in bits 0-4 of fcsr
+pub const NX: u32 = 1;
+/// tininess is detected before rounding
+pub const UF: u32 = 2;
+pub const OF: u32 = 4;
+pub const DZ: u32 = 8;
+pub const NV: u32 = 16;
+
+const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+pub fn is_float(funct: u32) -> bool {
+    (32..64).contains(&funct)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FloatError {
+    Funct,
+    RoundingMode,
+}
+
+pub fn float(s1: u32, s2: u32, s3: u32, funct: u32, fcsr: &mut u32) -> Result<u32, FloatError> {
+    let rm = (*fcsr >> 5) & 7;
+    let rounds = !matches!(funct, FMIN | FMAX | FEQ | FLT | FLE | FRCSR | FSCSR);
+    if rounds && rm > RMM {
+        return Err(FloatError::RoundingMode)
+    }
+    let (a, b, c) = (f32::from_bits(s1), f32::from_bits(s2), f32::from_bits(s3));
+    let mut f = Flags { rm, flags: 0 };
+
+    let v = match funct {
+        FADD => f.add(a, b).to_bits(),
+        FSUB => f.add(a, -b).to_bits(),
+        FMUL => f.fma(a, b, None).to_bits(),
+        FDIV => f.div(a, b).to_bits(),
+        FSQRT => f.sqrt(a).to_bits(),
+        FMIN | FMAX => f.min_max(a, b, funct == FMAX).to_bits(),
+        FEQ => {
+            f.check_signaling(&[a, b]);
+            (a == b) as u32
+        }
+        FLT | FLE => {
+            if a.is_nan() || b.is_nan() {
+                f.flags |= NV
+            }
+            (if funct == FLT { a < b } else { a <= b }) as u32
+        }
+        FCVT_S_W => f.round(s1 as i32 as f64, 0.0).to_bits(),
+        FCVT_S_WU => f.round(s1 as f64, 0.0).to_bits(),
+        FCVT_W_S => f.round_int(a, i32::MIN as f64, i32::MAX as f64) as i32 as u32,
+        FCVT_WU_S => f.round_int(a, 0.0, u32::MAX as f64) as u32,
+        FMADD => f.fma(a, b, Some(c)).to_bits(),
+        FMSUB => f.fma(a, b, Some(-c)).to_bits(),
+        FNMADD => f.fma(-a, b, Some(c)).to_bits(),
+        FRCSR => *fcsr,
+        FSCSR => {
+            let old = *fcsr;
+            *fcsr = s1 & 0xff;
+            return Ok(old)
+        }
+        _ => return Err(FloatError::Funct)
+    };
+    *fcsr |= f.flags;
+    Ok(v)
+}
+
+struct Flags {
+    rm: u32,
+    flags: u32,
+}
+impl Flags {
+    fn nan(&mut self, operands: &[f32]) -> f32 {
+        self.check_signaling(operands);
+        f32::from_bits(CANONICAL_NAN)
+    }
+    fn invalid(&mut self) -> f32 {
+        self.flags |= NV;
+        f32::from_bits(CANONICAL_NAN)
+    }
+    fn check_signaling(&mut self, operands: &[f32]) {
+        if operands.iter().any(|x| x.is_nan() && x.to_bits() & (1 << 22) == 0) {
+            self.flags |= NV
+        }
+    }
+
+    /// rounds `r + err` to f32, where `err` is small enough that only its sign matters
+    ///
+    /// f64 has enough bits over f32 that `r` and the sign of `err` pin the exact result down to
+    /// one of the two f32s either side of it, or a tie between them
+    fn round(&mut self, r: f64, err: f64) -> f32 {
+        let n = r as f32;
+        if r.is_infinite() || (n as f64 == r && err == 0.0) {
+            return n
+        }
+        self.flags |= NX;
+        let (lo, hi) = if (n as f64) < r || (n as f64 == r && err > 0.0) {
+            (n, n.next_up())
+        }
+        else {
+            (n.next_down(), n)
+        };
+
+        // infinity stands in for 2^128 when working out which is nearer
+        let wide = |f: f32| if f.is_infinite() { 2f64.powi(128).copysign(f as f64) } else { f as f64 };
+        let up = match self.rm {
+            RTZ => r < 0.0,
+            RDN => false,
+            RUP => true,
+            _ => {
+                let (below, above) = (r - wide(lo), wide(hi) - r);
+                if below != above {
+                    above < below
+                }
+                else if err != 0.0 {
+                    err > 0.0
+                }
+                else if self.rm == RMM {
+                    r > 0.0
+                }
+                else {
+                    hi.to_bits() & 1 == 0
+                }
+            }
+        };
+        let v = if up { hi } else { lo };
+
+        if v.is_infinite() || r.abs() > f32::MAX as f64 {
+            self.flags |= OF
+        }
+        if r.abs() < f32::MIN_POSITIVE as f64 {
+            self.flags |= UF
+        }
+        v
+    }
+
+    /// the sign of an exact zero sum of opposite signs depends on the rounding mode
+    fn exact_zero(&self, x: f64, y: f64) -> f64 {
+        if x.is_sign_negative() != y.is_sign_negative() {
+            if self.rm == RDN { -0.0 } else { 0.0 }
+        }
+        else { x }
+    }
+
+    fn add(&mut self, a: f32, b: f32) -> f32 {
+        if a.is_nan() || b.is_nan() {
+            return self.nan(&[a, b])
+        }
+        if a.is_infinite() && b.is_infinite() && a != b {
+            return self.invalid()
+        }
+        let (x, y) = (a as f64, b as f64);
+        let (r, err) = two_sum(x, y);
+        if r == 0.0 && err == 0.0 {
+            return self.exact_zero(x, y) as f32
+        }
+        self.round(r, err)
+    }
+
+    /// a * b, plus c if there is one. the product of two f32s is exact in f64
+    fn fma(&mut self, a: f32, b: f32, c: Option<f32>) -> f32 {
+        let operands = [a, b, c.unwrap_or(0.0)];
+        if (a.is_infinite() && b == 0.0) || (a == 0.0 && b.is_infinite()) {
+            self.check_signaling(&operands);
+            return self.invalid()
+        }
+        if operands.iter().any(|x| x.is_nan()) {
+            return self.nan(&operands)
+        }
+        let p = a as f64 * b as f64;
+        let Some(c) = c else { return self.round(p, 0.0) };
+        if p.is_infinite() && c.is_infinite() && p.is_sign_negative() != c.is_sign_negative() {
+            return self.invalid()
+        }
+        let (r, err) = two_sum(p, c as f64);
+        if r == 0.0 && err == 0.0 {
+            return self.exact_zero(p, c as f64) as f32
+        }
+        self.round(r, err)
+    }
+
+    fn div(&mut self, a: f32, b: f32) -> f32 {
+        if a.is_nan() || b.is_nan() {
+            return self.nan(&[a, b])
+        }
+        if (a == 0.0 && b == 0.0) || (a.is_infinite() && b.is_infinite()) {
+            return self.invalid()
+        }
+        if b == 0.0 && a.is_finite() {
+            self.flags |= DZ
+        }
+        let (x, y) = (a as f64, b as f64);
+        let q = x / y;
+        if !q.is_finite() || q == 0.0 {
+            return q as f32
+        }
+        // what's left over, exactly, and so which side of q the true quotient is
+        let rem = (-q).mul_add(y, x);
+        self.round(q, rem * y.signum())
+    }
+
+    fn sqrt(&mut self, a: f32) -> f32 {
+        if a.is_nan() {
+            return self.nan(&[a])
+        }
+        if a < 0.0 {
+            return self.invalid()
+        }
+        let x = a as f64;
+        let r = x.sqrt();
+        if !r.is_finite() || r == 0.0 {
+            return r as f32
+        }
+        self.round(r, (-r).mul_add(r, x))
+    }
+
+    /// -0 counts as less than +0, and a nan loses to any number
+    fn min_max(&mut self, a: f32, b: f32, max: bool) -> f32 {
+        self.check_signaling(&[a, b]);
+        match (a.is_nan(), b.is_nan()) {
+            (true, true) => f32::from_bits(CANONICAL_NAN),
+            (true, false) => b,
+            (false, true) => a,
+            _ if a == b => if a.is_sign_negative() != max { a } else { b },
+            _ => if max { a.max(b) } else { a.min(b) }
+        }
+    }
+
+    /// rounds to an integer in `min..=max`, saturating and flagging invalid outside it
+    fn round_int(&mut self, a: f32, min: f64, max: f64) -> f64 {
+        if a.is_nan() {
+            self.flags |= NV;
+            return max
+        }
+        let x = a as f64;
+        let r = match self.rm {
+            RTZ => x.trunc(),
+            RDN => x.floor(),
+            RUP => x.ceil(),
+            RNE => x.round_ties_even(),
+            _ => x.round(),
+        };
+        if r < min || r > max {
+            self.flags |= NV;
+            return if r < min { min } else { max }
+        }
+        if r != x {
+            self.flags |= NX
+        }
+        r
+    }
+}
+
+/// x + y as the nearest f64 and the exact error
+fn two_sum(x: f64, y: f64) -> (f64, f64) {
+    let s = x + y;
+    let yy = s - x;
+    let xx = s - yy;
+    (s, (x - xx) + (y - yy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::Rng;
+
+    fn op(funct: u32, a: f32, b: f32, rm: u32) -> (f32, u32) {
+        let mut fcsr = rm << 5;
+        let v = float(a.to_bits(), b.to_bits(), 0, funct, &mut fcsr).unwrap();
+        (f32::from_bits(v), fcsr & 31)
+    }
+
+    fn same(x: f32, y: f32) -> bool {
+        x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan())
+    }
+
+    #[test]
+    fn nearest_matches_host() {
+        // random bit patterns, so every class of float turns up
+        let mut r = Rng(0x1234_5678_9abc_def1);
+        for _ in 0..100_000 {
+            let [a, b, c] = [(); 3].map(|_| f32::from_bits(r.next()));
+            assert!(same(op(FADD, a, b, RNE).0, a + b), "{} + {}", a, b);
+            assert!(same(op(FSUB, a, b, RNE).0, a - b), "{} - {}", a, b);
+            assert!(same(op(FMUL, a, b, RNE).0, a * b), "{} * {}", a, b);
+            assert!(same(op(FDIV, a, b, RNE).0, a / b), "{} / {}", a, b);
+            assert!(same(op(FSQRT, a, b, RNE).0, a.sqrt()), "sqrt {}", a);
+
+            let mut fcsr = 0;
+            let v = float(a.to_bits(), b.to_bits(), c.to_bits(), FMADD, &mut fcsr).unwrap();
+            assert!(same(f32::from_bits(v), a.mul_add(b, c)), "{} * {} + {}", a, b, c);
+        }
+    }
+
+    #[test]
+    fn directed_rounding() {
+        let tiny = 2f32.powi(-30);
+        assert_eq!(op(FADD, 1.0, tiny, RUP), (1f32.next_up(), NX));
+        assert_eq!(op(FADD, 1.0, tiny, RDN), (1.0, NX));
+        assert_eq!(op(FADD, 1.0, -tiny, RTZ), (1f32.next_down(), NX));
+        assert_eq!(op(FADD, -1.0, -tiny, RTZ), (-1.0, NX));
+        assert_eq!(op(FADD, -1.0, -tiny, RDN), (-1f32.next_up(), NX));
+
+        let third = op(FDIV, 1.0, 3.0, RUP).0;
+        assert_eq!(op(FDIV, 1.0, 3.0, RDN).0.next_up(), third);
+        assert_eq!(op(FSQRT, 2.0, 0.0, RUP).0, op(FSQRT, 2.0, 0.0, RTZ).0.next_up());
+        assert_eq!(op(FSQRT, 4.0, 0.0, RUP), (2.0, 0));
+
+        // halfway between 1 and the next float up
+        assert_eq!(op(FADD, 1.0, 2f32.powi(-24), RNE).0, 1.0);
+        assert_eq!(op(FADD, 1.0, 2f32.powi(-24), RMM).0, 1f32.next_up());
+
+        assert_eq!(op(FSUB, 1.0, 1.0, RDN).0.to_bits(), (-0f32).to_bits());
+        assert_eq!(op(FSUB, 1.0, 1.0, RNE).0.to_bits(), 0f32.to_bits());
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(op(FDIV, 1.0, 0.0, RNE), (f32::INFINITY, DZ));
+        assert_eq!(op(FDIV, 0.0, 0.0, RNE).1, NV);
+        assert_eq!(op(FSQRT, -1.0, 0.0, RNE).1, NV);
+        assert_eq!(op(FMUL, f32::MAX, 2.0, RNE), (f32::INFINITY, OF | NX));
+        assert_eq!(op(FMUL, f32::MAX, 2.0, RTZ), (f32::MAX, OF | NX));
+        assert_eq!(op(FMUL, -f32::MAX, 2.0, RUP), (-f32::MAX, OF | NX));
+        assert_eq!(op(FMUL, f32::MIN_POSITIVE, 0.3, RNE).1, UF | NX);
+        assert_eq!(op(FMUL, f32::MIN_POSITIVE, 0.5, RNE).1, 0);
+
+        let snan = f32::from_bits(0x7f80_0001);
+        assert_eq!(op(FADD, snan, 1.0, RNE).0.to_bits(), CANONICAL_NAN);
+        assert_eq!(op(FADD, snan, 1.0, RNE).1, NV);
+        assert_eq!(op(FEQ, f32::NAN, 1.0, RNE), (0.0, 0));
+        assert_eq!(op(FLT, f32::NAN, 1.0, RNE), (0.0, NV));
+        assert_eq!(op(FMIN, f32::NAN, 1.0, RNE), (1.0, 0));
+        assert_eq!(op(FMIN, 0.0, -0.0, RNE).0.to_bits(), (-0f32).to_bits());
+        assert_eq!(op(FMAX, -0.0, 0.0, RNE).0.to_bits(), 0f32.to_bits());
+
+        let mut fcsr = 7 << 5;
+        assert_eq!(float(0, 0, 0, FADD, &mut fcsr), Err(FloatError::RoundingMode));
+        assert_eq!(float(0, 0, 0, 63, &mut 0), Err(FloatError::Funct));
+    }
+
+    #[test]
+    fn conversions() {
+        let cvt = |funct, x: u32, rm: u32| {
+            let mut fcsr = rm << 5;
+            (float(x, 0, 0, funct, &mut fcsr).unwrap(), fcsr & 31)
+        };
+        assert_eq!(cvt(FCVT_S_W, -3i32 as u32, RNE), ((-3f32).to_bits(), 0));
+        assert_eq!(cvt(FCVT_S_WU, u32::MAX, RNE), (4294967296f32.to_bits(), NX));
+        assert_eq!(cvt(FCVT_S_WU, u32::MAX, RTZ), (4294967296f32.next_down().to_bits(), NX));
+
+        assert_eq!(cvt(FCVT_W_S, 2.5f32.to_bits(), RNE), (2, NX));
+        assert_eq!(cvt(FCVT_W_S, 2.5f32.to_bits(), RMM), (3, NX));
+        assert_eq!(cvt(FCVT_W_S, (-2.5f32).to_bits(), RDN), (-3i32 as u32, NX));
+        assert_eq!(cvt(FCVT_W_S, 1e10f32.to_bits(), RNE), (i32::MAX as u32, NV));
+        assert_eq!(cvt(FCVT_W_S, f32::NAN.to_bits(), RNE), (i32::MAX as u32, NV));
+        assert_eq!(cvt(FCVT_WU_S, (-1f32).to_bits(), RNE), (0, NV));
+        assert_eq!(cvt(FCVT_WU_S, (-0.25f32).to_bits(), RTZ), (0, NX));
+    }
+}
@@ -0,0 +1,18 @@
+/// Func functs. calls push a register window and tail calls don't, so a tail-called function
+/// returns straight to whoever called the function that tail-called it
+///
+/// the plain forms are pc relative like always. the indirect forms go exactly to s1 + s2,
+/// so a function pointer is the address of the function's first instruction.
+/// they only make sense in the register form, since the immediate overlaps rs1
+pub const CALL: u32 = 0;
+pub const RET: u32 = 1;
+pub const CALL_INDIRECT: u32 = 2;
+pub const TAIL: u32 = 4;
+pub const TAIL_INDIRECT: u32 = 6;
+
+pub fn is_valid(funct: u32) -> bool {
+    matches!(funct, CALL | RET | CALL_INDIRECT | TAIL | TAIL_INDIRECT)
+}
+pub fn is_indirect(funct: u32) -> bool {
+    matches!(funct, CALL_INDIRECT | TAIL_INDIRECT)
+}
@@ -1,7 +1,12 @@
 use crate::memory::{Memory, MemoryError};
+use super::Opcode;
+
+pub fn effective_address(s1: u32, s2: u32) -> u32 {
+    s1.wrapping_add_signed(s2 as i32)
+}
 
 pub fn load<M: Memory>(s1: u32, s2: u32, funct: u32, mem: &M) -> Result<u32, LoadError> {
-    let addr = s1.wrapping_add_signed(s2 as i32);
+    let addr = effective_address(s1, s2);
     
     let res = match funct {
         0 => mem.read_u32(addr)?, // lw
@@ -15,6 +20,97 @@ pub fn load<M: Memory>(s1: u32, s2: u32, funct: u32, mem: &M) -> Result<u32, Loa
     Ok(res)
 }
 
+/// bytes read by a load funct
+pub fn load_width(funct: u32) -> Option<u32> {
+    match funct {
+        0 => Some(4),
+        1 | 2 => Some(2),
+        3 | 4 => Some(1),
+        _ => None
+    }
+}
+
+/// load-reserved, takes a reservation on the word
+pub const LR: u32 = 8;
+/// store-conditional, stores s3 and returns 0 if the reservation survived, 1 otherwise
+pub const SC: u32 = 9;
+/// stores s3, returns the old value
+pub const SWAP: u32 = 10;
+/// adds s3, returns the old value
+pub const FETCH_ADD: u32 = 11;
+/// stores s3 if the word is s2, returns the old value
+pub const CAS: u32 = 12;
+
+/// atomics live in the load funct space, as they all need an rd
+///
+/// they take the address from s1 alone, so s2 is free to be what cas compares against
+pub fn is_atomic(funct: u32) -> bool {
+    (LR..=CAS).contains(&funct)
+}
+
+/// compares s3 bytes at s1 with those at s2, giving 0 if they're the same, otherwise 1 or -1 like memcmp
+pub const COMPARE: u32 = 13;
+
+/// bulk stores, which take their addresses as they are and their length from s3
+///
+/// copies s3 bytes from s2 to s1, which can overlap
+pub const COPY: u32 = 3;
+/// sets s3 bytes at s1 to the low byte of s2
+pub const FILL: u32 = 4;
+
+/// address a load funct reads from
+pub fn load_address(s1: u32, s2: u32, funct: u32) -> u32 {
+    if is_atomic(funct) || funct == COMPARE { s1 } else { effective_address(s1, s2) }
+}
+/// address a store funct writes to
+pub fn store_address(s1: u32, s2: u32, funct: u32) -> u32 {
+    if matches!(funct, COPY | FILL) { s1 } else { effective_address(s1, s2) }
+}
+
+/// the bytes an instruction might store to, as (address, width)
+pub fn store_target(opcode: Opcode, funct: u32, s1: u32, s2: u32, s3: u32) -> Option<(u32, u32)> {
+    match opcode {
+        Opcode::St if matches!(funct, COPY | FILL) => (s3 != 0).then_some((s1, s3)),
+        Opcode::St => store_width(funct).map(|w| (effective_address(s1, s2), w)),
+        Opcode::Ld if is_atomic(funct) && funct != LR => Some((s1, 4)),
+        _ => None
+    }
+}
+
+pub fn compare<M: Memory>(s1: u32, s2: u32, s3: u32, mem: &M) -> Result<u32, LoadError> {
+    Ok(mem.compare(s1, s2, s3)? as i32 as u32)
+}
+
+/// `reserved` is whether an sc still holds its reservation, the caller keeps track of those.
+/// returns the value for rd and whether memory was written
+///
+/// every instruction already runs on its own, even with several harts, so nothing extra is needed to make these atomic
+pub fn atomic<M: Memory>(s1: u32, s2: u32, s3: u32, funct: u32, reserved: bool, mem: &mut M) -> Result<(u32, bool), LoadError> {
+    let addr = s1;
+    if !addr.is_multiple_of(4) {
+        return Err(LoadError::Mem(MemoryError::Unaligned))
+    }
+    if funct == SC {
+        if !reserved {
+            return Ok((1, false))
+        }
+        mem.write_u32(addr, s3)?;
+        return Ok((0, true))
+    }
+
+    let old = mem.read_u32(addr)?;
+    let new = match funct {
+        LR => return Ok((old, false)),
+        SWAP => s3,
+        FETCH_ADD => old.wrapping_add(s3),
+        CAS if old == s2 => s3,
+        CAS => return Ok((old, false)),
+        _ => return Err(LoadError::Funct)
+    };
+    mem.write_u32(addr, new)?;
+    Ok((old, true))
+}
+
 #[derive(Debug, PartialEq)]
 pub enum LoadError {
     Mem(MemoryError),
@@ -26,10 +122,22 @@ impl From<MemoryError> for LoadError {
     }
 }
 
+/// bytes written by a store funct
+pub fn store_width(funct: u32) -> Option<u32> {
+    match funct {
+        0 => Some(4),
+        1 => Some(2),
+        2 => Some(1),
+        _ => None
+    }
+}
+
 pub fn store<M: Memory>(s1: u32, s2: u32, s3: u32, funct: u32, mem: &mut M) -> Result<(), StoreError> {
-    let addr = s1.wrapping_add_signed(s2 as i32);
+    let addr = effective_address(s1, s2);
     
     Ok(match funct {
+        COPY => mem.copy(s1, s2, s3)?,
+        FILL => mem.fill(s1, s2 as u8, s3)?,
         0 => mem.write_u32(addr, s3)?,
         1 => mem.write_u16(addr, s3 as u16)?,
         2 => mem.write_u8(addr, s3 as u8)?,
@@ -64,4 +172,49 @@ mod tests {
         assert_eq!(load(104, 0, 3, &mut mem), Ok(1234 & 0xff));
         assert_eq!(load(104, 0, 4, &mut mem), Ok(-46i32 as u32));
     }
+
+    #[test]
+    fn atomics() {
+        let mut mem = MainMemory::new(vec![]).unwrap();
+        mem.write_u32(100, 5).unwrap();
+
+        assert_eq!(atomic(100, 0, 7, FETCH_ADD, false, &mut mem), Ok((5, true)));
+        assert_eq!(atomic(100, 0, 1, SWAP, false, &mut mem), Ok((12, true)));
+        assert_eq!(atomic(100, 2, 9, CAS, false, &mut mem), Ok((1, false)));
+        assert_eq!(atomic(100, 1, 9, CAS, false, &mut mem), Ok((1, true)));
+        assert_eq!(atomic(100, 0, 0, LR, false, &mut mem), Ok((9, false)));
+        assert_eq!(atomic(100, 0, 3, SC, false, &mut mem), Ok((1, false)));
+        assert_eq!(atomic(100, 0, 3, SC, true, &mut mem), Ok((0, true)));
+        assert_eq!(load(100, 0, 0, &mut mem), Ok(3));
+
+        assert_eq!(atomic(102, 0, 0, SWAP, false, &mut mem), Err(LoadError::Mem(MemoryError::Unaligned)));
+        assert_eq!(atomic(0x2000, 0, 0, LR, false, &mut mem), Err(LoadError::Mem(MemoryError::Uninit)));
+    }
+
+    #[test]
+    fn bulk() {
+        let mut mem = MainMemory::new(vec![0; 8]).unwrap();
+        store(0xfff, 0xab, 0x1005, FILL, &mut mem).unwrap();
+        assert_eq!(load(0xffc, 0, 0, &mem), Ok(0xab00_0000));
+        assert_eq!(load(0x2000, 0, 0, &mem), Ok(0xabab_abab));
+        assert_eq!(load(0x2004, 0, 0, &mem), Ok(0));
+
+        // overlapping both ways, and across the end of the object
+        mem.write_u32(4, 0x0403_0201).unwrap();
+        store(5, 4, 4, COPY, &mut mem).unwrap();
+        assert_eq!(load(4, 0, 0, &mem), Ok(0x0302_0101));
+        assert_eq!(load(8, 0, 0, &mem), Ok(4));
+        store(4, 6, 4, COPY, &mut mem).unwrap();
+        assert_eq!(load(4, 0, 0, &mem), Ok(0x0004_0302));
+
+        assert_eq!(compare(4, 4, 8, &mem), Ok(0));
+        assert_eq!(compare(4, 5, 3, &mem), Ok(-1i32 as u32));
+        assert_eq!(compare(5, 4, 3, &mem), Ok(1));
+        assert_eq!(compare(0x2ffe, 0x2ffe, 4, &mem), Err(LoadError::Mem(MemoryError::Uninit)));
+        // the first byte already differs, so the uninitialized block after it never comes into it
+        assert_eq!(compare(0x2ffe, 0x1000, 4, &mem), Ok(-1i32 as u32));
+        assert_eq!(store(0x10, 0x3000, 0x10, COPY, &mut mem), Err(StoreError::Mem(MemoryError::Uninit)));
+        assert_eq!(store(0xffff_fff0, 0, 0x11, FILL, &mut mem), Err(StoreError::Mem(MemoryError::OutOfBounds)));
+        assert_eq!(store_target(Opcode::St, FILL, 0x10, 0, 0), None);
+    }
 }
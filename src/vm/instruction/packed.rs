@@ -0,0 +1,140 @@
+/// packed integers: a register as four u8 lanes, or with `H` as two u16 lanes
+///
+/// these are the arithmetic functs with funct3 = 4, register form only. lane 0 is the low bits.
+/// compares fill a lane with ones when true, and signed ops see each lane as two's complement
+pub const H: u32 = 1;
+
+pub const ADD: u32 = 128;
+/// s1 - s2
+pub const SUB: u32 = 130;
+pub const ADDS_U: u32 = 132;
+pub const ADDS_I: u32 = 134;
+pub const SUBS_U: u32 = 136;
+pub const SUBS_I: u32 = 138;
+pub const CMPEQ: u32 = 140;
+pub const CMPLT_U: u32 = 142;
+pub const CMPLT_I: u32 = 144;
+pub const MIN_U: u32 = 146;
+pub const MAX_U: u32 = 148;
+pub const MIN_I: u32 = 150;
+pub const MAX_I: u32 = 152;
+/// rounds up
+pub const AVG_U: u32 = 154;
+/// each lane of s2 picks a lane out of s1 then s3, as if they were one register twice as wide.
+/// a selector with its top bit set gives zero
+pub const SHUF: u32 = 156;
+
+pub fn packed(s1: u32, s2: u32, s3: u32, funct: u32) -> Option<u32> {
+    let bits = if funct & H == 0 { 8 } else { 16 };
+    let op = funct & !H;
+    if !(ADD..=SHUF).contains(&op) {
+        return None
+    }
+    let mask = (1u32 << bits) - 1;
+    let lane = |v: u32, i: u32| (v >> (i * bits)) & mask;
+    let signed = |v: u32| ((v << (32 - bits)) as i32 >> (32 - bits)) as i64;
+    // both ends of a signed lane
+    let (lo, hi) = (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1);
+
+    let mut r = 0;
+    for i in 0..32 / bits {
+        let (x, y) = (lane(s1, i), lane(s2, i));
+        let (sx, sy) = (signed(x), signed(y));
+        let v = match op {
+            ADD => x.wrapping_add(y),
+            SUB => x.wrapping_sub(y),
+            ADDS_U => (x + y).min(mask),
+            ADDS_I => (sx + sy).clamp(lo, hi) as u32,
+            SUBS_U => x.saturating_sub(y),
+            SUBS_I => (sx - sy).clamp(lo, hi) as u32,
+            CMPEQ => if x == y { mask } else { 0 },
+            CMPLT_U => if x < y { mask } else { 0 },
+            CMPLT_I => if sx < sy { mask } else { 0 },
+            MIN_U => x.min(y),
+            MAX_U => x.max(y),
+            MIN_I => sx.min(sy) as u32,
+            MAX_I => sx.max(sy) as u32,
+            AVG_U => (x + y + 1) >> 1,
+            SHUF => {
+                let lanes = 32 / bits;
+                let sel = y & (2 * lanes - 1);
+                if y >> (bits - 1) != 0 {
+                    0
+                }
+                else if sel < lanes {
+                    lane(s1, sel)
+                }
+                else {
+                    lane(s3, sel - lanes)
+                }
+            }
+            _ => return None
+        };
+        r |= (v & mask) << (i * bits);
+    }
+    Some(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::Rng;
+
+    /// every op again, lane by lane with the host's own u8/i8/u16/i16 arithmetic
+    fn reference(s1: u32, s2: u32, s3: u32, funct: u32) -> u32 {
+        macro_rules! lanes {
+            ($u:ty, $i:ty) => {{
+                const N: usize = 4 / std::mem::size_of::<$u>();
+                let split = |v: u32| -> [$u; N] { std::array::from_fn(|i| (v >> (i * <$u>::BITS as usize)) as $u) };
+                let (a, b) = (split(s1), split(s2));
+                let wide = [split(s1), split(s3)].concat();
+                let ones = |c: bool| if c { <$u>::MAX } else { 0 };
+                let out: [$u; N] = std::array::from_fn(|i| {
+                    let (x, y) = (a[i], b[i]);
+                    let (sx, sy) = (x as $i, y as $i);
+                    match funct & !H {
+                        ADD => x.wrapping_add(y),
+                        SUB => x.wrapping_sub(y),
+                        ADDS_U => x.saturating_add(y),
+                        ADDS_I => sx.saturating_add(sy) as $u,
+                        SUBS_U => x.saturating_sub(y),
+                        SUBS_I => sx.saturating_sub(sy) as $u,
+                        CMPEQ => ones(x == y),
+                        CMPLT_U => ones(x < y),
+                        CMPLT_I => ones(sx < sy),
+                        MIN_U => x.min(y),
+                        MAX_U => x.max(y),
+                        MIN_I => sx.min(sy) as $u,
+                        MAX_I => sx.max(sy) as $u,
+                        AVG_U => x.midpoint(y) + ((x ^ y) & 1),
+                        SHUF => if sy < 0 { 0 } else { wide[y as usize % (2 * N)] },
+                        _ => unreachable!()
+                    }
+                });
+                out.iter().enumerate().fold(0, |r, (i, &v)| r | (v as u32) << (i * <$u>::BITS as usize))
+            }};
+        }
+        if funct & H == 0 { lanes!(u8, i8) } else { lanes!(u16, i16) }
+    }
+
+    #[test]
+    fn against_host() {
+        let mut r = Rng(0x0bad_cafe_f00d_d00d);
+        let edges = [0, u32::MAX, 0x8080_8080, 0x7f7f_7f7f, 0x8000_7fff, 0x0102_0304, 0x0001_fffe];
+        for funct in (ADD..=SHUF + H).filter(|f| packed(0, 0, 0, *f).is_some()) {
+            for _ in 0..2000 {
+                let [s1, s2, s3] = [(); 3].map(|_| if r.below(4) == 0 { r.pick(&edges) } else { r.next() });
+                assert_eq!(packed(s1, s2, s3, funct), Some(reference(s1, s2, s3, funct)), "{} {:x} {:x}", funct, s1, s2);
+            }
+        }
+        assert_eq!(packed(0, 0, 0, SHUF + 2), None);
+    }
+
+    #[test]
+    fn lanes() {
+        assert_eq!(packed(0x10ff_0180, 0x1001_0180, 0, ADDS_U), Some(0x20ff_02ff));
+        assert_eq!(packed(0x7fff_8000, 0x0001_ffff, 0, ADDS_I | H), Some(0x7fff_8000));
+        assert_eq!(packed(0x0403_0201, 0x8000_0307, 0x0807_0605, SHUF), Some(0x0001_0408));
+        assert_eq!(packed(0x0000_0001, 0x0000_0002, 0, AVG_U), Some(0x0000_0002));
+    }
+}
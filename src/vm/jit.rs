@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use crate::io::IoHandler;
+use crate::memory::Memory;
+use crate::utils::syscall6;
+use super::{VM, VMError, ILEN};
+use super::icache::ICache;
+use super::machine::Harts;
+use super::instruction::{Instruction, Opcode, mem};
+use super::registers::RegisterSelector as RS;
+
+/// translates straight line runs of instructions into x86-64
+///
+/// arithmetic, imm_upper, loads and stores get compiled, with memory accesses calling back into `M`.
+/// blocks end at skips, at anything that writes the pc and before anything that can't be compiled,
+/// which is then left to `VM::cycle`. stores into compiled code throw every block away
+pub struct Jit<M: Memory> {
+    blocks: HashMap<u32, Block>,
+    /// compiled instructions all fall within code_lo..code_hi
+    code_lo: u32,
+    code_hi: u32,
+    _m: PhantomData<M>,
+}
+
+struct Block {
+    code: ExecMem,
+}
+
+/// shared with the generated code, which fills in the first three fields on the way out
+#[repr(C)]
+struct Ctx<M> {
+    next_pc: u32,
+    executed: u32,
+    status: u32,
+
+    mem: *mut M,
+    icache: *mut Option<ICache>,
+    harts: *const Harts,
+    code_lo: u32,
+    code_hi: u32,
+}
+const OFF_NEXT_PC: u8 = 0;
+const OFF_EXECUTED: u8 = 4;
+const OFF_STATUS: u8 = 8;
+
+const STATUS_OK: u32 = 0;
+/// the instruction at next_pc faulted and hasn't run
+const STATUS_FAULT: u32 = 1;
+/// a store hit compiled code
+const STATUS_SMC: u32 = 2;
+
+const MAX_BLOCK: usize = 64;
+
+type BlockFn<M> = unsafe extern "C" fn(*const *mut u32, *mut Ctx<M>);
+
+impl<M: Memory> Jit<M> {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            code_lo: u32::MAX,
+            code_hi: 0,
+            _m: PhantomData,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.code_lo = u32::MAX;
+        self.code_hi = 0;
+    }
+
+    /// runs a compiled block at the current pc if there is one, otherwise a single `VM::cycle`
+    ///
+    /// tracing, history, io logs, shadow memory, the timing model, branch prediction and profiling all want to see every cycle, so with any of them on this is just `VM::cycle`
+    pub fn step(&mut self, vm: &mut VM, io: &mut IoHandler, memory: &mut M) -> Result<bool, VMError> {
+        if vm.tracer.is_some() || vm.history.is_some() || vm.io_log.is_some() || vm.shadow.is_some() || vm.timing.is_some() || vm.branches.is_some() || vm.profile.is_some() {
+            return vm.cycle(io, memory)
+        }
+        let pc = vm.registers.read(RS::PC);
+        if !self.blocks.contains_key(&pc) {
+            match compile::<M>(pc, memory) {
+                Some((b, len)) => {
+                    self.code_lo = self.code_lo.min(pc);
+                    self.code_hi = self.code_hi.max(pc.saturating_add(len * ILEN));
+                    self.blocks.insert(pc, b);
+                }
+                None => return self.interpret(vm, io, memory)
+            }
+        }
+
+        let b = &self.blocks[&pc];
+        let regs = vm.registers.pointers();
+        let mut ctx = Ctx {
+            next_pc: 0, executed: 0, status: STATUS_OK,
+            mem: memory as *mut M,
+            icache: &mut vm.icache as *mut _,
+            harts: &*vm.harts as *const _,
+            code_lo: self.code_lo, code_hi: self.code_hi,
+        };
+        unsafe {
+            let f: BlockFn<M> = std::mem::transmute(b.code.ptr);
+            f(regs.as_ptr(), &mut ctx);
+        }
+
+        vm.cycles += ctx.executed as u64;
+        vm.registers.write(RS::PC, ctx.next_pc);
+        match ctx.status {
+            // let the interpreter produce the error, from exactly the right state
+            STATUS_FAULT => vm.cycle(io, memory),
+            STATUS_SMC => {
+                self.clear();
+                Ok(false)
+            }
+            _ => Ok(false)
+        }
+    }
+
+    fn interpret(&mut self, vm: &mut VM, io: &mut IoHandler, memory: &mut M) -> Result<bool, VMError> {
+        let pc = vm.registers.read(RS::PC);
+        let store = memory.read_u32(pc).ok().map(Instruction::from_iword).and_then(|i| {
+            let (s1, s2) = (vm.registers.read(i.rs1), i.select_source_2(vm.registers.read(i.rs2)));
+            mem::store_target(i.opcode, i.funct, s1, s2, vm.registers.read(i.rs3))
+        });
+        let res = vm.cycle(io, memory);
+        if let Some((addr, width)) = store {
+            if overlaps(addr, width, self.code_lo, self.code_hi) {
+                self.clear()
+            }
+        }
+        res
+    }
+}
+
+fn overlaps(addr: u32, width: u32, lo: u32, hi: u32) -> bool {
+    (addr as u64) < hi as u64 && addr as u64 + width as u64 > lo as u64
+}
+
+extern "C" fn load_helper<M: Memory>(ctx: *mut Ctx<M>, addr: u32, funct: u32) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+    match mem::load(addr, 0, funct, unsafe { &*ctx.mem }) {
+        Ok(v) => v as u64,
+        Err(_) => 1 << 32
+    }
+}
+extern "C" fn store_helper<M: Memory>(ctx: *mut Ctx<M>, addr: u32, funct: u32, v: u32) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    if mem::store(addr, 0, v, funct, unsafe { &mut *ctx.mem }).is_err() {
+        return STATUS_FAULT
+    }
+    let width = mem::store_width(funct).unwrap_or(4);
+    if let Some(c) = unsafe { &mut *ctx.icache } {
+        c.invalidate(addr, width)
+    }
+    unsafe { &*ctx.harts }.stored(addr, width);
+    if overlaps(addr, width, ctx.code_lo, ctx.code_hi) {
+        STATUS_SMC
+    }
+    else { STATUS_OK }
+}
+
+/// returns the block and how many instructions went into it, or None if the first one can't be compiled
+fn compile<M: Memory>(start: u32, memory: &M) -> Option<(Block, u32)> {
+    let mut a = Asm::new();
+    a.prologue();
+
+    let mut pc = start;
+    let mut n = 0;
+    let mut ended = false;
+    while n < MAX_BLOCK {
+        let Ok(iw) = memory.read_u32(pc) else { break };
+        let i = Instruction::from_iword(iw);
+        if !supported(&i) {
+            break
+        }
+        ended = a.instruction::<M>(&i, pc, n as u32);
+        n += 1;
+        pc = pc.wrapping_add(ILEN);
+        if ended {
+            break
+        }
+    }
+    if n == 0 {
+        return None
+    }
+    if !ended {
+        a.exit(pc, n as u32, STATUS_OK)
+    }
+    Some((Block { code: ExecMem::new(&a.buf)? }, n as u32))
+}
+
+fn supported(i: &Instruction) -> bool {
+    use Opcode::*;
+    match i.opcode {
+        Arith | ArithSkip => matches!(i.funct, 0 | 2 | 4..=10 | 16..=31),
+        ImmUpper => matches!(i.funct, 0 | 1 | 4..=7),
+        Ld => mem::load_width(i.funct).is_some(),
+        St => mem::store_width(i.funct).is_some(),
+        _ => false
+    }
+}
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+
+/// just enough of an x86-64 assembler
+///
+/// rbx holds the register pointer table and r12 the context for the whole block
+struct Asm {
+    buf: Vec<u8>,
+}
+impl Asm {
+    fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+    fn emit(&mut self, b: &[u8]) {
+        self.buf.extend_from_slice(b)
+    }
+    fn imm32(&mut self, v: u32) {
+        self.emit(&v.to_le_bytes())
+    }
+
+    fn prologue(&mut self) {
+        // push rbx; push r12; push r13 (keeps the stack 16 byte aligned for calls)
+        self.emit(&[0x53, 0x41, 0x54, 0x41, 0x55]);
+        // mov rbx, rdi; mov r12, rsi
+        self.emit(&[0x48, 0x89, 0xfb, 0x49, 0x89, 0xf4]);
+    }
+    fn epilogue(&mut self) {
+        // pop r13; pop r12; pop rbx; ret
+        self.emit(&[0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+    }
+
+    fn store_ctx_imm(&mut self, off: u8, v: u32) {
+        // mov dword [r12 + off], imm32
+        self.emit(&[0x41, 0xc7, 0x44, 0x24, off]);
+        self.imm32(v)
+    }
+    fn exit(&mut self, next_pc: u32, executed: u32, status: u32) {
+        self.store_ctx_imm(OFF_NEXT_PC, next_pc);
+        self.store_ctx_imm(OFF_EXECUTED, executed);
+        self.store_ctx_imm(OFF_STATUS, status);
+        self.epilogue()
+    }
+    /// jumps to eax + ILEN, the same increment VM::cycle applies to writes to the pc
+    fn exit_dynamic(&mut self, executed: u32) {
+        // add eax, ILEN; mov [r12], eax
+        self.emit(&[0x83, 0xc0, ILEN as u8]);
+        self.emit(&[0x41, 0x89, 0x44, 0x24, OFF_NEXT_PC]);
+        self.store_ctx_imm(OFF_EXECUTED, executed);
+        self.store_ctx_imm(OFF_STATUS, STATUS_OK);
+        self.epilogue()
+    }
+
+    /// jcc rel32 with the offset left for `bind`
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.emit(&[0x0f, 0x80 | cc]);
+        self.imm32(0);
+        self.buf.len()
+    }
+    fn bind(&mut self, at: usize) {
+        let rel = (self.buf.len() - at) as u32;
+        self.buf[at - 4..at].copy_from_slice(&rel.to_le_bytes())
+    }
+
+    fn load_reg(&mut self, dst: u8, r: RS, pc: u32) {
+        match r.inner() {
+            // xor dst, dst
+            0 => self.emit(&[0x31, 0xc0 | dst << 3 | dst]),
+            // reading the pc gives the address of the current instruction
+            2 => {
+                self.emit(&[0xb8 + dst]);
+                self.imm32(pc)
+            }
+            r => {
+                // mov dst64, [rbx + 8r]; mov dst, [dst64]
+                self.emit(&[0x48, 0x8b, 0x80 | dst << 3 | 3]);
+                self.imm32(r as u32 * 8);
+                self.emit(&[0x8b, dst << 3 | dst])
+            }
+        }
+    }
+    fn load_source_2(&mut self, i: &Instruction, pc: u32) {
+        match i.immediate() {
+            Some(v) => {
+                // mov ecx, imm32
+                self.emit(&[0xb9]);
+                self.imm32(v)
+            }
+            None => self.load_reg(ECX, i.rs2, pc)
+        }
+    }
+    /// writes eax to rd, leaving eax alone. the pc is handled by the caller
+    ///
+    /// r0 does get written, like `Registers::write` does, even though nothing reads it back
+    fn store_rd(&mut self, rd: RS) {
+        // mov rdx, [rbx + 8rd]; mov [rdx], eax
+        self.emit(&[0x48, 0x8b, 0x93]);
+        self.imm32(rd.inner() as u32 * 8);
+        self.emit(&[0x89, 0x02])
+    }
+
+    fn call(&mut self, f: usize) {
+        // mov rdi, r12; mov rax, imm64; call rax
+        self.emit(&[0x4c, 0x89, 0xe7, 0x48, 0xb8]);
+        self.emit(&(f as u64).to_le_bytes());
+        self.emit(&[0xff, 0xd0])
+    }
+
+    /// eax = s1 op ecx
+    fn arith(&mut self, funct: u32) {
+        match funct {
+            0 => self.emit(&[0x01, 0xc8]), // add eax, ecx
+            2 => self.emit(&[0x29, 0xc1, 0x89, 0xc8]), // sub ecx, eax; mov eax, ecx
+            4 => self.emit(&[0x21, 0xc8]), // and
+            5 => self.emit(&[0x09, 0xc8]), // or
+            6 => self.emit(&[0x31, 0xc8]), // xor
+            7 => self.emit(&[0xf7, 0xd0]), // not eax
+            8 | 10 => self.emit(&[0x0f, 0xaf, 0xc1]), // imul eax, ecx (low half is the same signed or not)
+            9 => self.emit(&[0xf7, 0xe1, 0x89, 0xd0]), // mul ecx; mov eax, edx
+            16 | 18 => self.emit(&[0xd3, 0xe0]), // shl eax, cl
+            17 => self.emit(&[0xd3, 0xe8]), // shr
+            19 => self.emit(&[0xd3, 0xf8]), // sar
+            20 => self.emit(&[0xd3, 0xc0]), // rol
+            21 => self.emit(&[0xd3, 0xc8]), // ror
+            22..=31 => {
+                let cc = [0x4, 0x5, 0x7, 0x3, 0xf, 0xd, 0x2, 0x6, 0xc, 0xe][funct as usize - 22];
+                // cmp eax, ecx; setcc al; movzx eax, al
+                self.emit(&[0x39, 0xc8, 0x0f, 0x90 | cc, 0xc0, 0x0f, 0xb6, 0xc0])
+            }
+            _ => unreachable!("checked by supported()")
+        }
+    }
+    fn imm_upper(&mut self, funct: u32) {
+        match funct {
+            0 => self.emit(&[0x01, 0xc8]),
+            1 => self.emit(&[0x29, 0xc8]), // sub eax, ecx
+            4 => self.emit(&[0x21, 0xc8]),
+            5 => self.emit(&[0x09, 0xc8]),
+            6 => self.emit(&[0x31, 0xc8]),
+            7 => {
+                // and eax, 0x1fff; or eax, ecx
+                self.emit(&[0x25]);
+                self.imm32((1 << 13) - 1);
+                self.emit(&[0x09, 0xc8])
+            }
+            _ => unreachable!("checked by supported()")
+        }
+    }
+
+    /// `n` is how many instructions of the block came before this one. returns true if the block ends here
+    fn instruction<M: Memory>(&mut self, i: &Instruction, pc: u32, n: u32) -> bool {
+        use Opcode::*;
+        match i.opcode {
+            Arith | ArithSkip | ImmUpper => {
+                self.load_reg(EAX, i.rs1, pc);
+                self.load_source_2(i, pc);
+                if let ImmUpper = i.opcode {
+                    self.imm_upper(i.funct)
+                }
+                else {
+                    self.arith(i.funct)
+                }
+            }
+            Ld => {
+                self.load_reg(EAX, i.rs1, pc);
+                self.load_source_2(i, pc);
+                // add eax, ecx; mov esi, eax; mov edx, funct
+                self.emit(&[0x01, 0xc8, 0x89, 0xc6, 0xba]);
+                self.imm32(i.funct);
+                self.call(load_helper::<M> as *const () as usize);
+                // bt rax, 32; jnc ok
+                self.emit(&[0x48, 0x0f, 0xba, 0xe0, 0x20]);
+                let ok = self.jcc(0x3);
+                self.exit(pc, n, STATUS_FAULT);
+                self.bind(ok);
+            }
+            St => {
+                self.load_reg(EAX, i.rs1, pc);
+                self.load_source_2(i, pc);
+                // add eax, ecx; mov esi, eax
+                self.emit(&[0x01, 0xc8, 0x89, 0xc6]);
+                self.load_reg(ECX, i.rs3, pc);
+                self.emit(&[0xba]);
+                self.imm32(i.funct);
+                self.call(store_helper::<M> as *const () as usize);
+                // test eax, eax; jz ok; cmp eax, STATUS_SMC; jz smc
+                self.emit(&[0x85, 0xc0]);
+                let ok = self.jcc(0x4);
+                self.emit(&[0x83, 0xf8, STATUS_SMC as u8]);
+                let smc = self.jcc(0x4);
+                self.exit(pc, n, STATUS_FAULT);
+                self.bind(smc);
+                self.exit(pc.wrapping_add(ILEN), n + 1, STATUS_SMC);
+                self.bind(ok);
+                return false
+            }
+            _ => unreachable!("checked by supported()")
+        }
+
+        if i.rd == RS::PC {
+            self.exit_dynamic(n + 1);
+            return true
+        }
+        self.store_rd(i.rd);
+        if let ArithSkip = i.opcode {
+            // test eax, eax; jnz skip
+            self.emit(&[0x85, 0xc0]);
+            let skip = self.jcc(0x5);
+            self.exit(pc.wrapping_add(ILEN), n + 1, STATUS_OK);
+            self.bind(skip);
+            self.exit(pc.wrapping_add(2 * ILEN), n + 1, STATUS_OK);
+            return true
+        }
+        false
+    }
+}
+
+/// an executable mapping, written once and then flipped to read+exec
+struct ExecMem {
+    ptr: *mut u8,
+    len: usize,
+}
+impl ExecMem {
+    const PROT_READ: usize = 1;
+    const PROT_WRITE: usize = 2;
+    const PROT_EXEC: usize = 4;
+    const MAP_PRIVATE: usize = 2;
+    const MAP_ANONYMOUS: usize = 0x20;
+
+    fn new(code: &[u8]) -> Option<Self> {
+        let len = (code.len() + 4095) & !4095;
+        unsafe {
+            let ptr = syscall6(9, 0, len, Self::PROT_READ | Self::PROT_WRITE, Self::MAP_PRIVATE | Self::MAP_ANONYMOUS, usize::MAX, 0);
+            if ptr < 0 {
+                return None
+            }
+            let ptr = ptr as *mut u8;
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+            let m = Self { ptr, len };
+            if syscall6(10, ptr as usize, len, Self::PROT_READ | Self::PROT_EXEC, 0, 0, 0) < 0 {
+                return None
+            }
+            Some(m)
+        }
+    }
+}
+impl Drop for ExecMem {
+    fn drop(&mut self) {
+        unsafe {
+            syscall6(11, self.ptr as usize, self.len, 0, 0, 0, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MainMemory;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::{program, Rng, random_instruction};
+
+    const DATA: u32 = 0x1000;
+
+    /// runs until the pc reaches `end`, returning the first error
+    fn run(words: &[u32], end: u32, jit: bool) -> (VM, MainMemory, Result<(), VMError>) {
+        let mut mem = program(words);
+        for a in (DATA..DATA + 0x100).step_by(4) {
+            mem.write_u32(a, a.wrapping_mul(0x9e37_79b9)).unwrap();
+        }
+        let mut io = IoHandler::new();
+        let mut vm = VM::new();
+        vm.registers.write(RS::new(1).unwrap(), DATA);
+        let mut j = Jit::new();
+
+        let mut res = Ok(());
+        while vm.registers.read(RS::PC) < end && vm.cycles < 100_000 {
+            let r = if jit { j.step(&mut vm, &mut io, &mut mem) } else { vm.cycle(&mut io, &mut mem) };
+            if let Err(e) = r {
+                res = Err(e);
+                break
+            }
+        }
+        (vm, mem, res)
+    }
+
+    fn assert_same(words: &[u32], end: u32) {
+        let (vi, mi, ri) = run(words, end, false);
+        let (vj, mj, rj) = run(words, end, true);
+        assert_eq!(ri, rj);
+        assert_eq!(vi.registers, vj.registers);
+        assert_eq!(vi.cycles, vj.cycles);
+        for a in (DATA..DATA + 0x100).step_by(4) {
+            assert_eq!(mi.read_u32(a), mj.read_u32(a), "memory at {:x}", a);
+        }
+        for a in (0..end).step_by(4) {
+            assert_eq!(mi.read_u32(a), mj.read_u32(a), "code at {:x}", a);
+        }
+    }
+
+    #[test]
+    fn differential() {
+        let mut r = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let len = 1 + r.below(100) as usize;
+            let mut words: Vec<u32> = (0..len).map(|_| random_instruction(&mut r)).collect();
+            // two of them, so a skip on the last instruction still lands on one
+            words.extend([call(0, 0), call(0, 0)]);
+            assert_same(&words, len as u32 * ILEN);
+        }
+    }
+
+    #[test]
+    fn loop_with_skip() {
+        let words = [
+            arith_imm(8, 0, 0, 100), arith_imm(9, 9, 0, 3), arith_imm(8, 8, 0, -1),
+            skip_imm(0, 8, 22, 0), arith_imm(2, 0, 0, 0), call(0, 0),
+        ];
+        assert_same(&words, 20);
+        let (vm, _, _) = run(&words, 20, true);
+        assert_eq!(vm.registers.read(RS::new(9).unwrap()), 300);
+    }
+
+    #[test]
+    fn fault_mid_block() {
+        let words = [arith_imm(3, 0, 0, 5), lw(4, 0, 0x800), arith_imm(5, 0, 0, 1), call(0, 0)];
+        assert_same(&words, 12);
+        let (vm, _, res) = run(&words, 12, true);
+        assert_eq!(res, Err(VMError::Mem(crate::memory::MemoryError::Uninit)));
+        assert_eq!(vm.registers.read(RS::PC), 4);
+        assert_eq!(vm.cycles, 1);
+    }
+
+    #[test]
+    fn self_modifying_block() {
+        let words = [
+            lw(10, 0, 32), store_imm(0, 10, 0, 12), arith_imm(3, 0, 0, 1), arith_imm(9, 0, 0, 5),
+            call(0, 0), 0, 0, 0,
+            arith_imm(9, 0, 0, 6),
+        ];
+        assert_same(&words, 16);
+        let (vm, _, _) = run(&words, 16, true);
+        assert_eq!(vm.registers.read(RS::new(9).unwrap()), 6);
+    }
+}
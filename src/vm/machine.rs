@@ -0,0 +1,373 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use thiserror::Error;
+
+use crate::io::{IoHandler, IoError, IoResult};
+use crate::memory::Memory;
+use super::{VM, VMError};
+
+/// io functs for hart control, handled by the vm rather than `IoHandler`
+///
+/// returns the calling hart's id
+pub const IO_HART_ID: u32 = 96;
+/// starts hart s2 at address s1 with s3 in its r1. returns 0, or 1 if that hart is already running
+pub const IO_HART_START: u32 = 97;
+/// stops the calling hart, which can then be started again. on a lone vm this is an exit
+pub const IO_HART_STOP: u32 = 98;
+
+/// several vms, each with their own registers, sharing one memory and io handler
+///
+/// every instruction happens atomically and in a single total order that keeps each hart's own order,
+/// so guest code sees sequentially consistent memory whichever way the harts are scheduled.
+/// harts run without the decoded instruction cache, so code stored by one is seen by the rest straight away
+///
+/// hart 0 starts out running, the rest wait to be started. the machine stops once every hart has
+pub struct Machine<M: Memory> {
+    pub harts: Vec<VM>,
+    pub memory: M,
+    pub io: IoHandler,
+    ctl: Arc<Harts>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("hart {hart}: {error}")]
+pub struct HartError {
+    pub hart: u32,
+    pub error: VMError,
+}
+
+impl<M: Memory> Machine<M> {
+    /// `first` becomes hart 0 and keeps its registers, the other `n - 1` are fresh
+    pub fn new(first: VM, n: u32, memory: M, io: IoHandler) -> Self {
+        let ctl = Arc::new(Harts::new(n.max(1)));
+        let mut harts: Vec<VM> = std::iter::once(first).chain((1..n).map(|_| VM::new())).collect();
+        for (id, h) in harts.iter_mut().enumerate() {
+            h.set_icache(false);
+            h.set_hart(id as u32, ctl.clone());
+        }
+        Self { harts, memory, io, ctl }
+    }
+
+    /// runs up to `quantum` instructions of each running hart in turn, on this thread
+    pub fn run(&mut self, quantum: u64) -> Result<(), HartError> {
+        while !self.ctl.all_idle() {
+            for (id, vm) in self.harts.iter_mut().enumerate() {
+                match self.ctl.claim(id) {
+                    Slot::Idle => continue,
+                    Slot::Start { pc, arg } => vm.start(pc, arg),
+                    Slot::Running => {}
+                }
+                for _ in 0..quantum {
+                    let stopped = vm.cycle(&mut self.io, &mut self.memory).map_err(|error| HartError { hart: id as u32, error })?;
+                    if stopped {
+                        self.ctl.stop(id);
+                        break
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// runs every hart on its own host thread, taking turns on a lock around memory and io
+    ///
+    /// which interleaving happens is up to the host, so unlike `run` this isn't reproducible.
+    /// if harts fail, the error from the lowest numbered one is returned
+    pub fn run_threads(&mut self) -> Result<(), HartError> where M: Send {
+        let shared = Mutex::new((&mut self.memory, &mut self.io));
+        let ctl = &*self.ctl;
+        std::thread::scope(|s| {
+            let threads: Vec<_> = self.harts.iter_mut().enumerate().map(|(id, vm)| {
+                let shared = &shared;
+                s.spawn(move || {
+                    while let Some(slot) = ctl.wait(id) {
+                        if let Slot::Start { pc, arg } = slot {
+                            vm.start(pc, arg)
+                        }
+                        while !ctl.failed.load(Ordering::Relaxed) {
+                            let mut g = shared.lock().unwrap();
+                            let (memory, io) = &mut *g;
+                            match vm.cycle(io, *memory) {
+                                Ok(false) => {}
+                                Ok(true) => {
+                                    drop(g);
+                                    ctl.stop(id);
+                                    break
+                                }
+                                Err(error) => {
+                                    ctl.fail();
+                                    return Err(HartError { hart: id as u32, error })
+                                }
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            }).collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect::<Result<Vec<_>, _>>()
+        })?;
+        Ok(())
+    }
+}
+
+/// what each hart is doing, shared between the harts themselves and whatever runs them
+///
+/// a lone vm has one of these to itself
+pub struct Harts {
+    slots: Mutex<Vec<Slot>>,
+    changed: Condvar,
+    failed: AtomicBool,
+    /// word each hart's load-reserved holds, if any
+    reserved: Mutex<Vec<Option<u32>>>,
+    /// how many of those there are, so stores can skip the lock
+    outstanding: AtomicUsize,
+}
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Slot {
+    Idle,
+    Start { pc: u32, arg: u32 },
+    Running,
+}
+impl Harts {
+    /// hart 0 starts out running
+    pub fn new(n: u32) -> Self {
+        let mut slots = vec![Slot::Idle; n as usize];
+        slots[0] = Slot::Running;
+        Self {
+            slots: Mutex::new(slots),
+            changed: Condvar::new(),
+            failed: AtomicBool::new(false),
+            reserved: Mutex::new(vec![None; n as usize]),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    /// false if the hart is busy
+    pub fn start(&self, id: u32, pc: u32, arg: u32) -> IoResult<bool> {
+        let mut slots = self.slots.lock().unwrap();
+        let s = slots.get_mut(id as usize).ok_or(IoError::InvalidParams)?;
+        if *s != Slot::Idle {
+            return Ok(false)
+        }
+        *s = Slot::Start { pc, arg };
+        self.changed.notify_all();
+        Ok(true)
+    }
+
+    /// replaces any reservation `hart` already had
+    pub fn reserve(&self, hart: u32, addr: u32) {
+        let mut r = self.reserved.lock().unwrap();
+        if r[hart as usize].replace(addr & !3).is_none() {
+            self.outstanding.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// true if `hart` still has a reservation on `addr`, which is then gone either way
+    pub fn take_reservation(&self, hart: u32, addr: u32) -> bool {
+        let mut r = self.reserved.lock().unwrap();
+        match r[hart as usize].take() {
+            Some(a) => {
+                self.outstanding.fetch_sub(1, Ordering::Relaxed);
+                a == addr & !3
+            }
+            None => false
+        }
+    }
+    /// breaks every reservation on the words `width` bytes at `addr` touch
+    pub fn stored(&self, addr: u32, width: u32) {
+        if self.outstanding.load(Ordering::Relaxed) == 0 {
+            return
+        }
+        let (first, last) = (addr as u64 & !3, addr as u64 + width as u64 - 1);
+        for s in self.reserved.lock().unwrap().iter_mut() {
+            if s.is_some_and(|a| (first..=last).contains(&(a as u64))) {
+                *s = None;
+                self.outstanding.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// moves a hart that was asked to start to running, returning what it was before
+    fn claim(&self, id: usize) -> Slot {
+        let mut slots = self.slots.lock().unwrap();
+        let s = slots[id];
+        if let Slot::Start { .. } = s {
+            slots[id] = Slot::Running
+        }
+        s
+    }
+    /// like `claim`, but waits while the hart is idle. None once there's nothing left to do
+    fn wait(&self, id: usize) -> Option<Slot> {
+        let mut slots = self.slots.lock().unwrap();
+        loop {
+            if self.failed.load(Ordering::Relaxed) || slots.iter().all(|s| *s == Slot::Idle) {
+                return None
+            }
+            match slots[id] {
+                Slot::Idle => slots = self.changed.wait(slots).unwrap(),
+                s => {
+                    slots[id] = Slot::Running;
+                    return Some(s)
+                }
+            }
+        }
+    }
+
+    fn stop(&self, id: usize) {
+        self.slots.lock().unwrap()[id] = Slot::Idle;
+        self.changed.notify_all();
+    }
+    fn fail(&self) {
+        // taking the lock means no hart can be between checking and waiting
+        let _slots = self.slots.lock().unwrap();
+        self.failed.store(true, Ordering::Relaxed);
+        self.changed.notify_all();
+    }
+
+    fn all_idle(&self) -> bool {
+        self.slots.lock().unwrap().iter().all(|s| *s == Slot::Idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MainMemory, MemoryError};
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::program;
+
+    const DATA: i32 = 0x400;
+    const FLAG: i32 = 0x500;
+
+    fn machine(words: &[u32], n: u32) -> Machine<MainMemory> {
+        Machine::new(VM::new(), n, program(words), IoHandler::new())
+    }
+
+    /// hart 0 starts the rest, then they all write id + 100 to DATA + id * 4
+    fn ids() -> Vec<u32> {
+        let entry = 24;
+        vec![
+            arith_imm(3, 0, 0, entry),
+            io_imm(4, 3, IO_HART_START, 1), io_imm(4, 3, IO_HART_START, 2), io_imm(4, 3, IO_HART_START, 3),
+            io_imm(4, 3, IO_HART_START, 1),
+            arith_imm(2, 0, 0, entry - 4),
+
+            io_imm(5, 0, IO_HART_ID, 0), arith_imm(6, 5, 16, 2), arith_imm(7, 5, 0, 100), store_imm(6, 7, 0, DATA),
+            io_imm(0, 0, IO_HART_STOP, 0),
+        ]
+    }
+
+    #[test]
+    fn start_harts() {
+        let mut m = machine(&ids(), 4);
+        m.run(100).unwrap();
+        for id in 0..4 {
+            assert_eq!(m.memory.read_u32(DATA as u32 + id * 4), Ok(id + 100));
+        }
+        // hart 1 was still waiting to start when it was asked again
+        assert_eq!(m.harts[0].registers.read(super::super::RS::new(4).unwrap()), 1);
+
+        let mut m = machine(&ids(), 4);
+        m.run_threads().unwrap();
+        for id in 0..4 {
+            assert_eq!(m.memory.read_u32(DATA as u32 + id * 4), Ok(id + 100));
+        }
+    }
+
+    #[test]
+    fn message_passing() {
+        let producer = 40;
+        let words = [
+            store_imm(0, 0, 0, FLAG), store_imm(0, 0, 0, DATA),
+            arith_imm(3, 0, 0, producer), io_imm(0, 3, IO_HART_START, 1),
+            // spin until the flag is set, then copy the data
+            lw(8, 0, FLAG), skip_imm(0, 8, 23, 0), arith_imm(2, 0, 0, 16 - 4),
+            lw(9, 0, DATA), store_imm(0, 9, 0, DATA + 4),
+            io_imm(0, 0, IO_HART_STOP, 0),
+
+            arith_imm(10, 0, 0, 42), store_imm(0, 10, 0, DATA),
+            arith_imm(10, 0, 0, 1), store_imm(0, 10, 0, FLAG),
+            io_imm(0, 0, IO_HART_STOP, 0),
+        ];
+        for quantum in [1, 3, 1000] {
+            let mut m = machine(&words, 2);
+            m.run(quantum).unwrap();
+            assert_eq!(m.memory.read_u32(DATA as u32 + 4), Ok(42));
+        }
+        for _ in 0..10 {
+            let mut m = machine(&words, 2);
+            m.run_threads().unwrap();
+            assert_eq!(m.memory.read_u32(DATA as u32 + 4), Ok(42));
+        }
+    }
+
+    #[test]
+    fn atomic_counter() {
+        use crate::vm::instruction::mem::*;
+        let (entry, counter) = (24, DATA);
+        let words = [
+            store_imm(0, 0, 0, counter),
+            arith_imm(3, 0, 0, entry),
+            io_imm(0, 3, IO_HART_START, 1), io_imm(0, 3, IO_HART_START, 2), io_imm(0, 3, IO_HART_START, 3),
+            arith_imm(2, 0, 0, entry - 4),
+
+            // 100 times round, add one with each of fetch-add, lr/sc and cas
+            arith_imm(8, 0, 0, 100), arith_imm(9, 0, 0, counter), arith_imm(10, 0, 0, 1),
+            load_reg(11, 9, 0, 10, FETCH_ADD),
+            load_reg(12, 9, 0, 0, LR), arith_imm(12, 12, 0, 1), load_reg(13, 9, 0, 12, SC),
+            skip_imm(0, 13, 22, 0), arith_imm(2, 0, 0, 40 - 4),
+            lw(14, 9, 0), arith_imm(15, 14, 0, 1), load_reg(16, 9, 14, 15, CAS),
+            skip_reg(0, 16, 14, 22), arith_imm(2, 0, 0, 60 - 4),
+            arith_imm(8, 8, 0, -1), skip_imm(0, 8, 22, 0), arith_imm(2, 0, 0, 36 - 4),
+            io_imm(0, 0, IO_HART_STOP, 0),
+        ];
+        for quantum in [1, 5, 1000] {
+            let mut m = machine(&words, 4);
+            m.run(quantum).unwrap();
+            assert_eq!(m.memory.read_u32(counter as u32), Ok(1200), "quantum {}", quantum);
+        }
+        for _ in 0..5 {
+            let mut m = machine(&words, 4);
+            m.run_threads().unwrap();
+            assert_eq!(m.memory.read_u32(counter as u32), Ok(1200));
+        }
+    }
+
+    #[test]
+    fn broken_reservation() {
+        use crate::vm::instruction::mem::*;
+        let words = [
+            store_imm(0, 0, 0, DATA), arith_imm(9, 0, 0, DATA), arith_imm(10, 0, 0, 7),
+            load_reg(11, 9, 0, 0, LR), store_imm(9, 10, 2, 3), load_reg(12, 9, 0, 10, SC),
+            load_reg(11, 9, 0, 0, LR), load_reg(14, 9, 0, 10, SC),
+            load_reg(13, 10, 0, 0, SWAP),
+        ];
+        let mut m = machine(&words, 1);
+        assert_eq!(m.run(100), Err(HartError { hart: 0, error: VMError::Mem(MemoryError::Unaligned) }));
+        let r = |m: &Machine<MainMemory>, n| m.harts[0].registers.read(super::super::RS::new(n).unwrap());
+        // the byte store broke the first reservation but not the second
+        assert_eq!((r(&m, 11), r(&m, 12), r(&m, 14)), (0x0700_0000, 1, 0));
+        assert_eq!(m.memory.read_u32(DATA as u32), Ok(7));
+    }
+
+    #[test]
+    fn faults() {
+        let words = [arith_imm(3, 0, 0, 12), io_imm(0, 3, IO_HART_START, 1), io_imm(0, 0, IO_HART_STOP, 0), lw(4, 0, 0x800)];
+        let err = Err(HartError { hart: 1, error: VMError::Mem(MemoryError::Uninit) });
+        assert_eq!(machine(&words, 2).run(10), err);
+        assert_eq!(machine(&words, 2).run_threads(), err);
+
+        let err = Err(HartError { hart: 0, error: VMError::Io(IoError::InvalidParams) });
+        assert_eq!(machine(&words, 1).run(10), err);
+        assert_eq!(machine(&words, 1).run_threads(), err);
+    }
+
+    #[test]
+    fn lone_vm() {
+        let mut mem = program(&[io_imm(3, 0, IO_HART_ID, 0), io_imm(0, 0, IO_HART_STOP, 0), io_imm(0, 0, IO_HART_START, 1)]);
+        let mut io = IoHandler::new();
+        let mut vm = VM::new();
+        assert_eq!(vm.cycle(&mut io, &mut mem), Ok(false));
+        assert_eq!(vm.cycle(&mut io, &mut mem), Ok(true));
+        assert_eq!(vm.cycle(&mut io, &mut mem), Err(VMError::Io(IoError::InvalidParams)));
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use super::instruction::{Instruction, Opcode};
+use super::registers::RegisterSelector as RS;
+use super::trace::WindowEvent;
+use super::ILEN;
+
+/// guesses whether skips are taken, learning from what they actually did
+pub trait Predictor: Send {
+    fn predict(&self, pc: u32) -> bool;
+    fn update(&mut self, pc: u32, taken: bool);
+}
+
+/// never taken, so the next instruction is always the one fetched
+pub struct Static;
+impl Predictor for Static {
+    fn predict(&self, _pc: u32) -> bool {
+        false
+    }
+    fn update(&mut self, _pc: u32, _taken: bool) {}
+}
+
+/// a 2 bit saturating counter for each skip, going by the low bits of its pc
+pub struct Bimodal {
+    counters: Vec<u8>,
+}
+impl Bimodal {
+    pub fn new(bits: u32) -> Self {
+        Self { counters: vec![1; 1 << bits] }
+    }
+    fn index(&self, pc: u32) -> usize {
+        (pc / ILEN) as usize & (self.counters.len() - 1)
+    }
+}
+impl Predictor for Bimodal {
+    fn predict(&self, pc: u32) -> bool {
+        self.counters[self.index(pc)] >= 2
+    }
+    fn update(&mut self, pc: u32, taken: bool) {
+        let i = self.index(pc);
+        train(&mut self.counters[i], taken)
+    }
+}
+
+/// like bimodal, but the counter also depends on which way the last few skips went
+pub struct Gshare {
+    counters: Vec<u8>,
+    /// newest in the low bit
+    history: u32,
+}
+impl Gshare {
+    pub fn new(bits: u32) -> Self {
+        Self { counters: vec![1; 1 << bits], history: 0 }
+    }
+    fn index(&self, pc: u32) -> usize {
+        ((pc / ILEN) ^ self.history) as usize & (self.counters.len() - 1)
+    }
+}
+impl Predictor for Gshare {
+    fn predict(&self, pc: u32) -> bool {
+        self.counters[self.index(pc)] >= 2
+    }
+    fn update(&mut self, pc: u32, taken: bool) {
+        let i = self.index(pc);
+        train(&mut self.counters[i], taken);
+        self.history = self.history << 1 | taken as u32
+    }
+}
+
+fn train(c: &mut u8, taken: bool) {
+    *c = if taken { (*c + 1).min(3) } else { c.saturating_sub(1) }
+}
+
+/// static, bimodal or gshare, with an optional :bits for the log2 of the table size
+pub fn parse(spec: &str) -> Result<Box<dyn Predictor>, String> {
+    let bad = || format!("{}: expected static, bimodal[:bits] or gshare[:bits]", spec);
+    let (name, bits) = match spec.split_once(':') {
+        Some((n, b)) => (n, b.parse::<u32>().ok().filter(|b| (1..=24).contains(b)).ok_or_else(bad)?),
+        None => (spec, 12)
+    };
+    Ok(match name {
+        "static" if !spec.contains(':') => Box::new(Static),
+        "bimodal" => Box::new(Bimodal::new(bits)),
+        "gshare" => Box::new(Gshare::new(bits)),
+        _ => return Err(bad())
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Kind {
+    Skip,
+    Return,
+}
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Accuracy {
+    pub predictions: u64,
+    pub correct: u64,
+}
+impl Accuracy {
+    fn add(&mut self, correct: bool) {
+        self.predictions += 1;
+        self.correct += correct as u64
+    }
+    fn rate(&self) -> f64 {
+        if self.predictions == 0 { 0.0 } else { self.correct as f64 * 100.0 / self.predictions as f64 }
+    }
+}
+
+/// predicts every skip with a `Predictor`, and every return with a return address stack
+///
+/// skips that write the pc are jumps rather than branches, and aren't counted.
+/// reverse execution doesn't take the counts back with it
+pub struct BranchSim {
+    predictor: Box<dyn Predictor>,
+    /// where each call returns to, with the oldest dropped once it's full
+    stack: VecDeque<u32>,
+    by_pc: BTreeMap<(Kind, u32), Accuracy>,
+}
+impl BranchSim {
+    /// calls the return address stack holds
+    const STACK: usize = 16;
+
+    pub fn new(predictor: Box<dyn Predictor>) -> Self {
+        Self { predictor, stack: VecDeque::new(), by_pc: BTreeMap::new() }
+    }
+
+    /// `next` is where `i` at `pc` went on to
+    pub fn observe(&mut self, pc: u32, i: &Instruction, window: Option<WindowEvent>, next: u32) {
+        if i.opcode == Opcode::ArithSkip && i.rd != RS::PC {
+            let taken = next != pc.wrapping_add(ILEN);
+            let correct = self.predictor.predict(pc) == taken;
+            self.predictor.update(pc, taken);
+            self.by_pc.entry((Kind::Skip, pc)).or_default().add(correct)
+        }
+        match window {
+            Some(WindowEvent::Call) => {
+                if self.stack.len() == Self::STACK {
+                    self.stack.pop_front();
+                }
+                // returns land just past what they return to
+                self.stack.push_back(pc.wrapping_add(ILEN))
+            }
+            Some(WindowEvent::Return) => {
+                let correct = self.stack.pop_back() == Some(next);
+                self.by_pc.entry((Kind::Return, pc)).or_default().add(correct)
+            }
+            None => ()
+        }
+    }
+
+    pub fn totals(&self, kind: Kind) -> Accuracy {
+        self.by_pc.range((kind, 0)..=(kind, u32::MAX)).fold(Accuracy::default(), |a, (_, b)| Accuracy {
+            predictions: a.predictions + b.predictions,
+            correct: a.correct + b.correct,
+        })
+    }
+
+    /// totals for skips and returns, then the `top` pcs with the most mispredictions
+    pub fn report(&self, top: usize) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        for (name, kind) in [("skips", Kind::Skip), ("returns", Kind::Return)] {
+            let a = self.totals(kind);
+            writeln!(out, "{:<8} {:>12} predicted {:>12} correct {:>7.2}%", name, a.predictions, a.correct, a.rate()).unwrap();
+        }
+        let mut pcs: Vec<_> = self.by_pc.iter().filter(|(_, a)| a.correct < a.predictions).collect();
+        pcs.sort_by_key(|((_, pc), a)| (std::cmp::Reverse(a.predictions - a.correct), *pc));
+        if !pcs.is_empty() {
+            writeln!(out, "mispredictions by pc:").unwrap();
+        }
+        for ((kind, pc), a) in pcs.into_iter().take(top) {
+            let name = match kind { Kind::Skip => "skip", Kind::Return => "return" };
+            writeln!(out, "  {:#010x}  {:<6}  {}/{} wrong  {:.2}%", pc, name, a.predictions - a.correct, a.predictions, a.rate()).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::IoHandler;
+    use crate::vm::VM;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::program;
+
+    fn accuracy(b: &BranchSim, kind: Kind, pc: u32) -> Accuracy {
+        b.by_pc.get(&(kind, pc)).copied().unwrap_or_default()
+    }
+    fn wrong(p: &mut dyn Predictor, pattern: &[bool], times: usize) -> usize {
+        let mut wrong = 0;
+        for _ in 0..times {
+            for &taken in pattern {
+                wrong += (p.predict(0x40) != taken) as usize;
+                p.update(0x40, taken)
+            }
+        }
+        wrong
+    }
+
+    #[test]
+    fn predictors() {
+        // a loop branch, taken every time but the last
+        let mut pattern = vec![true; 9];
+        pattern.push(false);
+        assert_eq!(wrong(&mut Static, &pattern, 10), 90);
+        assert_eq!(wrong(&mut Bimodal::new(4), &pattern, 10), 1 + 10);
+        // alternating, which only history can follow
+        assert_eq!(wrong(&mut Bimodal::new(4), &[true, false], 50), 100);
+        assert!(wrong(&mut Gshare::new(4), &[true, false], 50) < 5);
+
+        assert!(parse("gshare:10").is_ok());
+        for bad in ["static:4", "bimodal:0", "bimodal:x", "perceptron"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn vm() {
+        let f = 12;
+        let words = [
+            arith_imm(8, 0, 0, 20), call(16, f - 8), arith_imm(9, 0, 0, 0),
+            // f: r24 += f(r24 - 1), unless r24 is 0
+            skip_imm(0, 24, 22, 0), arith_imm(2, 2, 0, 4), ret(16),
+            arith_imm(8, 24, 0, -1), call(16, -20), arith_reg(24, 24, 8, 0), ret(16),
+        ];
+        let (mut mem, mut io) = (program(&words), IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_branch_sim(Box::new(Bimodal::new(8)));
+        for _ in 0..2 + 20 * 6 + 2 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        let b = vm.branch_sim().unwrap();
+        // the skip is only taken at the bottom
+        assert_eq!(accuracy(b, Kind::Skip, 12), Accuracy { predictions: 21, correct: 20 });
+        // 21 calls deep, so the stack has forgotten the first 5 by the time they return
+        assert_eq!(accuracy(b, Kind::Return, 20), Accuracy { predictions: 1, correct: 1 });
+        assert_eq!(accuracy(b, Kind::Return, 36), Accuracy { predictions: 20, correct: 15 });
+        assert!(b.report(10).contains("mispredictions by pc:\n  0x00000024  return  5/20 wrong  75.00%\n  0x0000000c  skip    1/21 wrong  95.24%\n"));
+    }
+}
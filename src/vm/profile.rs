@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use super::trace::WindowEvent;
+
+/// counts what runs at each pc, and which function it ran in
+///
+/// a function is wherever a call went, and runs until the matching return. tail calls stay part of the
+/// function they came from, and whatever runs before the first call counts as a function starting at the first pc.
+/// a recursive function's inclusive count covers its outermost call only, so nothing is counted twice
+pub struct Profile {
+    instructions: u64,
+    by_pc: BTreeMap<u32, u64>,
+    functions: BTreeMap<u32, Function>,
+    /// innermost last
+    stack: Vec<Frame>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Function {
+    /// instructions run in it, not counting what it called
+    pub own: u64,
+    /// instructions run in it and everything it called, for calls that have returned
+    pub inclusive: u64,
+    pub calls: u64,
+    /// calls of it still running
+    active: u32,
+}
+struct Frame {
+    entry: u32,
+    /// instructions run before the call
+    start: u64,
+    /// false for a recursive call, which its outermost call already covers
+    outermost: bool,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self { instructions: 0, by_pc: BTreeMap::new(), functions: BTreeMap::new(), stack: Vec::new() }
+    }
+
+    /// counts the instruction at `pc`, which went on to `next`
+    pub fn observe(&mut self, pc: u32, window: Option<WindowEvent>, next: u32) {
+        if self.stack.is_empty() {
+            self.enter(pc)
+        }
+        self.instructions += 1;
+        *self.by_pc.entry(pc).or_default() += 1;
+        let top = self.stack.last().unwrap().entry;
+        self.functions.get_mut(&top).unwrap().own += 1;
+
+        match window {
+            Some(WindowEvent::Call) => self.enter(next),
+            // a return off the bottom leaves everything after it in the first function
+            Some(WindowEvent::Return) if self.stack.len() > 1 => {
+                let f = self.stack.pop().unwrap();
+                let func = self.functions.get_mut(&f.entry).unwrap();
+                func.active -= 1;
+                if f.outermost {
+                    func.inclusive += self.instructions - f.start
+                }
+            }
+            _ => ()
+        }
+    }
+    fn enter(&mut self, entry: u32) {
+        let f = self.functions.entry(entry).or_default();
+        f.calls += 1;
+        f.active += 1;
+        self.stack.push(Frame { entry, start: self.instructions, outermost: f.active == 1 })
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    /// by entry point, with the calls that are still running counted up to now
+    pub fn functions(&self) -> BTreeMap<u32, Function> {
+        let mut functions = self.functions.clone();
+        for f in self.stack.iter().filter(|f| f.outermost) {
+            functions.get_mut(&f.entry).unwrap().inclusive += self.instructions - f.start
+        }
+        functions
+    }
+
+    /// functions by own and inclusive counts, then pcs, `top` of each
+    pub fn report(&self, top: usize, symbols: &Symbols) -> String {
+        use std::fmt::Write;
+        let total = self.instructions();
+        let percent = |n: u64| n as f64 * 100.0 / total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "{} instructions", total).unwrap();
+
+        let functions = self.functions();
+        let mut flat: Vec<_> = functions.iter().collect();
+        for (title, inclusive) in [("flat", false), ("inclusive", true)] {
+            flat.sort_by_key(|(entry, f)| (std::cmp::Reverse(if inclusive { f.inclusive } else { f.own }), **entry));
+            writeln!(out, "{} profile:\n  {:>12}        {:>12}        {:>8}  function", title, "own", "inclusive", "calls").unwrap();
+            for (entry, f) in flat.iter().take(top) {
+                writeln!(out, "  {:>12} {:>5.1}% {:>12} {:>5.1}% {:>8}  {}",
+                    f.own, percent(f.own), f.inclusive, percent(f.inclusive), f.calls, symbols.describe(**entry)).unwrap();
+            }
+        }
+
+        let mut pcs: Vec<_> = self.by_pc.iter().collect();
+        pcs.sort_by_key(|(pc, n)| (std::cmp::Reverse(**n), **pc));
+        writeln!(out, "hottest pcs:").unwrap();
+        for (pc, n) in pcs.into_iter().take(top) {
+            writeln!(out, "  {:>12} {:>5.1}%  {}", n, percent(*n), symbols.describe(*pc)).unwrap();
+        }
+        out
+    }
+}
+
+/// names for addresses, from lines of a hex address then a name, with an optional type letter between like nm prints
+#[derive(Default)]
+pub struct Symbols(BTreeMap<u32, String>);
+impl Symbols {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = BTreeMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (addr, name) = match fields[..] {
+                [] => continue,
+                [addr, name] | [addr, _, name] => (addr, name),
+                _ => return Err(format!("line {}: expected an address and a name", n + 1))
+            };
+            let addr = u32::from_str_radix(addr.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("line {}: {}: {}", n + 1, addr, e))?;
+            symbols.insert(addr, name.to_string());
+        }
+        Ok(Self(symbols))
+    }
+
+    /// the address, then the symbol it's in and how far into it, if there is one
+    pub fn describe(&self, addr: u32) -> String {
+        match self.0.range(..=addr).next_back() {
+            Some((&at, name)) if at == addr => format!("{:#010x}  {}", addr, name),
+            Some((&at, name)) => format!("{:#010x}  {}+{:#x}", addr, name, addr - at),
+            None => format!("{:#010x}", addr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::IoHandler;
+    use crate::vm::VM;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::program;
+
+    #[test]
+    fn recursion() {
+        let f = 12;
+        let words = [
+            arith_imm(8, 0, 0, 3), call(16, f - 8), arith_imm(9, 0, 0, 0),
+            // f: r24 += f(r24 - 1), unless r24 is 0
+            skip_imm(0, 24, 22, 0), arith_imm(2, 2, 0, 4), ret(16),
+            arith_imm(8, 24, 0, -1), call(16, -20), arith_reg(24, 24, 8, 0), ret(16),
+        ];
+        let (mut mem, mut io) = (program(&words), IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_profile();
+        for _ in 0..2 + 3 * 6 + 2 + 1 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        let p = vm.profile().unwrap();
+        assert_eq!(p.instructions(), 23);
+        let functions = p.functions();
+        let (main, f) = (functions[&0], functions[&12]);
+        assert_eq!((main.own, main.inclusive, main.calls), (3, 23, 1));
+        // four calls deep, but only the outermost counts towards inclusive
+        assert_eq!((f.own, f.inclusive, f.calls), (20, 20, 4));
+        assert_eq!(p.by_pc[&12], 4);
+
+        let symbols = Symbols::parse("00000000 T main\n\n0xc f\n").unwrap();
+        let report = p.report(10, &symbols);
+        assert!(report.contains("flat profile:\n"), "{}", report);
+        assert!(report.contains("\n            20  87.0%           20  87.0%        4  0x0000000c  f\n"), "{}", report);
+        assert!(report.contains("hottest pcs:\n             4  17.4%  0x0000000c  f\n             3  13.0%  0x00000010  f+0x4\n"), "{}", report);
+    }
+
+    #[test]
+    fn symbols() {
+        let s = Symbols::parse("10 T start\n0x40 loop\n").unwrap();
+        assert_eq!(s.describe(0x8), "0x00000008");
+        assert_eq!(s.describe(0x44), "0x00000044  loop+0x4");
+        assert!(Symbols::parse("10\n").is_err());
+        assert!(Symbols::parse("zz start\n").is_err());
+    }
+}
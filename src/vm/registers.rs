@@ -1,16 +1,22 @@
+use std::io::{Read, Write};
 use crate::utils::*;
+use super::snapshot::*;
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Registers {
     globals: [u32; 8],
     // top local is shared with callee (r8..=15)
     // second from top local is true locals and shared with caller (r16..=23, r24..=31)
     locals: Vec<LocalSet>,
+    /// float flags and rounding mode, see `instruction::float`
+    pub fcsr: u32,
 }
 impl Registers {
     pub fn new() -> Self {
         let mut ret = Self {
             globals: [0; 8],
-            locals: Vec::new()
+            locals: Vec::new(),
+            fcsr: 0,
         };
         ret.call(); ret.call();
         ret
@@ -19,8 +25,12 @@ impl Registers {
     pub fn call(&mut self) {
         self.locals.push(LocalSet::new())
     }
-    pub fn ret(&mut self) {
-        self.locals.pop();
+    /// hands back the window that was dropped, so it can be put back with `unret`
+    pub fn ret(&mut self) -> Option<LocalSet> {
+        self.locals.pop()
+    }
+    pub fn unret(&mut self, set: LocalSet) {
+        self.locals.push(set)
     }
 
     pub fn read(&self, rs: RegisterSelector) -> u32 {
@@ -67,6 +77,64 @@ impl Registers {
     }
 }
 
+#[cfg(feature = "jit")]
+impl Registers {
+    /// where each register in the current window lives, for compiled code
+    ///
+    /// only valid until the next call, return or other change to the registers. r0 must never be read through
+    pub fn pointers(&mut self) -> [*mut u32; 32] {
+        let llen = self.locals.len();
+        let (lower, top) = self.locals.split_at_mut(llen - 1);
+        let (top, second) = (&mut top[0], &mut lower[llen - 2]);
+
+        let mut p = [std::ptr::null_mut(); 32];
+        for r in 0..8 {
+            p[r] = &mut self.globals[r] as *mut u32;
+            p[8 + r] = &mut top.shared[r] as *mut u32;
+            p[16 + r] = &mut second.local[r] as *mut u32;
+            p[24 + r] = &mut second.shared[r] as *mut u32;
+        }
+        p
+    }
+}
+
+impl Persist for Registers {
+    fn save<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for g in self.globals {
+            put_u32(w, g)?
+        }
+        put_u32(w, self.fcsr)?;
+        put_u32(w, self.locals.len() as u32)?;
+        for set in &self.locals {
+            for v in set.shared.iter().chain(set.local.iter()) {
+                put_u32(w, *v)?
+            }
+        }
+        Ok(())
+    }
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let mut globals = [0; 8];
+        for g in globals.iter_mut() {
+            *g = get_u32(r)?
+        }
+        let fcsr = get_u32(r)?;
+        let depth = get_u32(r)?;
+        if depth < 2 {
+            // the bottom two windows are always there, see `new`
+            return Err(SnapshotError::Corrupt("register window stack too shallow"))
+        }
+        let mut locals = Vec::new();
+        for _ in 0..depth {
+            let mut set = LocalSet::new();
+            for v in set.shared.iter_mut().chain(set.local.iter_mut()) {
+                *v = get_u32(r)?
+            }
+            locals.push(set)
+        }
+        Ok(Self { globals, locals, fcsr })
+    }
+}
+
 #[test]
 fn santiy() {
     let mut r = Registers::new();
@@ -80,7 +148,8 @@ fn santiy() {
     assert_eq!(r.read(RS(15)), 456);
 }
 
-struct LocalSet {
+#[derive(Clone, PartialEq, Debug)]
+pub struct LocalSet {
     shared: [u32; 8],
     local: [u32; 8]
 }
@@ -91,7 +160,7 @@ impl LocalSet {
 }
 
 /// enforces an invariant
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct RegisterSelector(u8);
 impl RegisterSelector {
     pub fn new(r: u8) -> Option<Self> {
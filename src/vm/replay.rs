@@ -0,0 +1,232 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::io::{IoError, IoResult, IO_MMAP, IO_MMAP_COW};
+use crate::memory::{FileRegion, Memory};
+use super::instruction::InsData;
+
+/// one value handed from the io handler back to the guest, plus enough context to spot divergence
+#[derive(Debug, PartialEq, Clone)]
+pub struct IoEvent {
+    /// instructions executed before this one
+    pub cycle: u64,
+    pub pc: u32,
+    pub funct: u32,
+    pub args: [u32; 3],
+    pub result: IoResult<u32>,
+    /// what a successful mmap put in front of the guest, so a replay can map it without the file
+    pub mapped: Option<Vec<u8>>,
+}
+impl IoEvent {
+    pub fn new(cycle: u64, pc: u32, funct: u32, d: InsData, result: IoResult<u32>) -> Self {
+        Self {
+            cycle, pc, funct,
+            args: [d.s1, d.s2, d.s3],
+            result,
+            mapped: None
+        }
+    }
+
+    /// successful mmaps are followed by the mapped bytes in the log
+    fn maps(&self) -> bool {
+        matches!(self.funct, IO_MMAP | IO_MMAP_COW) && self.result.is_ok()
+    }
+
+    /// true if `other` is the same request, regardless of what it returned
+    fn same_request(&self, other: &Self) -> bool {
+        (self.cycle, self.pc, self.funct, self.args) == (other.cycle, other.pc, other.funct, other.args)
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.cycle.to_le_bytes())?;
+        let (code, value) = match self.result {
+            Ok(v) => (0, v),
+            Err(e) => (e as u32, 0)
+        };
+        for v in [self.pc, self.funct, self.args[0], self.args[1], self.args[2], code, value] {
+            w.write_all(&v.to_le_bytes())?
+        }
+        if self.maps() {
+            let mapped = self.mapped.as_deref().unwrap_or_default();
+            w.write_all(&(mapped.len() as u32).to_le_bytes())?;
+            w.write_all(mapped)?
+        }
+        Ok(())
+    }
+    /// returns None at a clean end of stream
+    fn read<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        let mut b = [0; 36];
+        let n = r.read(&mut b)?;
+        if n == 0 {
+            return Ok(None)
+        }
+        r.read_exact(&mut b[n..])?;
+
+        let word = |i: usize| u32::from_le_bytes(b[8 + i * 4..12 + i * 4].try_into().unwrap());
+        let result = match word(5) {
+            0 => Ok(word(6)),
+            c => Err(IoError::from_code(c).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad io error code"))?)
+        };
+        let mut e = Self {
+            cycle: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+            pc: word(0), funct: word(1),
+            args: [word(2), word(3), word(4)],
+            result,
+            mapped: None
+        };
+        if e.maps() {
+            let mut len = [0; 4];
+            r.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len) as u64;
+            let mut mapped = Vec::new();
+            if r.take(len).read_to_end(&mut mapped)? as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+            e.mapped = Some(mapped)
+        }
+        Ok(Some(e))
+    }
+}
+impl fmt::Display for IoEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cycle {} pc {:08x} io {} ({:08x}, {:08x}, {:08x}) -> {:?}",
+            self.cycle, self.pc, self.funct, self.args[0], self.args[1], self.args[2], self.result)
+    }
+}
+
+/// the first point where a replayed run stopped matching its recording
+#[derive(Debug, PartialEq, Clone)]
+pub struct Divergence {
+    /// index of the io event in the log
+    pub index: u64,
+    /// None if the log ran out
+    pub expected: Option<IoEvent>,
+    /// None if the program finished with events left over
+    pub actual: Option<IoEvent>,
+}
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "replay diverged at io event {}: ", self.index)?;
+        match (&self.expected, &self.actual) {
+            (Some(e), Some(a)) => write!(f, "expected {}, got {}", e, a),
+            (None, Some(a)) => write!(f, "log ran out, got {}", a),
+            (Some(e), None) => write!(f, "program finished, expected {}", e),
+            (None, None) => write!(f, "nothing happened"),
+        }
+    }
+}
+
+pub enum IoLog {
+    Record(Recorder),
+    Replay(Replayer),
+}
+
+const MAGIC: [u8; 4] = *b"RVIO";
+const VERSION: u32 = 2;
+
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+}
+impl Recorder {
+    pub fn new(mut out: Box<dyn Write + Send>) -> io::Result<Self> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { out })
+    }
+    pub fn record(&mut self, e: &IoEvent) -> io::Result<()> {
+        e.write(&mut self.out)
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// serves recorded values back without going near the host
+pub struct Replayer {
+    events: std::vec::IntoIter<IoEvent>,
+    index: u64,
+}
+impl Replayer {
+    pub fn new<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        if header[0..4] != MAGIC || header[4..8] != VERSION.to_le_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an io log"))
+        }
+        let mut events = Vec::new();
+        while let Some(e) = IoEvent::read(&mut r)? {
+            events.push(e)
+        }
+        Ok(Self::from_events(events))
+    }
+    pub fn from_events(events: Vec<IoEvent>) -> Self {
+        Self {
+            events: events.into_iter(),
+            index: 0,
+        }
+    }
+
+    /// `actual.result` is ignored, the recorded result is returned instead.
+    /// mmaps put the recorded bytes back in `memory`, where the recording had them
+    pub fn replay<M: Memory>(&mut self, actual: IoEvent, memory: &mut M) -> Result<IoResult<u32>, Box<Divergence>> {
+        let index = self.index;
+        self.index += 1;
+        match self.events.next() {
+            Some(e) if e.same_request(&actual) => {
+                if let (Ok(at), Some(bytes)) = (e.result, &e.mapped) {
+                    let got = memory.map(FileRegion::owned(bytes.clone(), e.funct == IO_MMAP_COW));
+                    if got != Some(at) {
+                        let actual = IoEvent { result: got.ok_or(IoError::Other), ..actual };
+                        return Err(Box::new(Divergence { index, expected: Some(e), actual: Some(actual) }))
+                    }
+                }
+                Ok(e.result)
+            }
+            expected => Err(Box::new(Divergence { index, expected, actual: Some(actual) }))
+        }
+    }
+
+    /// call once the program is done to catch a run that stopped short of the recording
+    pub fn finish(&mut self) -> Result<(), Box<Divergence>> {
+        match self.events.next() {
+            None => Ok(()),
+            expected => Err(Box::new(Divergence { index: self.index, expected, actual: None }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MainMemory;
+
+    fn event(cycle: u64, result: IoResult<u32>) -> IoEvent {
+        IoEvent::new(cycle, cycle as u32 * 4, 64, InsData::new(1, 2, 3), result)
+    }
+
+    #[test]
+    fn log_round_trip() {
+        let events = vec![event(0, Ok(5)), event(3, Err(IoError::BadFd)), event(9, Ok(0))];
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        for e in &events {
+            e.write(&mut buf).unwrap()
+        }
+
+        let mut r = Replayer::new(buf.as_slice()).unwrap();
+        let mut mem = MainMemory::new(vec![0; 8]).unwrap();
+        assert_eq!(r.replay(event(0, Ok(0)), &mut mem), Ok(Ok(5)));
+        assert_eq!(r.replay(event(3, Ok(0)), &mut mem), Ok(Err(IoError::BadFd)));
+        assert_eq!(r.finish(), Err(Box::new(Divergence { index: 2, expected: Some(events[2].clone()), actual: None })));
+    }
+
+    #[test]
+    fn divergence() {
+        let mut r = Replayer::from_events(vec![event(0, Ok(5))]);
+        let mut mem = MainMemory::new(vec![0; 8]).unwrap();
+        let wrong = event(1, Ok(0));
+        assert_eq!(r.replay(wrong.clone(), &mut mem), Err(Box::new(Divergence { index: 0, expected: Some(event(0, Ok(5))), actual: Some(wrong.clone()) })));
+        assert_eq!(r.replay(wrong.clone(), &mut mem), Err(Box::new(Divergence { index: 1, expected: None, actual: Some(wrong) })));
+    }
+}
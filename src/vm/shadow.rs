@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::memory::{Memory, MemoryError};
+use super::instruction::{self, Instruction, InsData, Opcode};
+use super::registers::{Registers, RegisterSelector as RS};
+use super::trace::WindowEvent;
+
+/// which bits of the registers and memory are undefined, like valgrind's memcheck
+///
+/// memory allocates a whole block on the first store to it and the rest of the block reads as zero,
+/// but here those bytes stay undefined until something is stored to them.
+/// loads of undefined bytes, and skips or jumps that depend on undefined bits, are reported rather than
+/// stopping the vm, and the undefined bits carry on into rd. copies carry them along without a report
+///
+/// io results, and anything in memory before this was turned on, count as defined
+pub struct Shadow {
+    /// a set bit is an undefined one. the windows move along with the real registers
+    regs: Registers,
+    /// the store that allocated whatever each register's undefined bits came from
+    origins: Registers,
+    pages: BTreeMap<u32, Box<Page>>,
+    reports: Vec<Report>,
+    /// so a loop doesn't report the same thing every time round
+    seen: BTreeSet<(Kind, u32)>,
+}
+
+/// undefined bits for every byte of a page, laid out like the bytes themselves
+struct Page {
+    vbits: [u8; PAGE_SIZE],
+    /// origin of each word's undefined bytes
+    origins: [u32; PAGE_SIZE / 4],
+}
+impl Page {
+    fn defined() -> Box<Self> {
+        Box::new(Self { vbits: [0; PAGE_SIZE], origins: [0; PAGE_SIZE / 4] })
+    }
+}
+/// same granularity as the sparse memories, so a fresh block is a fresh page
+const PAGE_SIZE_LOG_2: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SIZE_LOG_2;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Kind {
+    /// a load or compare touched undefined bytes
+    Read,
+    /// a skip or jump depended on undefined bits
+    Branch,
+}
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    pub kind: Kind,
+    pub pc: u32,
+    /// the first undefined byte, for reads
+    pub addr: Option<u32>,
+    /// the store that allocated the undefined bytes
+    pub origin: u32,
+}
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.kind, self.addr) {
+            (Kind::Read, Some(a)) => write!(f, "{:#010x}: read of undefined byte {:#010x}", self.pc, a)?,
+            _ => write!(f, "{:#010x}: branch on an undefined value", self.pc)?,
+        }
+        write!(f, ", allocated by the store at {:#010x}", self.origin)
+    }
+}
+
+/// what `before` worked out, for `after` to apply once the instruction has run
+pub struct Effect {
+    /// undefined bits of rd, and their origin
+    rd: (u32, u32),
+    store: Option<Store>,
+    /// shadow pages the store will need, which only go in if it happens
+    fresh: Vec<(u32, Box<Page>)>,
+}
+enum Store {
+    Value { addr: u32, width: u32, bits: u32, origin: u32 },
+    Copy { dst: u32, src: u32, len: u32 },
+    Fill { dst: u32, len: u32, bits: u8, origin: u32 },
+}
+
+impl Shadow {
+    pub fn new() -> Self {
+        Self {
+            regs: Registers::new(),
+            origins: Registers::new(),
+            pages: BTreeMap::new(),
+            reports: Vec::new(),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// in the order they happened, once per kind and pc
+    pub fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+
+    /// looks at `i` before it runs, while memory still holds what it did
+    pub fn before<M: Memory>(&mut self, pc: u32, i: &Instruction, d: InsData, memory: &M) -> Effect {
+        use Opcode::*;
+        use instruction::{mem, func};
+        let s1 = (self.regs.read(i.rs1), self.origins.read(i.rs1));
+        let s2 = if i.immediate().is_some() { (0, 0) } else { (self.regs.read(i.rs2), self.origins.read(i.rs2)) };
+        let s3 = (self.regs.read(i.rs3), self.origins.read(i.rs3));
+
+        let fresh = match mem::store_target(i.opcode, i.funct, d.s1, d.s2, d.s3) {
+            Some((addr, len)) => self.allocate(addr, len, pc, memory),
+            None => Vec::new()
+        };
+        let mut store = None;
+        let rd = match i.opcode {
+            Arith | ArithSkip => {
+                // s3 is only an operand for register forms with funct3 set
+                let s3 = if i.immediate().is_none() && i.funct >= 32 { s3 } else { (0, 0) };
+                (arith_bits(i.funct, d, s1.0, s2.0, s3.0), origin(&[s1, s2, s3]))
+            }
+            ImmUpper => (imm_upper_bits(i.funct, d, s1.0), s1.1),
+            Ld if i.funct == mem::COMPARE => {
+                // it stops at the first difference, so nothing past that is read
+                let len = (0..d.s3)
+                    .position(|k| {
+                        let (x, y) = (memory.peek_u8(d.s1.wrapping_add(k)), memory.peek_u8(d.s2.wrapping_add(k)));
+                        x.is_err() || y.is_err() || x != y
+                    })
+                    .map_or(d.s3, |k| k as u32 + 1);
+                let a = self.load(pc, d.s1, len);
+                let b = self.load(pc, d.s2, len);
+                if a.0 == 0 && b.0 == 0 { (0, 0) } else { (!0, origin(&[a, b])) }
+            }
+            Ld if mem::is_atomic(i.funct) => {
+                let old = self.load(pc, d.s1, 4);
+                let value = |bits, origin| Store::Value { addr: d.s1, width: 4, bits, origin };
+                store = match i.funct {
+                    mem::LR => None,
+                    mem::FETCH_ADD => Some(value(left(old.0 | s3.0), origin(&[old, s3]))),
+                    _ => Some(value(s3.0, s3.1)),
+                };
+                if i.funct == mem::SC { (0, 0) } else { old }
+            }
+            Ld => match mem::load_width(i.funct) {
+                Some(w) => {
+                    let (bits, o) = self.load(pc, mem::load_address(d.s1, d.s2, i.funct), w);
+                    // sign extension copies the top bit, defined or not
+                    let bits = match i.funct {
+                        2 => bits as i16 as i32 as u32,
+                        4 => bits as i8 as i32 as u32,
+                        _ => bits
+                    };
+                    (bits, o)
+                }
+                None => (0, 0)
+            }
+            St => {
+                store = match i.funct {
+                    mem::COPY => Some(Store::Copy { dst: d.s1, src: d.s2, len: d.s3 }),
+                    mem::FILL => Some(Store::Fill { dst: d.s1, len: d.s3, bits: s2.0 as u8, origin: s2.1 }),
+                    f => mem::store_width(f).map(|width| Store::Value {
+                        addr: mem::store_address(d.s1, d.s2, f), width, bits: s3.0, origin: s3.1
+                    }),
+                };
+                (0, 0)
+            }
+            Func => {
+                let target = match i.funct {
+                    func::CALL_INDIRECT | func::TAIL_INDIRECT => (s1.0 | s2.0, origin(&[s1, s2])),
+                    _ => s2
+                };
+                if target.0 != 0 {
+                    self.report(Kind::Branch, pc, None, target.1)
+                }
+                (0, 0)
+            }
+            Io | Comp => (0, 0),
+        };
+        Effect { rd, store, fresh }
+    }
+
+    /// applies what `before` worked out, once `i` has run and the windows have moved. `value` is what went to rd
+    pub fn after(&mut self, pc: u32, i: &Instruction, e: Effect, value: u32, stored: bool, window: Option<WindowEvent>) {
+        match window {
+            Some(WindowEvent::Call) => {
+                self.regs.call();
+                self.origins.call();
+            }
+            Some(WindowEvent::Return) => {
+                self.regs.ret();
+                self.origins.ret();
+            }
+            None => {}
+        }
+        if stored {
+            for (page, p) in e.fresh {
+                self.pages.entry(page).or_insert(p);
+            }
+            if let Some(s) = e.store {
+                self.store(s)
+            }
+        }
+
+        let (bits, origin) = e.rd;
+        // a skip is taken on anything nonzero, so the defined bits might settle it anyway
+        if i.opcode == Opcode::ArithSkip && bits != 0 && value & !bits == 0 {
+            self.report(Kind::Branch, pc, None, origin)
+        }
+        if i.rd == RS::PC {
+            if bits != 0 {
+                self.report(Kind::Branch, pc, None, origin)
+            }
+        }
+        else {
+            self.regs.write(i.rd, bits);
+            self.origins.write(i.rd, origin);
+        }
+    }
+
+    fn report(&mut self, kind: Kind, pc: u32, addr: Option<u32>, origin: u32) {
+        if self.seen.insert((kind, pc)) {
+            self.reports.push(Report { kind, pc, addr, origin })
+        }
+    }
+
+    /// (undefined bits, origin) of a byte
+    fn byte(&self, addr: u32) -> (u8, u32) {
+        let (page, byte) = (addr >> PAGE_SIZE_LOG_2, addr as usize & (PAGE_SIZE - 1));
+        match self.pages.get(&page) {
+            Some(p) => (p.vbits[byte], p.origins[byte / 4]),
+            None => (0, 0)
+        }
+    }
+    fn set_byte(&mut self, addr: u32, bits: u8, origin: u32) {
+        let (page, byte) = (addr >> PAGE_SIZE_LOG_2, addr as usize & (PAGE_SIZE - 1));
+        let p = self.pages.entry(page).or_insert_with(Page::defined);
+        p.vbits[byte] = bits;
+        if bits != 0 {
+            p.origins[byte / 4] = origin
+        }
+    }
+
+    /// undefined bits of `len` bytes at `addr`, little endian like a load, and the origin of the first undefined byte.
+    /// reports a read if there are any
+    fn load(&mut self, pc: u32, addr: u32, len: u32) -> (u32, u32) {
+        let mut bits = 0;
+        let mut first = None;
+        for k in 0..len {
+            let a = addr.wrapping_add(k);
+            let (b, o) = self.byte(a);
+            if b != 0 && first.is_none() {
+                first = Some((a, o))
+            }
+            if k < 4 {
+                bits |= (b as u32) << (8 * k)
+            }
+        }
+        match first {
+            Some((a, o)) => {
+                self.report(Kind::Read, pc, Some(a), o);
+                // a compare longer than a word still has undefined bytes past the first four
+                (if bits == 0 { !0 } else { bits }, o)
+            }
+            None => (0, 0)
+        }
+    }
+
+    fn store(&mut self, s: Store) {
+        match s {
+            Store::Value { addr, width, bits, origin } => {
+                for k in 0..width {
+                    self.set_byte(addr.wrapping_add(k), (bits >> (8 * k)) as u8, origin)
+                }
+            }
+            Store::Fill { dst, len, bits, origin } => {
+                for k in 0..len {
+                    self.set_byte(dst + k, bits, origin)
+                }
+            }
+            Store::Copy { dst, src, len } => {
+                let from: Vec<_> = (0..len).map(|k| self.byte(src + k)).collect();
+                for (k, (bits, origin)) in from.into_iter().enumerate() {
+                    self.set_byte(dst + k as u32, bits, origin)
+                }
+            }
+        }
+    }
+
+    /// shadow pages for the ends of `[addr, addr + len)` that don't have one yet, for the store at `pc` to bring in.
+    /// bytes memory doesn't have yet are about to be allocated by it, and read as zero after, so they start out undefined.
+    ///
+    /// only the bytes the store leaves alone are looked at, as it sets the rest itself.
+    /// that's at most the first and last page, however long it is
+    fn allocate<M: Memory>(&self, addr: u32, len: u32, pc: u32, memory: &M) -> Vec<(u32, Box<Page>)> {
+        let end = addr as u64 + len as u64;
+        let first = addr >> PAGE_SIZE_LOG_2;
+        let last = ((end - 1).min(u32::MAX as u64) as u32) >> PAGE_SIZE_LOG_2;
+        let mut fresh = Vec::new();
+        for page in [first, last] {
+            if self.pages.contains_key(&page) || fresh.iter().any(|(p, _)| *p == page) {
+                continue
+            }
+            let mut p = Page::defined();
+            let base = page << PAGE_SIZE_LOG_2;
+            for byte in 0..PAGE_SIZE {
+                let a = base + byte as u32;
+                if (a < addr || a as u64 >= end) && memory.peek_u8(a) == Err(MemoryError::Uninit) {
+                    p.vbits[byte] = 0xff;
+                    p.origins[byte / 4] = pc;
+                }
+            }
+            fresh.push((page, p));
+        }
+        fresh
+    }
+}
+
+/// every bit at and above the lowest undefined one, as a carry can take an undefined bit anywhere upwards
+fn left(bits: u32) -> u32 {
+    bits | bits.wrapping_neg()
+}
+/// undefined bits that come out of an arithmetic funct
+///
+/// ops that only move the bits of s1 around do the same to its undefined bits.
+/// anything without a rule of its own is all undefined if any operand has an undefined bit
+fn arith_bits(funct: u32, d: InsData, m1: u32, m2: u32, m3: u32) -> u32 {
+    use instruction::arithmetic::arithmetic;
+    let any = |m: u32| if m != 0 { !0 } else { 0 };
+    match funct {
+        0 | 2 => left(m1 | m2),
+        64 | 66 => left(m1 | m2 | any(m3)),
+        // a defined 0 settles an and, a defined 1 settles an or
+        4 => (m1 | m2) & (m1 | d.s1) & (m2 | d.s2),
+        5 => (m1 | m2) & (m1 | !d.s1) & (m2 | !d.s2),
+        6 => m1 | m2,
+        7 => m1,
+        1 | 3 | 16..=21 if m2 == 0 => arithmetic(m1, d.s2, 0, funct).unwrap_or(!0),
+        99 | 100 => arithmetic(m1, 0, 0, funct).unwrap_or(!0),
+        105 if m3 == 0 => arithmetic(m1, m2, d.s3, funct).unwrap_or(!0),
+        22..=31 => ((m1 | m2) != 0) as u32,
+        96..=98 => any(m1),
+        _ => any(m1 | m2 | m3)
+    }
+}
+/// the same for imm_upper, where s2 is always the immediate
+fn imm_upper_bits(funct: u32, d: InsData, m1: u32) -> u32 {
+    match funct {
+        0 | 1 => left(m1),
+        4 => m1 & d.s2,
+        5 => m1 & !d.s2,
+        7 => m1 & ((1 << 13) - 1),
+        _ => m1
+    }
+}
+/// the origin of the first of these with undefined bits
+fn origin(parts: &[(u32, u32)]) -> u32 {
+    parts.iter().find(|(bits, _)| *bits != 0).map_or(0, |(_, o)| *o)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::IoHandler;
+    use crate::vm::VM;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::instruction::mem::COPY;
+    use crate::vm::tests::program;
+
+    fn run(words: &[u32]) -> Vec<Report> {
+        let (mut mem, mut io) = (program(words), IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_shadow();
+        for _ in words {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        vm.shadow_reports().to_vec()
+    }
+
+    #[test]
+    fn undefined_reads() {
+        let words = [
+            // a word at 0x2000 allocates the block, the word after it is still undefined
+            imm_upper(8, 0, 0x2000), sw(8, 8), lw(9, 8, 0), lw(10, 8, 4),
+            // a byte leaves the rest of its word undefined
+            store_imm(8, 8, 2, 0x10), load_imm(11, 8, 3, 0x10), lw(12, 8, 0x10),
+        ];
+        assert_eq!(run(&words), [
+            Report { kind: Kind::Read, pc: 12, addr: Some(0x2004), origin: 4 },
+            Report { kind: Kind::Read, pc: 24, addr: Some(0x2011), origin: 4 },
+        ]);
+        // the object and io results are defined
+        assert!(run(&[lw(8, 0, 0), io_imm(9, 0, crate::vm::machine::IO_HART_ID, 0), skip_reg(0, 8, 9, 22)]).is_empty());
+    }
+
+    #[test]
+    fn compare_prefix() {
+        use crate::vm::instruction::mem::COMPARE;
+        let words = [
+            imm_upper(8, 0, 0x2000), arith_imm(9, 0, 0, 1), sw(8, 9),
+            arith_imm(10, 8, 0, 8), sw(10, 0), arith_imm(11, 0, 0, 8),
+            // the first bytes differ, so the undefined ones after them don't matter
+            load_reg(12, 8, 10, 11, COMPARE),
+            load_reg(13, 8, 8, 11, COMPARE),
+        ];
+        assert_eq!(run(&words), [Report { kind: Kind::Read, pc: 28, addr: Some(0x2004), origin: 8 }]);
+    }
+
+    #[test]
+    fn propagation() {
+        let words = [
+            imm_upper(8, 0, 0x4000), store_imm(8, 8, 2, 0), lw(9, 8, 0),
+            // masking off the undefined bytes leaves a defined value to skip on
+            arith_imm(10, 9, 4, 0xff), skip_imm(0, 10, 23, 0), arith_imm(0, 0, 0, 0),
+            // but the upper bytes decide this one
+            arith_imm(11, 9, 17, 8), skip_imm(0, 11, 23, 0), arith_imm(0, 0, 0, 0),
+            // copied undefined bytes stay undefined without a report of their own
+            arith_imm(12, 0, 0, 4), imm_upper(13, 0, 0x6000), store_reg(13, 8, 12, COPY), lw(14, 13, 0),
+            // and a register holding them can't be jumped through
+            func_reg(0, 14, 0, crate::vm::instruction::func::TAIL_INDIRECT),
+        ];
+        let (mut mem, mut io) = (program(&words), IoHandler::new());
+        let mut vm = VM::new();
+        vm.enable_shadow();
+        for _ in 0..words.len() - 2 {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        assert_eq!(vm.shadow_reports(), [
+            Report { kind: Kind::Read, pc: 8, addr: Some(0x4001), origin: 4 },
+            Report { kind: Kind::Branch, pc: 28, addr: None, origin: 4 },
+        ]);
+        vm.cycle(&mut io, &mut mem).unwrap();
+        assert_eq!(vm.shadow_reports()[2], Report { kind: Kind::Read, pc: 48, addr: Some(0x6001), origin: 4 });
+        // the jump still happens, after the report
+        assert!(vm.cycle(&mut io, &mut mem).is_ok());
+        assert_eq!(vm.shadow_reports()[3], Report { kind: Kind::Branch, pc: 52, addr: None, origin: 4 });
+        assert_eq!(vm.shadow_reports().len(), 4);
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+use super::VM;
+use crate::io::IoHandler;
+use crate::memory::MainMemory;
+
+/// everything needed to pick a program back up where it left off
+///
+/// the pc lives in the registers, so it doesn't get its own field
+pub struct Snapshot {
+    pub vm: VM,
+    pub memory: MainMemory,
+    pub io: IoHandler,
+}
+impl Snapshot {
+    const MAGIC: [u8; 4] = *b"RVSN";
+    /// bump this whenever the layout of anything implementing `Persist` changes
+    pub const VERSION: u32 = 5;
+
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&Self::MAGIC)?;
+        put_u32(w, Self::VERSION)?;
+
+        self.vm.registers.save(w)?;
+        self.memory.save(w)?;
+        self.io.save(w)
+    }
+
+    pub fn load<R: Read>(r: &mut R) -> SnapshotResult<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != Self::MAGIC {
+            return Err(SnapshotError::Magic)
+        }
+        let version = get_u32(r)?;
+        if version != Self::VERSION {
+            return Err(SnapshotError::Version(version))
+        }
+
+        let registers = Persist::load(r)?;
+        let memory = Persist::load(r)?;
+        let io = Persist::load(r)?;
+
+        let mut vm = VM::new();
+        vm.registers = registers;
+        Ok(Self { vm, memory, io })
+    }
+}
+
+/// binary (de)serialisation for snapshot components
+///
+/// all integers are little endian u32s unless they need to be u64s, byte strings are length prefixed
+pub trait Persist: Sized {
+    fn save<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn load<R: Read>(r: &mut R) -> SnapshotResult<Self>;
+}
+
+pub fn put_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+pub fn get_u32<R: Read>(r: &mut R) -> SnapshotResult<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+pub fn put_u64<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+pub fn get_u64<R: Read>(r: &mut R) -> SnapshotResult<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+pub fn put_bytes<W: Write>(w: &mut W, b: &[u8]) -> io::Result<()> {
+    put_u32(w, b.len() as u32)?;
+    w.write_all(b)
+}
+pub fn get_bytes<R: Read>(r: &mut R) -> SnapshotResult<Vec<u8>> {
+    // the length can't be trusted, so the buffer only grows as far as there's data for it
+    let len = get_u32(r)? as u64;
+    let mut b = Vec::new();
+    r.take(len).read_to_end(&mut b)?;
+    if (b.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+    Ok(b)
+}
+
+pub type SnapshotResult<T> = Result<T, SnapshotError>;
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a snapshot file")]
+    Magic,
+    #[error("unsupported snapshot version {0}")]
+    Version(u32),
+    #[error("corrupt snapshot: {0}")]
+    Corrupt(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use super::super::registers::RegisterSelector as RS;
+
+    #[test]
+    fn round_trip() {
+        let mut s = Snapshot {
+            vm: VM::new(),
+            memory: MainMemory::new(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap(),
+            io: IoHandler::new(),
+        };
+        s.vm.registers.write(RS::PC, 4);
+        s.vm.registers.write(RS::new(9).unwrap(), 99);
+        s.vm.registers.call();
+        s.vm.registers.write(RS::new(20).unwrap(), 1234);
+        s.memory.write_u32(0x10_0000, 0xdead_beef).unwrap();
+        s.memory.write_u8(0x20_0003, 7).unwrap();
+
+        let mut buf = Vec::new();
+        s.save(&mut buf).unwrap();
+        let mut l = Snapshot::load(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(l.vm.registers.read(RS::PC), 4);
+        assert_eq!(l.vm.registers.read(RS::new(20).unwrap()), 1234);
+        assert_eq!(l.vm.registers.read(RS::new(25).unwrap()), 99);
+        l.vm.registers.ret();
+        assert_eq!(l.vm.registers.read(RS::new(9).unwrap()), 99);
+
+        assert_eq!(l.memory.read_u32(4), Ok(0x0807_0605));
+        assert_eq!(l.memory.read_u32(0x10_0000), Ok(0xdead_beef));
+        assert_eq!(l.memory.read_u8(0x20_0003), Ok(7));
+        assert_eq!(l.memory.read_u8(0x30_0000), Err(crate::memory::MemoryError::Uninit));
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(matches!(Snapshot::load(&mut &b"RVSX\x01\0\0\0"[..]), Err(SnapshotError::Magic)));
+        assert!(matches!(Snapshot::load(&mut &b"RVSN\x07\0\0\0"[..]), Err(SnapshotError::Version(7))));
+        // a length with nothing like that much after it
+        let err = get_bytes(&mut &b"\xff\xff\xff\xff\x01\x02"[..]).unwrap_err();
+        assert!(matches!(err, SnapshotError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -0,0 +1,264 @@
+use super::instruction::{self, Instruction, InsData, Opcode};
+use super::registers::RegisterSelector as RS;
+use super::trace::WindowEvent;
+
+/// counts the cycles a simple in-order pipeline would take over what the vm runs
+///
+/// one instruction issues a cycle at best. it waits for its sources, loses cycles after anything that changes
+/// the pc, and holds the pipeline while bulk memory ops run or a register window is spilled or filled.
+/// memory is taken to always hit, and reverse execution doesn't take the counts back with it
+pub struct Timing {
+    config: Config,
+    /// cycle the last instruction issued in
+    now: u64,
+    instructions: u64,
+    stalls: Stalls,
+    /// when each register in the current window can next be read, and what it's waiting on
+    ready: [(u64, Wait); 32],
+    /// windows held in the register file, with the rest spilled to memory
+    resident: u32,
+    /// windows spilled to memory, which returns will have to fill back
+    spilled: u64,
+}
+
+/// latencies count from issue, so 1 means the next instruction can use the result straight away
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Config {
+    pub alu: u32,
+    pub load: u32,
+    /// mull, mulh and friends
+    pub mul: u32,
+    /// div, rem and the 64 bit dividend forms
+    pub div: u32,
+    pub float: u32,
+    pub fmul: u32,
+    /// fdiv and fsqrt
+    pub fdiv: u32,
+    /// cycles lost after a taken skip
+    pub skip: u32,
+    /// cycles lost after a call, return, tail or write to the pc
+    pub jump: u32,
+    /// windows the register file holds, counting the two the bottom of the stack always has
+    pub windows: u32,
+    /// cycles to store or load one window, when calls run out of them or returns find theirs spilled
+    pub spill: u32,
+    /// bytes a copy, fill or compare gets through a cycle
+    pub bulk: u32,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self { alu: 1, load: 2, mul: 3, div: 20, float: 4, fmul: 4, fdiv: 16, skip: 2, jump: 2, windows: 8, spill: 16, bulk: 4 }
+    }
+}
+
+/// cycles lost, by what they were lost to
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Stalls {
+    /// waiting on a load
+    pub load_use: u64,
+    /// waiting on a multiply, divide or float op
+    pub latency: u64,
+    pub skip: u64,
+    pub jump: u64,
+    pub spill: u64,
+    pub fill: u64,
+    pub bulk: u64,
+}
+impl Stalls {
+    pub fn total(&self) -> u64 {
+        self.load_use + self.latency + self.skip + self.jump + self.spill + self.fill + self.bulk
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Wait {
+    Load,
+    Latency,
+}
+
+impl Timing {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            now: 0,
+            instructions: 0,
+            stalls: Stalls::default(),
+            ready: [(0, Wait::Latency); 32],
+            resident: 2,
+            spilled: 0,
+        }
+    }
+
+    /// cycles from the first issue to the last
+    pub fn cycles(&self) -> u64 {
+        self.now
+    }
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    pub fn stalls(&self) -> Stalls {
+        self.stalls
+    }
+
+    /// the cycles it takes for the result of `i` to be ready, and what anything waiting on it is waiting on
+    fn latency(&self, i: &Instruction) -> (u32, Wait) {
+        use Opcode::*;
+        use instruction::float::*;
+        let c = &self.config;
+        match (i.opcode, i.funct) {
+            (Ld, _) => (c.load, Wait::Load),
+            (Arith | ArithSkip, 8..=11) => (c.mul, Wait::Latency),
+            (Arith | ArithSkip, 12..=15 | 68..=73) => (c.div, Wait::Latency),
+            (Arith | ArithSkip, FMUL | FMADD..=FNMADD) => (c.fmul, Wait::Latency),
+            (Arith | ArithSkip, FDIV | FSQRT) => (c.fdiv, Wait::Latency),
+            (Arith | ArithSkip, f) if is_float(f) && !matches!(f, FRCSR | FSCSR) => (c.float, Wait::Latency),
+            _ => (c.alu, Wait::Latency)
+        }
+    }
+
+    /// accounts for `i` once it has run. `redirected` is whether it went anywhere but the next instruction
+    pub fn retire(&mut self, i: &Instruction, d: InsData, redirected: bool, window: Option<WindowEvent>) {
+        use Opcode::*;
+        use instruction::mem;
+        let c = self.config;
+
+        // s2 is an immediate or the register, s3 an operand only where something reads it
+        let uses_s3 = match i.opcode {
+            St | Io => true,
+            Ld => mem::is_atomic(i.funct) || i.funct == mem::COMPARE,
+            Arith | ArithSkip => i.immediate().is_none() && (32..256).contains(&i.funct),
+            _ => false
+        };
+        let sources = [Some(i.rs1), i.immediate().is_none().then_some(i.rs2), uses_s3.then_some(i.rs3)];
+        let (ready, wait) = sources.into_iter().flatten()
+            .filter(|r| !matches!(*r, RS::ZERO | RS::PC))
+            .map(|r| self.ready[r.inner() as usize])
+            .max_by_key(|r| r.0)
+            .unwrap_or((0, Wait::Latency));
+
+        let issue = (self.now + 1).max(ready);
+        match wait {
+            Wait::Load => self.stalls.load_use += issue - self.now - 1,
+            Wait::Latency => self.stalls.latency += issue - self.now - 1,
+        }
+        self.now = issue;
+        self.instructions += 1;
+
+        // anything that reads or writes a run of bytes holds the pipeline while it does
+        let bulk = match (i.opcode, i.funct) {
+            (St, mem::COPY | mem::FILL) | (Ld, mem::COMPARE) => (d.s3 as u64).div_ceil(c.bulk.max(1) as u64),
+            _ => 0
+        };
+        self.stalls.bulk += bulk;
+        self.now += bulk;
+
+        match window {
+            Some(WindowEvent::Call) => {
+                if self.resident == c.windows.max(2) {
+                    self.spilled += 1;
+                    self.stalls.spill += c.spill as u64;
+                    self.now += c.spill as u64
+                }
+                else { self.resident += 1 }
+                // the caller's r8..r15 are the callee's r24..r31, and the rest are new
+                self.ready.copy_within(8..16, 24);
+                self.ready[8..24].fill((0, Wait::Latency));
+            }
+            Some(WindowEvent::Return) => {
+                self.resident -= 1;
+                if self.resident < 2 && self.spilled > 0 {
+                    self.spilled -= 1;
+                    self.resident += 1;
+                    self.stalls.fill += c.spill as u64;
+                    self.now += c.spill as u64
+                }
+                else if self.resident < 2 {
+                    // returning off the bottom of the stack, which the registers don't mind either
+                    self.resident = 2
+                }
+                self.ready.copy_within(24..32, 8);
+                self.ready[16..].fill((0, Wait::Latency));
+            }
+            None => ()
+        }
+
+        if i.rd != RS::ZERO && i.rd != RS::PC {
+            let (latency, wait) = self.latency(i);
+            self.ready[i.rd.inner() as usize] = (self.now + latency as u64, wait);
+        }
+
+        if redirected {
+            let (lost, stall) = if i.opcode == ArithSkip && i.rd != RS::PC {
+                (c.skip, &mut self.stalls.skip)
+            }
+            else { (c.jump, &mut self.stalls.jump) };
+            *stall += lost as u64;
+            self.now += lost as u64
+        }
+    }
+
+    /// total cycles and where the stalls went
+    pub fn report(&self) -> String {
+        let (cycles, instructions, s) = (self.cycles(), self.instructions(), self.stalls());
+        let cpi = if instructions == 0 { 0.0 } else { cycles as f64 / instructions as f64 };
+        format!(
+            "{} cycles for {} instructions ({:.2} cpi)\n{} stall cycles: load-use {}, latency {}, skip {}, jump {}, spill {}, fill {}, bulk {}\n",
+            cycles, instructions, cpi, s.total(), s.load_use, s.latency, s.skip, s.jump, s.spill, s.fill, s.bulk
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::IoHandler;
+    use crate::memory::Memory;
+    use crate::vm::VM;
+    use crate::vm::instruction::encode::*;
+    use crate::vm::tests::program;
+
+    fn run(words: &[u32], cycles: usize, config: Config) -> Timing {
+        let (mut mem, mut io) = (program(words), IoHandler::new());
+        mem.fill(0x4000, 0, 0x100).unwrap();
+        let mut vm = VM::new();
+        vm.enable_timing(config);
+        for _ in 0..cycles {
+            vm.cycle(&mut io, &mut mem).unwrap();
+        }
+        vm.timing.take().unwrap()
+    }
+
+    #[test]
+    fn hazards() {
+        let words = [
+            imm_upper(8, 0, 0x4000),
+            // load-use: one cycle lost with a load latency of 2
+            lw(9, 8, 0), arith_imm(10, 9, 0, 1),
+            // a multiply with an unrelated instruction between it and its use
+            arith_reg(11, 10, 10, 8), arith_imm(12, 0, 0, 1), arith_imm(13, 11, 0, 1),
+            // taken, so the next instruction is skipped
+            skip_imm(0, 0, 22, 0), arith_imm(14, 0, 0, 1),
+            arith_reg(15, 12, 12, 12), arith_imm(16, 15, 0, 0),
+        ];
+        let t = run(&words, 9, Config::default());
+        assert_eq!(t.instructions(), 9);
+        assert_eq!(t.stalls(), Stalls { load_use: 1, latency: 1 + 19, skip: 2, ..Stalls::default() });
+        assert_eq!(t.cycles(), 9 + t.stalls().total());
+        assert!(t.report().starts_with("32 cycles for 9 instructions (3.56 cpi)\n23 stall cycles: load-use 1, latency 20, skip 2,"));
+    }
+
+    #[test]
+    fn windows() {
+        let f = 12;
+        let words = [
+            arith_imm(8, 0, 0, 5), call(16, f - 8), arith_imm(9, 0, 0, 0),
+            // f: r24 += f(r24 - 1), unless r24 is 0
+            skip_imm(0, 24, 22, 0), arith_imm(2, 2, 0, 4), ret(16),
+            arith_imm(8, 24, 0, -1), call(16, -20), arith_reg(24, 24, 8, 0), ret(16),
+        ];
+        let t = run(&words, 2 + 5 * 6 + 2, Config { windows: 4, ..Config::default() });
+        // six calls deep, with room for two of them before the rest spill, and the returns fill them back
+        assert_eq!(t.stalls(), Stalls { skip: 2, jump: 17 * 2, spill: 4 * 16, fill: 4 * 16, ..Stalls::default() });
+        assert_eq!(t.cycles(), 34 + t.stalls().total());
+    }
+}
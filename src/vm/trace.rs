@@ -0,0 +1,290 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use super::instruction::{Instruction, Opcode, mem::FILL};
+
+/// one executed instruction
+///
+/// opcode and funct aren't stored in the binary format, they get decoded again from the instruction word
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    pub pc: u32,
+    pub iword: u32,
+    pub opcode: Opcode,
+    pub funct: u32,
+
+    pub s1: u32,
+    pub s2: u32,
+    pub s3: u32,
+
+    pub rd: u8,
+    /// the value actually written to rd, after the pc adjustment
+    pub value: u32,
+
+    pub mem: Option<MemAccess>,
+    pub window: Option<WindowEvent>,
+    /// it failed and stopped the vm, so nothing it would have written was
+    pub fault: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MemAccess {
+    pub addr: u32,
+    /// bytes from `addr`, which for copies, fills and compares is however many they cover
+    pub len: u32,
+    /// the word loaded or stored, or the byte a fill stores. 0 for copies and compares, a compare's result is in rd
+    pub value: u32,
+    pub store: bool,
+    /// where a copy reads from, or the other range a compare reads
+    pub src: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WindowEvent {
+    Call,
+    Return,
+}
+
+impl TraceRecord {
+    const HAS_MEM: u8 = 1;
+    const STORE: u8 = 2;
+    const CALL: u8 = 4;
+    const RETURN: u8 = 8;
+    const FAULT: u8 = 16;
+    const HAS_SRC: u8 = 32;
+
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for v in [self.pc, self.iword, self.s1, self.s2, self.s3, self.value] {
+            w.write_all(&v.to_le_bytes())?
+        }
+        let mut flags = 0;
+        if let Some(m) = self.mem {
+            flags |= Self::HAS_MEM;
+            if m.store {
+                flags |= Self::STORE
+            }
+            if m.src.is_some() {
+                flags |= Self::HAS_SRC
+            }
+        }
+        match self.window {
+            Some(WindowEvent::Call) => flags |= Self::CALL,
+            Some(WindowEvent::Return) => flags |= Self::RETURN,
+            None => {}
+        }
+        if self.fault {
+            flags |= Self::FAULT
+        }
+        w.write_all(&[self.rd, flags])?;
+        if let Some(m) = self.mem {
+            for v in [m.addr, m.len, m.value].into_iter().chain(m.src) {
+                w.write_all(&v.to_le_bytes())?
+            }
+        }
+        Ok(())
+    }
+
+    /// returns None at a clean end of stream
+    pub fn read_binary<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        let mut fixed = [0; 26];
+        // tell a clean eof apart from a truncated record
+        let n = r.read(&mut fixed)?;
+        if n == 0 {
+            return Ok(None)
+        }
+        r.read_exact(&mut fixed[n..])?;
+
+        let word = |i: usize| u32::from_le_bytes(fixed[i * 4..i * 4 + 4].try_into().unwrap());
+        let (rd, flags) = (fixed[24], fixed[25]);
+        if rd > 31 || flags & !(Self::HAS_MEM | Self::STORE | Self::CALL | Self::RETURN | Self::FAULT | Self::HAS_SRC) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt trace record"))
+        }
+
+        let mem = if flags & Self::HAS_MEM != 0 {
+            let mut m = [0; 16];
+            let n = if flags & Self::HAS_SRC != 0 { 16 } else { 12 };
+            r.read_exact(&mut m[..n])?;
+            let word = |i: usize| u32::from_le_bytes(m[i * 4..i * 4 + 4].try_into().unwrap());
+            Some(MemAccess {
+                addr: word(0), len: word(1), value: word(2),
+                store: flags & Self::STORE != 0,
+                src: (flags & Self::HAS_SRC != 0).then(|| word(3)),
+            })
+        }
+        else { None };
+        let window = if flags & Self::CALL != 0 {
+            Some(WindowEvent::Call)
+        }
+        else if flags & Self::RETURN != 0 {
+            Some(WindowEvent::Return)
+        }
+        else { None };
+
+        let i = Instruction::from_iword(word(1));
+        Ok(Some(Self {
+            pc: word(0), iword: word(1),
+            opcode: i.opcode, funct: i.funct,
+            s1: word(2), s2: word(3), s3: word(4),
+            rd, value: word(5),
+            mem, window,
+            fault: flags & Self::FAULT != 0
+        }))
+    }
+}
+
+/// the text format, one record per line
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}: {:08x} {:<9} {:<5} s1={:08x} s2={:08x} s3={:08x} r{:<2} <- {:08x}",
+            self.pc, self.iword, format!("{:?}", self.opcode).to_lowercase(), self.funct,
+            self.s1, self.s2, self.s3, self.rd, self.value)?;
+        if let Some(m) = self.mem {
+            let (op, arrow) = if m.store { ("st", "<-") } else { ("ld", "->") };
+            let fill = self.opcode == Opcode::St && self.funct == FILL;
+            write!(f, " {} [{:08x}]", op, m.addr)?;
+            match m.src {
+                Some(src) if m.store => write!(f, " <- [{:08x}]", src)?,
+                Some(src) => write!(f, " [{:08x}]", src)?,
+                None if fill => write!(f, " <- {:02x}", m.value)?,
+                None => write!(f, " {} {:08x}", arrow, m.value)?
+            }
+            if m.src.is_some() || fill {
+                write!(f, " len={:08x}", m.len)?
+            }
+        }
+        match self.window {
+            Some(WindowEvent::Call) => write!(f, " call")?,
+            Some(WindowEvent::Return) => write!(f, " ret")?,
+            None => {}
+        }
+        if self.fault {
+            write!(f, " fault")?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceFormat {
+    Binary,
+    Text,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+}
+impl Tracer {
+    pub const MAGIC: [u8; 4] = *b"RVTR";
+    pub const VERSION: u32 = 3;
+
+    /// binary traces start with a header, text traces don't
+    pub fn new(mut out: Box<dyn Write + Send>, format: TraceFormat) -> io::Result<Self> {
+        if let TraceFormat::Binary = format {
+            out.write_all(&Self::MAGIC)?;
+            out.write_all(&Self::VERSION.to_le_bytes())?
+        }
+        Ok(Self { out, format })
+    }
+
+    pub fn record(&mut self, r: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Binary => r.write_binary(&mut self.out),
+            TraceFormat::Text => writeln!(self.out, "{}", r),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// reads records back out of a binary trace
+pub struct TraceReader<R: Read> {
+    r: R,
+}
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut r: R) -> io::Result<Self> {
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        if header[0..4] != Tracer::MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary trace"))
+        }
+        if header[4..8] != Tracer::VERSION.to_le_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported trace version"))
+        }
+        Ok(Self { r })
+    }
+}
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+    fn next(&mut self) -> Option<Self::Item> {
+        TraceRecord::read_binary(&mut self.r).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<TraceRecord> {
+        vec![
+            TraceRecord {
+                pc: 0, iword: 0x0028_0089, opcode: Opcode::Arith, funct: 0,
+                s1: 0, s2: 5, s3: 0, rd: 8, value: 5,
+                mem: None, window: None, fault: false
+            },
+            TraceRecord {
+                pc: 4, iword: 0x0000_0007, opcode: Opcode::St, funct: 0,
+                s1: 0x100, s2: 0, s3: 5, rd: 0, value: 0,
+                mem: Some(MemAccess { addr: 0x100, len: 4, value: 5, store: true, src: None }), window: None, fault: false
+            },
+            TraceRecord {
+                pc: 8, iword: 0x0010_0005, opcode: Opcode::Func, funct: 0,
+                s1: 0, s2: 0x100, s3: 0, rd: 24, value: 8,
+                mem: None, window: Some(WindowEvent::Call), fault: false
+            },
+            TraceRecord {
+                pc: 0x100, iword: 0x0002_0093, opcode: Opcode::Ld, funct: 0,
+                s1: 0x41, s2: 0, s3: 0, rd: 9, value: 0,
+                mem: Some(MemAccess { addr: 0x41, len: 4, value: 0, store: false, src: None }), window: None, fault: true
+            },
+            TraceRecord {
+                pc: 0x104, iword: 0x0b42_4606, opcode: Opcode::St, funct: 3,
+                s1: 0x80, s2: 0x40, s3: 0x10, rd: 0, value: 0,
+                mem: Some(MemAccess { addr: 0x80, len: 0x10, value: 0, store: true, src: Some(0x40) }), window: None, fault: false
+            },
+        ]
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&Tracer::MAGIC);
+        buf.extend_from_slice(&Tracer::VERSION.to_le_bytes());
+        for r in sample() {
+            r.write_binary(&mut buf).unwrap()
+        }
+
+        let read: Vec<_> = TraceReader::new(buf.as_slice()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(read, sample());
+
+        // chop the last record in half
+        buf.truncate(buf.len() - 10);
+        let mut r = TraceReader::new(buf.as_slice()).unwrap();
+        for _ in 0..4 {
+            assert!(r.next().unwrap().is_ok());
+        }
+        assert!(r.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn text() {
+        let s = sample();
+        assert_eq!(s[0].to_string(), "00000000: 00280089 arith     0     s1=00000000 s2=00000005 s3=00000000 r8  <- 00000005");
+        assert!(s[1].to_string().ends_with(" st [00000100] <- 00000005"));
+        assert!(s[2].to_string().ends_with(" call"));
+        assert!(s[3].to_string().ends_with(" ld [00000041] -> 00000000 fault"));
+        assert!(s[4].to_string().ends_with(" st [00000080] <- [00000040] len=00000010"));
+    }
+}